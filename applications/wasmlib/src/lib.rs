@@ -33,6 +33,22 @@ impl Handle for CASHandle {
 
 }
 
+/// An object being written with `object_write`. Dropping it without `object_close` abandons the
+/// object.
+#[derive(Debug, PartialEq, Eq)]
+#[repr(transparent)]
+pub struct ObjectWriterHandle(NonZeroU32);
+
+impl Drop for ObjectWriterHandle {
+    fn drop(&mut self) {
+        _handle_release(self.0.get())
+    }
+}
+
+impl Handle for ObjectWriterHandle {
+
+}
+
 trait Handle : Eq+Drop {
 }
 
@@ -78,6 +94,32 @@ extern "C" {
 
     #[link_name = "handle_release"]
     fn _handle_release(handle: u32);
+
+    // Sets the message recorded by the next update_state.
+    #[link_name = "set_commit_message"]
+    fn _set_commit_message(src: *const u8, len: usize);
+
+    // Sets an application-defined value recorded by the next update_state.
+    #[link_name = "set_commit_metadata"]
+    fn _set_commit_metadata(key: *const u8, key_len: usize, value: *const u8, value_len: usize);
+
+    // Opens the object in key for streaming with read, loading it a chunk at a time.
+    // Returns 0 if there is no such object.
+    #[link_name = "object_open"]
+    fn _object_open(key: u32) -> u32;
+
+    // Starts writing a new object that links to the given handles.
+    #[link_name = "object_create"]
+    fn _object_create(handles: *const u32, handles_len: usize) -> u32;
+
+    // Appends len bytes starting at src to an object started with object_create.
+    #[link_name = "object_write"]
+    fn _object_write(handle: u32, src: *const u8, len: usize) -> usize;
+
+    // Finishes a written object, returning a handle to its key, or closes an opened one,
+    // returning 0. Either way the handle is released.
+    #[link_name = "object_close"]
+    fn _object_close(handle: u32) -> u32;
 }
 
 pub fn update_state(key: &KeyHandle) {
@@ -86,6 +128,18 @@ pub fn update_state(key: &KeyHandle) {
     }
 }
 
+pub fn set_commit_message(message: &str) {
+    unsafe {
+        _set_commit_message(message.as_ptr(), message.len());
+    }
+}
+
+pub fn set_commit_metadata(key: &str, value: &[u8]) {
+    unsafe {
+        _set_commit_metadata(key.as_ptr(), key.len(), value.as_ptr(), value.len());
+    }
+}
+
 pub fn get_state() -> Option<KeyHandle> {
     unsafe {
         Some(KeyHandle(NonZeroU32::new(_get_state())?))
@@ -142,6 +196,42 @@ pub fn cas_put(data: &[u8], links: Vec<KeyHandle>) -> Option<KeyHandle> {
     Some(KeyHandle(NonZeroU32::new(k)?))
 }
 
+pub fn object_open(key: &KeyHandle) -> Option<CASHandle> {
+    let h;
+    unsafe {
+        h = _object_open(key.0.get());
+    }
+
+    Some(CASHandle(NonZeroU32::new(h)?))
+}
+
+pub fn object_create(links: Vec<KeyHandle>) -> Option<ObjectWriterHandle> {
+    let h;
+    unsafe {
+        h = _object_create(links.as_ptr() as *const u32, links.len());
+    }
+
+    Some(ObjectWriterHandle(NonZeroU32::new(h)?))
+}
+
+pub fn object_write(handle: &ObjectWriterHandle, data: &[u8]) {
+    unsafe {
+        _object_write(handle.0.get(), data.as_ptr(), data.len());
+    }
+}
+
+pub fn object_close(handle: ObjectWriterHandle) -> Option<KeyHandle> {
+    let k;
+    unsafe {
+        k = _object_close(handle.0.get());
+    }
+
+    // The host has already released the writer's handle.
+    core::mem::forget(handle);
+
+    Some(KeyHandle(NonZeroU32::new(k)?))
+}
+
 #[macro_export]
 macro_rules! print {
     ($($arg:tt)*) => ($crate::_print(format_args!($($arg)*)));
@@ -1,10 +1,15 @@
-use distcomp::{ApplicationId, Journal, SqliteJournal, CASKey};
+use distcomp::stream::{ObjectReader, ObjectWriter};
+use distcomp::sync::{self, Remote};
+use distcomp::history::{self, Relation};
+use distcomp::{chunk, ApplicationId, CommitInfo, Journal, JournalError, JournalKey, OverlayJournal, SqliteJournal, StagedJournal, CASKey};
 use std::io::Write;
 use std::convert::TryInto;
 use uuid::Uuid;
 use wasmi::{ImportsBuilder, ModuleInstance};
 use handlemanager::HandleManager;
 use std::collections::HashMap;
+use std::io::{Read, Seek, SeekFrom};
+use std::rc::Rc;
 
 #[macro_use]
 extern crate derive_more;
@@ -12,6 +17,8 @@ extern crate derive_more;
 enum Handle {
     Key(CASKey),
     Data(Vec<u8>),
+    Reader(ObjectReader<Rc<dyn Journal>>),
+    Writer(ObjectWriter<Rc<dyn Journal>>),
 }
 
 impl Handle {
@@ -57,9 +64,17 @@ impl Handles {
 
 struct HostExternals {
     appid: ApplicationId,
-    journal: Box<dyn Journal>,
+    journal: Rc<dyn Journal>,
     memory: wasmi::MemoryRef,
     handles: Handles,
+
+    /// Message and metadata set by the guest, recorded in its next commit.
+    pending: CommitInfo,
+
+    /// Another device's head that has all of this device's history, taken on instead of our own
+    /// head. The guest sees its state, and its next commit records it as a parent, so our head only
+    /// ever points at entries we signed.
+    adopted: Option<JournalKey>,
 }
 
 #[derive(Debug, Display)]
@@ -68,6 +83,18 @@ struct InvalidHandleError(u32);
 
 impl wasmi::HostError for InvalidHandleError {} 
 
+#[derive(Debug, Display)]
+#[display(fmt = "Object stream failed: {}", _0)]
+struct StreamError(std::io::Error);
+
+impl wasmi::HostError for StreamError {}
+
+#[derive(Debug, Display)]
+#[display(fmt = "Object {:?} is not stored locally and no peer had it", _0)]
+struct MissingObjectError(CASKey);
+
+impl wasmi::HostError for MissingObjectError {}
+
 impl wasmi::Externals for HostExternals {
     fn invoke_index(
         &mut self,
@@ -86,12 +113,20 @@ impl wasmi::Externals for HostExternals {
                 .ok_or(InvalidHandleError(handle))?
                 .as_key().ok_or(InvalidHandleError(handle))?;
 
-                self.journal.commit_self(self.appid, *key);
+                let merged: Vec<JournalKey> = self.adopted.into_iter().collect();
+
+                // Only cleared once committed, so a failed commit can be retried as it was.
+                self.journal.commit_with(self.appid, *key, &merged, self.pending.clone())?;
+                self.pending = CommitInfo::default();
+                self.adopted = None;
 
                 Ok(None)
             }
             2 => {
-                let head = self.journal.get_state(self.appid);
+                let head = match self.adopted {
+                    Some(adopted) => self.journal.get(adopted)?.map(|entry| entry.new_state()),
+                    None => self.journal.get_state(self.appid)?,
+                };
 
 
                 if let Some(head) = head {
@@ -111,7 +146,7 @@ impl wasmi::Externals for HostExternals {
                 .ok_or(InvalidHandleError(handle))?
                 .as_key().ok_or(InvalidHandleError(handle))?;
 
-                let data = self.journal.cas_get(*key).expect("failed to get data").data;
+                let data = chunk::get(&*self.journal, *key)?.ok_or(MissingObjectError(*key))?.data;
 
                 let handle: u32 = self.handles.insert(Handle::Data(data)).expect("failed to insert handle").try_into().expect("could not convert a handle to a u32");
 
@@ -141,10 +176,10 @@ impl wasmi::Externals for HostExternals {
                     .get(src, len as usize)
                     .map_err(|_| MemoryAccessOutOfBounds)?;
 
-                let key = self.journal.cas_put(distcomp::CASObj {
+                let key = chunk::put(&*self.journal, distcomp::CASObj {
                     data,
                     links,
-                });
+                })?;
 
                 let handle: u32 = self.handles.insert(Handle::Key(key)).expect("failed to insert handle").try_into().expect("could nto convert a handle to a u32");
 
@@ -173,10 +208,26 @@ impl wasmi::Externals for HostExternals {
                 let len = args.nth_checked::<u32>(2)?;
                 let offset = args.nth_checked::<u32>(3)?;
 
+                if let Handle::Reader(reader) = self.handles.get(handle as usize).ok_or(InvalidHandleError(handle))? {
+                    reader.seek(SeekFrom::Start(offset.into())).map_err(StreamError)?;
+
+                    // The guest has to cope with short reads anyway, so each is capped at a chunk
+                    // and at what's left, rather than allocating however much it asked for.
+                    let available = reader.len().saturating_sub(offset.into());
+                    let len = (len as usize).min(chunk::MAX_CHUNK).min(available.try_into().unwrap_or(usize::MAX));
+
+                    let mut buf = vec![0; len];
+                    let read = reader.read(&mut buf).map_err(StreamError)?;
+
+                    self.memory.set(dest_addr, &buf[..read]).map_err(|_| MemoryAccessOutOfBounds)?;
+
+                    return Ok(Some((read as u32).into()));
+                }
+
                 let data = self.handles.get(handle as usize).expect("failed to get handle").as_data().expect("invalid handle type");
 
                 let start = u32::min(data.len() as u32, offset);
-                let stop = u32::min(data.len() as u32, offset + len);
+                let stop = u32::min(data.len() as u32, offset.saturating_add(len));
 
                 let data_sliced = &data[start as usize ..stop as usize];
 
@@ -195,7 +246,7 @@ impl wasmi::Externals for HostExternals {
 
                 let mut buf = Vec::new();
 
-                let links = self.journal.cas_get(*data).expect("failed to get object").links;
+                let links = chunk::links(&*self.journal, *data)?.ok_or(MissingObjectError(*data))?;
 
                 for link in links {
                     let handle = self.handles.insert(Handle::Key(link)).expect("failed to insert handle") as u32;
@@ -215,6 +266,126 @@ impl wasmi::Externals for HostExternals {
 
                 Ok(None)
             }
+            9 => {
+                let src = args.nth_checked::<u32>(0)?;
+                let len = args.nth_checked::<u32>(1)?;
+
+                let data = self
+                    .memory
+                    .get(src, len as usize)
+                    .map_err(|_| MemoryAccessOutOfBounds)?;
+
+                self.pending.message = Some(String::from_utf8_lossy(&data).into_owned());
+
+                Ok(None)
+            }
+            10 => {
+                let key_src = args.nth_checked::<u32>(0)?;
+                let key_len = args.nth_checked::<u32>(1)?;
+                let value_src = args.nth_checked::<u32>(2)?;
+                let value_len = args.nth_checked::<u32>(3)?;
+
+                let key = self
+                    .memory
+                    .get(key_src, key_len as usize)
+                    .map_err(|_| MemoryAccessOutOfBounds)?;
+
+                let value = self
+                    .memory
+                    .get(value_src, value_len as usize)
+                    .map_err(|_| MemoryAccessOutOfBounds)?;
+
+                self.pending.metadata.insert(String::from_utf8_lossy(&key).into_owned(), value);
+
+                Ok(None)
+            }
+            11 => {
+                let handle = args.nth_checked::<u32>(0)?;
+
+                let key = *self.handles
+                .get(handle as usize)
+                .ok_or(InvalidHandleError(handle))?
+                .as_key().ok_or(InvalidHandleError(handle))?;
+
+                match ObjectReader::open(Rc::clone(&self.journal), key)? {
+                    Some(reader) => {
+                        let handle: u32 = self.handles.insert(Handle::Reader(reader)).expect("failed to insert handle").try_into().expect("could not convert a handle to a u32");
+
+                        Ok(Some(handle.into()))
+                    }
+                    None => Ok(Some(I32(0))),
+                }
+            }
+            12 => {
+                let handle_ptr = args.nth_checked::<u32>(0)?;
+                let handle_count = args.nth_checked::<u32>(1)?;
+
+                let mut links = Vec::new();
+
+                for offset in (0..handle_count).map(|x| x*4) {
+                    let h: u32 = self.memory.get_value(handle_ptr + offset).map_err(|_| MemoryAccessOutOfBounds)?;
+
+                    let key = self.handles
+                    .get(h as usize)
+                    .ok_or(InvalidHandleError(h))?
+                    .as_key().ok_or(InvalidHandleError(h))?;
+
+                    links.push(*key);
+                }
+
+                let writer = ObjectWriter::new(Rc::clone(&self.journal), links);
+
+                let handle: u32 = self.handles.insert(Handle::Writer(writer)).expect("failed to insert handle").try_into().expect("could not convert a handle to a u32");
+
+                Ok(Some(handle.into()))
+            }
+            13 => {
+                use std::io::Write;
+
+                let handle = args.nth_checked::<u32>(0)?;
+                let src = args.nth_checked::<u32>(1)?;
+                let len = args.nth_checked::<u32>(2)?;
+
+                let data = self
+                    .memory
+                    .get(src, len as usize)
+                    .map_err(|_| MemoryAccessOutOfBounds)?;
+
+                match self.handles.get(handle as usize) {
+                    Some(Handle::Writer(writer)) => {
+                        writer.write_all(&data).map_err(StreamError)?;
+                    }
+                    _ => return Err(InvalidHandleError(handle).into()),
+                }
+
+                Ok(Some(len.into()))
+            }
+            14 => {
+                let handle = args.nth_checked::<u32>(0)?;
+
+                let writer = match self.handles.handles.remove(&(handle as usize)) {
+                    Some(Handle::Writer(writer)) => writer,
+                    Some(Handle::Reader(_)) => {
+                        self.handles.release(handle as usize);
+
+                        return Ok(Some(I32(0)));
+                    }
+                    Some(other) => {
+                        self.handles.handles.insert(handle as usize, other);
+
+                        return Err(InvalidHandleError(handle).into());
+                    }
+                    None => return Err(InvalidHandleError(handle).into()),
+                };
+
+                self.handles.release(handle as usize);
+
+                let key = writer.finish()?;
+
+                let handle: u32 = self.handles.insert(Handle::Key(key)).expect("failed to insert handle").try_into().expect("could not convert a handle to a u32");
+
+                Ok(Some(handle.into()))
+            }
             _ => panic!("Unimplemented function at {}", index),
         }
     }
@@ -279,6 +450,42 @@ impl wasmi::ModuleImportResolver for Resolver {
                     8,
                 ));
             }
+            "set_commit_message" => {
+                return Ok(wasmi::FuncInstance::alloc_host(
+                    wasmi::Signature::new(&[I32, I32][..], None),
+                    9,
+                ));
+            }
+            "set_commit_metadata" => {
+                return Ok(wasmi::FuncInstance::alloc_host(
+                    wasmi::Signature::new(&[I32, I32, I32, I32][..], None),
+                    10,
+                ));
+            }
+            "object_open" => {
+                return Ok(wasmi::FuncInstance::alloc_host(
+                    wasmi::Signature::new(&[I32][..], Some(I32)),
+                    11,
+                ));
+            }
+            "object_create" => {
+                return Ok(wasmi::FuncInstance::alloc_host(
+                    wasmi::Signature::new(&[I32, I32][..], Some(I32)),
+                    12,
+                ));
+            }
+            "object_write" => {
+                return Ok(wasmi::FuncInstance::alloc_host(
+                    wasmi::Signature::new(&[I32, I32, I32][..], Some(I32)),
+                    13,
+                ));
+            }
+            "object_close" => {
+                return Ok(wasmi::FuncInstance::alloc_host(
+                    wasmi::Signature::new(&[I32][..], Some(I32)),
+                    14,
+                ));
+            }
             _ => {
                 return Err(wasmi::Error::Instantiation("Failed to resolve".to_string()));
             }
@@ -312,24 +519,434 @@ fn func_main(appid: ApplicationId, journal: Box<Journal>) {
 
     let handles = Handles::default();
 
+    // The guest's writes are staged in memory and applied in one short transaction afterwards, so
+    // the database isn't locked for as long as the guest runs. If another process moves our head
+    // meanwhile, applying fails instead of overwriting its commit.
+    let staged = Rc::new(StagedJournal::new(Rc::from(journal)));
+
     let mut externals = HostExternals {
         appid,
-        journal,
+        journal: staged.clone(),
         memory,
         handles,
+        pending: CommitInfo::default(),
+        adopted: None,
     };
 
-    instance
-        .invoke_export("main", &[], &mut externals)
+    merge_heads(&instance, &mut externals).expect("failed to merge heads");
+    apply(&staged, appid);
+
+    atomic(&mut externals, |externals| instance.invoke_export("main", &[], externals))
         .expect("failed to execute export");
+    apply(&staged, appid);
+}
+
+/// Stores what the guest staged, exiting with a message if that fails.
+fn apply(staged: &StagedJournal, appid: ApplicationId) {
+    match staged.apply() {
+        Ok(()) => {}
+        Err(JournalError::HeadConflict { .. }) => {
+            eprintln!(
+                "Another process committed to application {} on this device while it ran, so its changes were not stored; run it again",
+                appid.0
+            );
+            std::process::exit(1);
+        }
+        Err(e) => {
+            eprintln!("Failed to store the application's changes: {}", e);
+            std::process::exit(1);
+        }
+    }
+}
+
+/// Runs `f`, which usually invokes a guest export, with every journal write it makes staged in a
+/// transaction. The writes are only kept if `f` succeeds, so a guest that traps or makes the host
+/// panic partway through leaves the journal as it was.
+fn atomic<T>(
+    externals: &mut HostExternals,
+    f: impl FnOnce(&mut HostExternals) -> Result<T, wasmi::Error>,
+) -> Result<T, wasmi::Error> {
+    use std::panic::{catch_unwind, resume_unwind, AssertUnwindSafe};
+
+    externals.journal.begin_transaction()?;
+
+    match catch_unwind(AssertUnwindSafe(|| f(externals))) {
+        Ok(Ok(value)) => match externals.journal.commit_transaction() {
+            Ok(()) => Ok(value),
+            Err(e) => {
+                let _ = externals.journal.rollback_transaction();
+                Err(e.into())
+            }
+        },
+        Ok(Err(e)) => {
+            let _ = externals.journal.rollback_transaction();
+            Err(e)
+        }
+        Err(panic) => {
+            let _ = externals.journal.rollback_transaction();
+            resume_unwind(panic)
+        }
+    }
+}
+
+/// Brings this device's head up to date with the heads other devices have for the application.
+///
+/// Heads that are descendants of ours are adopted: the guest sees their state and its next commit
+/// has them as a parent, but our head is left alone until then. Heads that have diverged are merged
+/// by the guest's `merge(base, ours, theirs)` export, which gets a key handle for each state (0 for
+/// a base when there is no common history) and returns a key handle for the merged state.
+fn merge_heads(instance: &wasmi::ModuleRef, externals: &mut HostExternals) -> Result<(), wasmi::Error> {
+    use wasmi::RuntimeValue::I32;
+
+    let appid = externals.appid;
+
+    for theirs in history::divergent_heads(&*externals.journal, appid)? {
+        let journal = &externals.journal;
+
+        let head = externals.adopted.map_or_else(|| journal.this_head(appid), |k| Ok(Some(k)))?;
+
+        let state_of = |key| -> Result<CASKey, JournalError> {
+            let entry = journal.get(key)?.ok_or_else(|| {
+                JournalError::Integrity(format!("head points at missing entry {:?}", key))
+            })?;
+
+            Ok(entry.new_state())
+        };
+
+        let ours = match (history::relate(&**journal, head, theirs)?, head) {
+            (Relation::Diverged, Some(ours)) => ours,
+            (Relation::FastForward, _) => {
+                externals.adopted = Some(theirs);
+                continue;
+            }
+            _ => continue,
+        };
+
+        if instance.export_by_name("merge").is_none() {
+            eprintln!("Heads have diverged but the application does not export `merge`, not merging");
+            return Ok(());
+        }
+
+        let states = [
+            match history::merge_base(&**journal, ours, theirs)? {
+                Some(base) => Some(state_of(base)?),
+                None => None,
+            },
+            Some(state_of(ours)?),
+            Some(state_of(theirs)?),
+        ];
+
+        let args: Vec<u32> = states
+            .iter()
+            .map(|state| match state {
+                Some(key) => externals.handles.insert(Handle::Key(*key)).expect("failed to insert handle").try_into().expect("could not convert a handle to a u32"),
+                None => 0,
+            })
+            .collect();
+
+        let merged_handle = atomic(externals, |externals| {
+            let result = instance
+                .invoke_export("merge", &[I32(args[0] as i32), I32(args[1] as i32), I32(args[2] as i32)], externals)?;
+
+            let merged_handle = match result {
+                Some(I32(h)) => h as u32,
+                _ => return Err(wasmi::Trap::new(wasmi::TrapKind::UnexpectedSignature).into()),
+            };
+
+            let merged = *externals
+                .handles
+                .get(merged_handle as usize)
+                .and_then(|h| h.as_key())
+                .ok_or(InvalidHandleError(merged_handle))?;
+
+            let parents: Vec<JournalKey> = externals.adopted.into_iter().chain(Some(theirs)).collect();
+
+            externals.journal.commit_merge(appid, merged, &parents)?;
+            externals.adopted = None;
+
+            Ok(merged_handle)
+        })?;
+
+        if !args.contains(&merged_handle) {
+            externals.handles.release(merged_handle as usize);
+        }
+
+        for handle in args.into_iter().filter(|&h| h != 0) {
+            externals.handles.release(handle as usize);
+        }
+    }
+
+    Ok(())
+}
+
+fn bundle_command(journal: &dyn Journal, args: &[String]) {
+    match args {
+        [cmd, path, apps @ ..] if cmd == "create" => {
+            let apps: Vec<ApplicationId> = apps
+                .iter()
+                .map(|a| ApplicationId(Uuid::parse_str(a).expect("invalid application id")))
+                .collect();
+
+            let mut file = std::fs::File::create(path).expect("failed to create bundle file");
+
+            distcomp::bundle::create(journal, &apps, &mut file).expect("failed to create bundle");
+        }
+        [cmd, path] if cmd == "import" => {
+            let mut file = std::fs::File::open(path).expect("failed to open bundle file");
+
+            let stats = distcomp::bundle::import(journal, &mut file).expect("failed to import bundle");
+
+            println!(
+                "Imported {} entries and {} objects, updated {} heads",
+                stats.entries, stats.objects, stats.heads_updated
+            );
+        }
+        _ => {
+            eprintln!("usage: distcomp bundle create <file> [application id...]");
+            eprintln!("       distcomp bundle import <file>");
+            std::process::exit(1);
+        }
+    }
+}
+
+fn gc_command(journal: &dyn Journal, args: &[String]) {
+    let mut options = distcomp::gc::GcOptions::default();
+
+    let mut args = args.iter();
+
+    while let Some(arg) = args.next() {
+        match arg.as_str() {
+            "--dry-run" => options.dry_run = true,
+            "--grace-period" => {
+                let secs = args
+                    .next()
+                    .and_then(|s| s.parse().ok())
+                    .expect("--grace-period needs a number of seconds");
+
+                options.grace_period = std::time::Duration::from_secs(secs);
+            }
+            _ => {
+                eprintln!("usage: distcomp gc [--dry-run] [--grace-period <seconds>]");
+                std::process::exit(1);
+            }
+        }
+    }
+
+    let report = distcomp::gc::gc(journal, &options).expect("failed to collect garbage");
+
+    for key in &report.swept {
+        println!("{} {:?}", if options.dry_run { "would remove" } else { "removed" }, key);
+    }
+
+    println!(
+        "{} reachable, {} unreachable {}, {} unreachable but recent",
+        report.reachable,
+        report.swept.len(),
+        if options.dry_run { "to remove" } else { "removed" },
+        report.recent.len()
+    );
+}
+
+fn log_command(journal: &dyn Journal, appid: ApplicationId, args: &[String]) {
+    let usage = || -> ! {
+        eprintln!("usage: distcomp log [--max-depth <depth>] [--as-of <unix seconds>]");
+        std::process::exit(1);
+    };
+
+    let mut max_depth = None;
+    let mut as_of = None;
+
+    let mut args = args.iter();
+
+    while let Some(arg) = args.next() {
+        let value = args.next().unwrap_or_else(|| usage());
+
+        match arg.as_str() {
+            "--max-depth" => max_depth = Some(value.parse().expect("--max-depth needs a number")),
+            "--as-of" => {
+                let secs: u64 = value.parse().expect("--as-of needs a number of seconds");
+                as_of = Some(secs.saturating_mul(1000));
+            }
+            _ => usage(),
+        }
+    }
+
+    let mut head = match journal.this_head(appid).expect("failed to read heads") {
+        Some(head) => head,
+        None => return println!("No history yet"),
+    };
+
+    if let Some(time) = as_of {
+        head = match history::as_of(journal, head, time).expect("failed to read history") {
+            Some(entry) => entry.key,
+            None => return println!("No history at that time"),
+        };
+    }
+
+    let mut log = history::Log::new(journal, head);
+
+    if let Some(max_depth) = max_depth {
+        log = log.max_depth(max_depth);
+    }
+
+    for entry in log {
+        let entry = entry.expect("failed to read history");
+
+        println!("{:?}", entry.key);
+        println!("  device  {:?}", entry.device);
+        println!("  state   {:?}", entry.entry.new_state());
+
+        if let Some(timestamp) = entry.entry.timestamp() {
+            println!("  time    {}.{:03}", timestamp / 1000, timestamp % 1000);
+        }
+
+        if let Some(clock) = entry.entry.clock() {
+            println!("  clock   {}.{}", clock.wall, clock.counter);
+        }
+
+        for parent in entry.entry.parents() {
+            println!("  parent  {:?}", parent);
+        }
+
+        for (key, value) in entry.entry.metadata() {
+            println!("  meta    {} = {}", key, String::from_utf8_lossy(value));
+        }
+
+        if let Some(message) = entry.entry.message() {
+            println!();
+            for line in message.lines() {
+                println!("    {}", line);
+            }
+        }
+    }
+}
+
+fn recompress_command(journal: &SqliteJournal) {
+    let stats = journal.recompress().expect("failed to recompress");
+
+    println!(
+        "compressed {} of {} objects, {} bytes down to {}",
+        stats.compressed, stats.examined, stats.bytes_before, stats.bytes_after
+    );
+}
+
+/// Prints one line per problem, then a summary line in the same `name=value` format, and exits
+/// with a failure status if anything was wrong.
+fn fsck_command(journal: &dyn Journal) {
+    let report = distcomp::fsck::verify(journal).expect("failed to read journal");
+
+    for problem in &report.problems {
+        println!("{}", problem);
+    }
+
+    println!(
+        "summary objects={} entries={} heads={} problems={}",
+        report.objects_checked,
+        report.entries_checked,
+        report.heads_checked,
+        report.problems.len()
+    );
+
+    if !report.is_ok() {
+        std::process::exit(1);
+    }
+}
+
+/// How long [`serve_command`] waits on a peer before giving up on it.
+const PEER_TIMEOUT: std::time::Duration = std::time::Duration::from_secs(30);
+
+/// Answers fetches from other devices started with `--peer`, one connection at a time. A peer that
+/// stays idle for [`PEER_TIMEOUT`] is dropped so the next one can be served.
+///
+/// Peers are not authenticated and anyone who connects can read the whole journal, so this only
+/// listens on loopback unless given an address, which must be on a trusted network.
+fn serve_command(journal: &dyn Journal, args: &[String]) {
+    let addr = match args {
+        [port] if port.parse::<u16>().is_ok() => format!("127.0.0.1:{}", port),
+        [addr] => addr.clone(),
+        _ => {
+            eprintln!("usage: distcomp serve [<address>:]<port>");
+            std::process::exit(1);
+        }
+    };
+
+    let listener = std::net::TcpListener::bind(&addr).expect("failed to listen");
+
+    for stream in listener.incoming() {
+        let result = stream.and_then(|mut stream| {
+            stream.set_read_timeout(Some(PEER_TIMEOUT))?;
+            stream.set_write_timeout(Some(PEER_TIMEOUT))?;
+            sync::serve_fetches(journal, &mut stream)
+        });
+
+        if let Err(e) = result {
+            eprintln!("Serving a peer failed: {}", e);
+        }
+    }
+}
+
+/// Puts `journal` over a connection to `peer`, so that anything missing locally is fetched from
+/// the peer and kept, and adopts the peer's heads. This lets a new device start without any
+/// history and fetch only what the application reads.
+fn with_peer(journal: SqliteJournal, peer: &str) -> OverlayJournal {
+    let stream = std::net::TcpStream::connect(peer).expect("failed to connect to peer");
+    let remote = Rc::new(Remote::new(stream));
+
+    let heads = remote.heads().expect("failed to get heads from peer");
+
+    let journal = OverlayJournal::new(Rc::new(journal))
+        .with_archive(remote)
+        .cache_archived();
+
+    distcomp::transaction(&journal, |journal| sync::fast_forward_heads(journal, &heads))
+        .expect("failed to adopt heads from peer");
+
+    journal
 }
 
 fn main() {
     better_panic::install();
 
-    let journal = SqliteJournal::new("sqlite.db");
+    let journal = match SqliteJournal::new("sqlite.db") {
+        Ok(journal) => journal,
+        Err(e) => {
+            eprintln!("Failed to open journal: {}", e);
+            std::process::exit(1);
+        }
+    };
 
     let appid = ApplicationId(Uuid::parse_str("f524b42d-7108-4489-8c84-988462634d39").unwrap());
 
-    func_main(appid, Box::new(journal));
+    let mut args: Vec<String> = std::env::args().skip(1).collect();
+
+    let peer = match args.iter().position(|arg| arg == "--peer") {
+        Some(i) if i + 1 < args.len() => Some(args.drain(i..=i + 1).nth(1).unwrap()),
+        Some(_) => {
+            eprintln!("--peer needs an address:port");
+            std::process::exit(1);
+        }
+        None => None,
+    };
+
+    if let Some((cmd, rest)) = args.split_first() {
+        if peer.is_some() {
+            eprintln!("--peer can only be used when running the application");
+            std::process::exit(1);
+        }
+
+        match cmd.as_str() {
+            "bundle" => return bundle_command(&journal, rest),
+            "gc" => return gc_command(&journal, rest),
+            "log" => return log_command(&journal, appid, rest),
+            "recompress" => return recompress_command(&journal),
+            "fsck" => return fsck_command(&journal),
+            "serve" => return serve_command(&journal, rest),
+            _ => {}
+        }
+    }
+
+    match peer {
+        Some(peer) => func_main(appid, Box::new(with_peer(journal, &peer))),
+        None => func_main(appid, Box::new(journal)),
+    }
 }
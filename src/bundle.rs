@@ -0,0 +1,288 @@
+//! Self-contained bundle files for moving journal state between machines without a network.
+//!
+//! A bundle holds a set of heads, every signed entry reachable from them and every object those
+//! entries reach. Importing checks all of it before anything is written to the journal.
+//!
+//! After the magic and version, a bundle holds the length of its CBOR encoded heads as a big
+//! endian `u32`, the heads, and then a [pack](crate::pack) of the entries and objects.
+
+use crate::error::invalid;
+use crate::pack::{Pack, PackWriter};
+use crate::sync::fast_forward_heads;
+use crate::{
+    clock, transaction, ApplicationId, CASKey, CASObj, DevicePublicKey, Journal, JournalEntry,
+    JournalKey, Signed,
+};
+use std::collections::{HashMap, HashSet};
+use std::convert::TryFrom;
+use std::io::{self, Cursor, Read, Seek, SeekFrom, Write};
+
+/// Written at the start of every bundle file, followed by the format version.
+const MAGIC: &[u8; 16] = b"distcomp-bundle\n";
+const VERSION: u8 = 2;
+
+struct Bundle {
+    heads: Vec<(ApplicationId, DevicePublicKey, JournalKey)>,
+    entries: Vec<(JournalKey, Signed)>,
+    objects: Vec<(CASKey, CASObj)>,
+}
+
+/// What an import added to the journal. Entries and objects it already had aren't counted.
+#[derive(Clone, Copy, PartialEq, Eq, Default, Debug)]
+pub struct ImportStats {
+    pub entries: usize,
+    pub objects: usize,
+    pub heads_updated: usize,
+}
+
+/// Writes a bundle of the heads for `applications` (or every application, if empty) and all the
+/// history behind them.
+///
+/// Fails if the journal is missing anything reachable from those heads.
+pub fn create<W: Write>(
+    journal: &dyn Journal,
+    applications: &[ApplicationId],
+    writer: &mut W,
+) -> io::Result<()> {
+    let heads: Vec<_> = journal
+        .heads()?
+        .into_iter()
+        .filter(|((appid, _), _)| applications.is_empty() || applications.contains(appid))
+        .map(|((appid, device), key)| (appid, device, key))
+        .collect();
+
+    let heads_data = serde_cbor::to_vec(&heads).map_err(|e| invalid(e.to_string()))?;
+    let heads_len = u32::try_from(heads_data.len())
+        .map_err(|_| invalid("too many heads to bundle".to_string()))?;
+
+    writer.write_all(MAGIC)?;
+    writer.write_all(&[VERSION])?;
+    writer.write_all(&heads_len.to_be_bytes())?;
+    writer.write_all(&heads_data)?;
+
+    let mut pack = PackWriter::new(writer)?;
+
+    let mut seen_entries = HashSet::new();
+    let mut seen_objects = HashSet::new();
+
+    let mut entry_queue: Vec<JournalKey> = heads.iter().map(|&(_, _, key)| key).collect();
+    let mut object_queue = Vec::new();
+
+    while let Some(key) = entry_queue.pop() {
+        if !seen_entries.insert(key) {
+            continue;
+        }
+
+        let signed = journal
+            .get_signed(key)?
+            .ok_or_else(|| invalid(format!("journal is missing entry {:?}", key)))?;
+
+        let entry = signed
+            .verify()
+            .ok_or_else(|| invalid(format!("entry {:?} has a bad signature", key)))?;
+
+        entry_queue.extend(entry.parents);
+        object_queue.push(entry.new_state);
+
+        pack.add_entry(key, &signed)?;
+    }
+
+    while let Some(key) = object_queue.pop() {
+        if !seen_objects.insert(key) {
+            continue;
+        }
+
+        let obj = journal
+            .cas_get(key)?
+            .ok_or_else(|| invalid(format!("journal is missing object {:?}", key)))?;
+
+        object_queue.extend(obj.links.iter().copied());
+
+        pack.add_object(key, &obj)?;
+    }
+
+    pack.finish()?;
+
+    Ok(())
+}
+
+/// Reads what follows the header of a bundle.
+fn read_bundle<R: Read>(reader: &mut R) -> io::Result<Bundle> {
+    let mut heads_len = [0; 4];
+    reader.read_exact(&mut heads_len)?;
+
+    let heads_len = u64::from(u32::from_be_bytes(heads_len));
+
+    // Read only as much as is really there, rather than trusting the length up front.
+    let mut heads_data = Vec::new();
+    reader.take(heads_len).read_to_end(&mut heads_data)?;
+
+    if heads_data.len() as u64 != heads_len {
+        return Err(invalid("bundle ends in its heads".to_string()));
+    }
+
+    let heads = serde_cbor::from_slice(&heads_data).map_err(|e| invalid(e.to_string()))?;
+
+    let mut pack_data = Vec::new();
+    reader.read_to_end(&mut pack_data)?;
+
+    let read_pack = || -> crate::Result<Bundle> {
+        let pack = Pack::open(Cursor::new(pack_data))?;
+
+        let mut entries = Vec::new();
+        let mut objects = Vec::new();
+
+        for key in pack.entries() {
+            entries.extend(pack.entry(key)?.map(|signed| (key, signed)));
+        }
+
+        for key in pack.objects() {
+            objects.extend(pack.object(key)?.map(|obj| (key, obj)));
+        }
+
+        Ok(Bundle {
+            heads,
+            entries,
+            objects,
+        })
+    };
+
+    read_pack().map_err(|e| invalid(e.to_string()))
+}
+
+/// Reads a bundle and adds its contents to `journal`, fast-forwarding heads where possible.
+///
+/// Every signature and hash in the bundle is checked, and every parent and link must resolve
+/// either within the bundle or in the journal, before the journal is touched.
+pub fn import<R: Read>(journal: &dyn Journal, reader: &mut R) -> io::Result<ImportStats> {
+    let mut header = [0; 17];
+    reader.read_exact(&mut header)?;
+
+    if &header[..16] != MAGIC {
+        return Err(invalid("not a bundle file".to_string()));
+    }
+
+    if header[16] != VERSION {
+        return Err(invalid(format!(
+            "unsupported bundle version {}",
+            header[16]
+        )));
+    }
+
+    let bundle = read_bundle(reader)?;
+
+    let mut entries = HashMap::new();
+
+    for (key, signed) in &bundle.entries {
+        if signed.key_with(key.algorithm()) != *key {
+            return Err(invalid(format!("entry {:?} does not match its hash", key)));
+        }
+
+        let entry = signed
+            .verify()
+            .ok_or_else(|| invalid(format!("entry {:?} has a bad signature", key)))?;
+
+        entries.insert(*key, entry);
+    }
+
+    let objects: HashSet<CASKey> = bundle.objects.iter().map(|(key, _)| *key).collect();
+
+    for (key, obj) in &bundle.objects {
+        if obj.key_with(key.algorithm()) != *key {
+            return Err(invalid(format!("object {:?} does not match its hash", key)));
+        }
+    }
+
+    let has_entry = |key| -> io::Result<bool> {
+        Ok(entries.contains_key(&key) || journal.get_signed(key)?.is_some())
+    };
+    let has_object =
+        |key| -> io::Result<bool> { Ok(objects.contains(&key) || journal.cas_get(key)?.is_some()) };
+
+    for (key, entry) in &entries {
+        for &parent in &entry.parents {
+            if !has_entry(parent)? {
+                return Err(invalid(format!(
+                    "entry {:?} has missing parent {:?}",
+                    key, parent
+                )));
+            }
+        }
+
+        if !has_object(entry.new_state)? {
+            return Err(invalid(format!("entry {:?} has missing state", key)));
+        }
+    }
+
+    for (key, obj) in &bundle.objects {
+        for &link in &obj.links {
+            if !has_object(link)? {
+                return Err(invalid(format!(
+                    "object {:?} has missing link {:?}",
+                    key, link
+                )));
+            }
+        }
+    }
+
+    for &(_, _, key) in &bundle.heads {
+        if !has_entry(key)? {
+            return Err(invalid(format!("head {:?} is missing", key)));
+        }
+    }
+
+    let stats = transaction(journal, |journal| {
+        let mut stats = ImportStats::default();
+
+        for (key, obj) in bundle.objects {
+            if journal.cas_get(key)?.is_none() {
+                journal.cas_put_with(obj, key.algorithm())?;
+                stats.objects += 1;
+            }
+        }
+
+        for (key, signed) in &bundle.entries {
+            if journal.get_signed(*key)?.is_none() {
+                journal.put_signed_with(signed, key.algorithm())?;
+                stats.entries += 1;
+            }
+        }
+
+        if let Some(clock) = entries.values().filter_map(JournalEntry::clock).max() {
+            clock::observe(journal, clock)?;
+        }
+
+        stats.heads_updated = fast_forward_heads(journal, &bundle.heads)?;
+
+        Ok(stats)
+    })?;
+
+    Ok(stats)
+}
+
+/// Opens the entries and objects in a bundle for reading in place, without importing them, for
+/// example to use the bundle as an [`Archive`](crate::overlay::Archive). Nothing in them is
+/// checked.
+pub fn open<R: Read + Seek>(mut reader: R) -> io::Result<Pack<R>> {
+    let mut header = [0; 17];
+    reader.seek(SeekFrom::Start(0))?;
+    reader.read_exact(&mut header)?;
+
+    if &header[..16] != MAGIC {
+        return Err(invalid("not a bundle file".to_string()));
+    }
+
+    if header[16] != VERSION {
+        return Err(invalid(format!(
+            "unsupported bundle version {}",
+            header[16]
+        )));
+    }
+
+    let mut heads_len = [0; 4];
+    reader.read_exact(&mut heads_len)?;
+
+    let start = reader.seek(SeekFrom::Current(i64::from(u32::from_be_bytes(heads_len))))?;
+
+    Ok(Pack::open_at(reader, start)?)
+}
@@ -0,0 +1,200 @@
+//! Splitting large objects into content-defined chunks.
+//!
+//! Chunk boundaries are placed where a rolling hash of the preceding bytes matches a pattern, so
+//! they depend only on nearby content: an edit to a large object changes the chunks around it and
+//! leaves the rest with the same keys, so only those chunks need storing or syncing again.
+//!
+//! A chunked object is stored as a manifest: an object whose data is [`MANIFEST_MAGIC`] followed by
+//! a CBOR [`Manifest`], and whose links are the original links followed by the chunks in order.
+//! Objects whose data happens to start with the magic are always stored as manifests, even when
+//! small, so anything stored with that prefix really is one.
+
+use crate::{transaction, CASKey, CASObj, Journal, JournalError, Result};
+use serde::{Deserialize, Serialize};
+
+/// Marks the data of a manifest object.
+pub const MANIFEST_MAGIC: &[u8] = b"distcomp-chunked\n";
+
+/// Chunks are never shorter than this, except for the last one.
+pub const MIN_CHUNK: usize = 16 * 1024;
+
+/// Chunks are never longer than this. Objects with no more data than this are stored whole.
+pub const MAX_CHUNK: usize = 256 * 1024;
+
+/// A boundary is placed where these bits of the hash are all zero, giving chunks of about 64 KiB
+/// on average.
+const BOUNDARY_MASK: u64 = (1 << 16) - 1;
+
+#[derive(Serialize, Deserialize, Clone, PartialEq, Eq, Debug)]
+pub struct Manifest {
+    /// How many of the manifest's links are the original object's, rather than chunks.
+    pub links: usize,
+
+    /// The length of each chunk, in the same order as the chunk links.
+    pub lengths: Vec<u64>,
+}
+
+/// A pseudorandom value for every byte, mixed into the rolling hash. Derived with splitmix64 so
+/// that every device computes the same table, and so the same boundaries.
+pub(crate) fn gear_table() -> [u64; 256] {
+    let mut table = [0; 256];
+    let mut state: u64 = 0;
+
+    for value in &mut table {
+        state = state.wrapping_add(0x9e37_79b9_7f4a_7c15);
+
+        let mut z = state;
+        z = (z ^ (z >> 30)).wrapping_mul(0xbf58_476d_1ce4_e5b9);
+        z = (z ^ (z >> 27)).wrapping_mul(0x94d0_49bb_1331_11eb);
+
+        *value = z ^ (z >> 31);
+    }
+
+    table
+}
+
+/// The length of the first chunk of `data`. Only depends on the first [`MAX_CHUNK`] bytes, so a
+/// writer can place a boundary once it has that many buffered.
+pub(crate) fn boundary(data: &[u8], gear: &[u64; 256]) -> usize {
+    let end = data.len().min(MAX_CHUNK);
+
+    if end <= MIN_CHUNK {
+        return end;
+    }
+
+    let mut hash: u64 = 0;
+
+    for (i, &byte) in data.iter().enumerate().take(end).skip(MIN_CHUNK) {
+        hash = (hash << 1).wrapping_add(gear[usize::from(byte)]);
+
+        if hash & BOUNDARY_MASK == 0 {
+            return i + 1;
+        }
+    }
+
+    end
+}
+
+/// Splits `data` at content-defined boundaries. Concatenating the result gives back `data`.
+pub fn split(data: &[u8]) -> Vec<&[u8]> {
+    let gear = gear_table();
+
+    let mut chunks = Vec::new();
+    let mut rest = data;
+
+    while !rest.is_empty() {
+        let (chunk, tail) = rest.split_at(boundary(rest, &gear));
+        chunks.push(chunk);
+        rest = tail;
+    }
+
+    chunks
+}
+
+/// Whether an object with `data` is stored as a manifest rather than whole.
+pub(crate) fn needs_chunking(data: &[u8]) -> bool {
+    data.len() > MAX_CHUNK || data.starts_with(MANIFEST_MAGIC)
+}
+
+/// Encodes the data of a manifest object.
+pub(crate) fn manifest_data(manifest: &Manifest) -> Result<Vec<u8>> {
+    let mut data = MANIFEST_MAGIC.to_vec();
+
+    serde_cbor::to_writer(&mut data, manifest)?;
+
+    Ok(data)
+}
+
+/// Decodes `obj` as a manifest, if it is one.
+pub fn manifest(obj: &CASObj) -> Result<Option<Manifest>> {
+    if !obj.data.starts_with(MANIFEST_MAGIC) {
+        return Ok(None);
+    }
+
+    let manifest: Manifest = serde_cbor::from_slice(&obj.data[MANIFEST_MAGIC.len()..])?;
+
+    if manifest.links > obj.links.len()
+        || obj.links.len() - manifest.links != manifest.lengths.len()
+    {
+        return Err(JournalError::Integrity(
+            "manifest does not match its links".to_string(),
+        ));
+    }
+
+    Ok(Some(manifest))
+}
+
+/// Stores `obj`, as a manifest and chunks if it is large, returning the key to read it back with
+/// [`get`].
+pub fn put(journal: &dyn Journal, obj: CASObj) -> Result<CASKey> {
+    if !needs_chunking(&obj.data) {
+        return journal.cas_put(obj);
+    }
+
+    transaction(journal, |journal| {
+        let mut links = obj.links;
+        let mut lengths = Vec::new();
+
+        let original_links = links.len();
+
+        for chunk in split(&obj.data) {
+            links.push(journal.cas_put(CASObj {
+                links: vec![],
+                data: chunk.to_vec(),
+            })?);
+
+            lengths.push(chunk.len() as u64);
+        }
+
+        let data = manifest_data(&Manifest {
+            links: original_links,
+            lengths,
+        })?;
+
+        journal.cas_put(CASObj { links, data })
+    })
+}
+
+/// Reads an object stored with [`put`], reassembling it from its chunks if it was split.
+pub fn get(journal: &dyn Journal, key: CASKey) -> Result<Option<CASObj>> {
+    let mut obj = match journal.cas_get(key)? {
+        Some(obj) => obj,
+        None => return Ok(None),
+    };
+
+    let manifest = match manifest(&obj)? {
+        Some(manifest) => manifest,
+        None => return Ok(Some(obj)),
+    };
+
+    let chunks = obj.links.split_off(manifest.links);
+
+    let mut data = Vec::new();
+
+    for chunk in chunks {
+        let chunk = journal
+            .cas_get(chunk)?
+            .ok_or_else(|| JournalError::Integrity(format!("missing chunk {:?}", chunk)))?;
+
+        data.extend(chunk.data);
+    }
+
+    Ok(Some(CASObj {
+        links: obj.links,
+        data,
+    }))
+}
+
+/// The links of an object stored with [`put`], without reading its chunks.
+pub fn links(journal: &dyn Journal, key: CASKey) -> Result<Option<Vec<CASKey>>> {
+    let mut obj = match journal.cas_get(key)? {
+        Some(obj) => obj,
+        None => return Ok(None),
+    };
+
+    if let Some(manifest) = manifest(&obj)? {
+        obj.links.truncate(manifest.links);
+    }
+
+    Ok(Some(obj.links))
+}
@@ -0,0 +1,113 @@
+//! Hybrid logical clocks, for ordering entries from devices whose wall clocks disagree.
+//!
+//! A clock value is close to the wall clock of whichever device last advanced it, but never goes
+//! backwards and exceeds every value the device has seen, so an entry's clock is normally greater
+//! than those of all its ancestors. Values too far in the future aren't taken on, so entries built
+//! on one from a device with a broken clock can have smaller clocks than it.
+
+use crate::{Journal, JournalError, Result};
+use serde::{Deserialize, Serialize};
+use std::convert::TryInto;
+use std::time::{SystemTime, UNIX_EPOCH};
+
+/// The setting this device's latest clock value is kept in.
+pub(crate) const CLOCK_SETTING: &str = "Clock";
+
+/// How far ahead of this device's wall clock, in milliseconds, a value from another device may be.
+/// Anything further ahead is more likely a broken or hostile clock than drift, and observing it
+/// would drag this device's clock, and every entry it writes, along with it, so it is ignored.
+const MAX_DRIFT: u64 = 24 * 60 * 60 * 1000;
+
+/// A hybrid logical clock value. Values compare by `wall`, then by `counter`.
+#[derive(
+    Serialize, Deserialize, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash, Default, Debug,
+)]
+pub struct Hlc {
+    /// Milliseconds since the Unix epoch, as the largest wall clock reading seen so far.
+    pub wall: u64,
+
+    /// Distinguishes values with the same `wall`.
+    pub counter: u32,
+}
+
+impl Hlc {
+    /// The value following `self` for a local event happening at wall clock time `now`, or `None`
+    /// if that would overflow the counter.
+    pub fn tick(self, now: u64) -> Option<Self> {
+        if now > self.wall {
+            Some(Self {
+                wall: now,
+                counter: 0,
+            })
+        } else {
+            Some(Self {
+                wall: self.wall,
+                counter: self.counter.checked_add(1)?,
+            })
+        }
+    }
+
+    /// The value following `self` after seeing `remote` from another device at wall clock time
+    /// `now`, which is greater than both `self` and `remote`. `None` if that would overflow the
+    /// counter.
+    pub fn observe(self, remote: Self, now: u64) -> Option<Self> {
+        let wall = now.max(self.wall).max(remote.wall);
+
+        let counter = if wall == self.wall && wall == remote.wall {
+            self.counter.max(remote.counter).checked_add(1)?
+        } else if wall == self.wall {
+            self.counter.checked_add(1)?
+        } else if wall == remote.wall {
+            remote.counter.checked_add(1)?
+        } else {
+            0
+        };
+
+        Some(Self { wall, counter })
+    }
+}
+
+fn wall_clock() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map_or(0, |d| d.as_millis().try_into().unwrap_or(u64::MAX))
+}
+
+fn load<J: Journal + ?Sized>(journal: &J) -> Result<Hlc> {
+    match journal.settings_get(CLOCK_SETTING)? {
+        Some(data) => Ok(serde_cbor::from_slice(&data)?),
+        None => Ok(Hlc::default()),
+    }
+}
+
+fn store<J: Journal + ?Sized>(journal: &J, clock: Hlc) -> Result<()> {
+    journal.settings_set(CLOCK_SETTING, &serde_cbor::to_vec(&clock)?)
+}
+
+/// Advances this device's clock for a local event, returning the new value.
+pub(crate) fn tick<J: Journal + ?Sized>(journal: &J) -> Result<Hlc> {
+    let clock = load(journal)?
+        .tick(wall_clock())
+        .ok_or(JournalError::ClockExhausted)?;
+
+    store(journal, clock)?;
+
+    Ok(clock)
+}
+
+/// Advances this device's clock past `remote`, a value seen on an entry from elsewhere. Leaves the
+/// clock alone if `remote` is too far ahead of this device's wall clock, so that the entry can
+/// still be stored without one device's broken clock spreading to every other.
+pub(crate) fn observe<J: Journal + ?Sized>(journal: &J, remote: Hlc) -> Result<()> {
+    let now = wall_clock();
+
+    if remote.wall > now.saturating_add(MAX_DRIFT) {
+        return Ok(());
+    }
+
+    let clock = load(journal)?
+        .observe(remote, now)
+        .ok_or(JournalError::ClockExhausted)?;
+
+    store(journal, clock)
+}
@@ -0,0 +1,77 @@
+//! Compression of object bodies stored by [`SqliteJournal`](crate::SqliteJournal).
+//!
+//! Only the stored bytes are compressed. Keys are still the hash of the uncompressed CBOR, so
+//! compressing or recompressing an object never changes its key.
+
+use crate::{JournalError, Result};
+use flate2::read::ZlibDecoder;
+use flate2::write::ZlibEncoder;
+use flate2::Compression;
+use std::io::{Read, Write};
+
+/// How a stored body is encoded, kept alongside it as an integer tag.
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub(crate) enum Codec {
+    None = 0,
+    Zlib = 1,
+}
+
+/// Bodies shorter than this are stored as they are, since compressing them rarely helps.
+const MIN_COMPRESS: usize = 64;
+
+/// Compresses `data`, unless doing so wouldn't make it smaller.
+pub(crate) fn compress(data: Vec<u8>) -> Result<(Codec, Vec<u8>)> {
+    if data.len() < MIN_COMPRESS {
+        return Ok((Codec::None, data));
+    }
+
+    let mut encoder = ZlibEncoder::new(Vec::new(), Compression::default());
+
+    encoder
+        .write_all(&data)
+        .and_then(|()| encoder.finish())
+        .map_err(|e| JournalError::Integrity(format!("failed to compress: {}", e)))
+        .map(|compressed| {
+            if compressed.len() < data.len() {
+                (Codec::Zlib, compressed)
+            } else {
+                (Codec::None, data)
+            }
+        })
+}
+
+/// Undoes [`compress`], given the tag it returned.
+pub(crate) fn decompress(codec: i64, data: Vec<u8>) -> Result<Vec<u8>> {
+    decompress_at_most(codec, data, u64::MAX)
+}
+
+/// Like [`decompress`], but fails rather than produce more than `max` bytes, for data from
+/// elsewhere that could otherwise inflate to any size.
+pub(crate) fn decompress_at_most(codec: i64, data: Vec<u8>, max: u64) -> Result<Vec<u8>> {
+    let too_large = || JournalError::Integrity(format!("body larger than {} bytes", max));
+
+    match codec {
+        c if c == Codec::None as i64 => {
+            if data.len() as u64 > max {
+                return Err(too_large());
+            }
+
+            Ok(data)
+        }
+        c if c == Codec::Zlib as i64 => {
+            let mut decompressed = Vec::new();
+
+            ZlibDecoder::new(&data[..])
+                .take(max.saturating_add(1))
+                .read_to_end(&mut decompressed)
+                .map_err(|e| JournalError::Integrity(format!("failed to decompress: {}", e)))?;
+
+            if decompressed.len() as u64 > max {
+                return Err(too_large());
+            }
+
+            Ok(decompressed)
+        }
+        c => Err(JournalError::Integrity(format!("unknown codec {}", c))),
+    }
+}
@@ -0,0 +1,269 @@
+//! Journals assembled from stores kept in different backends.
+
+use crate::{
+    ApplicationId, CASKey, CASObj, CasStore, DevicePublicKey, EntryStore, HashAlgorithm, HeadStore,
+    JournalKey, KeyStore, Result, SettingsStore, Signed, Transactional,
+};
+use sodiumoxide::crypto::sign;
+use std::collections::HashMap;
+use std::rc::Rc;
+
+/// A [`Journal`](crate::Journal) assembled from separate stores, which may come from different
+/// backends or be shared with other journals.
+///
+/// For example, objects can be kept in a [`FileJournal`](crate::FileJournal) while everything
+/// else stays in a [`SqliteJournal`](crate::SqliteJournal):
+///
+/// ```ignore
+/// let journal = CompositeJournal::new(Rc::new(SqliteJournal::new("sqlite.db")?))
+///     .with_cas(Rc::new(FileJournal::new(Path::new("objects"))?));
+/// ```
+///
+/// Transactions are begun on every distinct store, and committed objects first and heads last. A
+/// backend that provides several of the stores takes part once, where it comes last in that order.
+/// Transactions are only atomic within each backend: if a commit fails part way, the backends
+/// committed so far keep their writes, but heads never point at anything the others failed to
+/// keep.
+pub struct CompositeJournal {
+    cas: Rc<dyn CasStore>,
+    entries: Rc<dyn EntryStore>,
+    settings: Rc<dyn SettingsStore>,
+    heads: Rc<dyn HeadStore>,
+    keys: Rc<dyn KeyStore>,
+}
+
+#[derive(Clone, Copy)]
+enum Op {
+    Begin,
+    Commit,
+    Rollback,
+}
+
+fn apply<S: Transactional + ?Sized>(store: &S, op: Op) -> Result<()> {
+    match op {
+        Op::Begin => store.begin_transaction(),
+        Op::Commit => store.commit_transaction(),
+        Op::Rollback => store.rollback_transaction(),
+    }
+}
+
+/// How many of the stores take part in transactions.
+const TRANSACTIONAL: usize = 4;
+
+impl CompositeJournal {
+    /// Uses `base` for every store, ready to have some of them replaced.
+    pub fn new<J>(base: Rc<J>) -> Self
+    where
+        J: CasStore + EntryStore + SettingsStore + HeadStore + KeyStore + 'static,
+    {
+        Self {
+            cas: Rc::clone(&base) as Rc<dyn CasStore>,
+            entries: Rc::clone(&base) as Rc<dyn EntryStore>,
+            settings: Rc::clone(&base) as Rc<dyn SettingsStore>,
+            heads: Rc::clone(&base) as Rc<dyn HeadStore>,
+            keys: base as Rc<dyn KeyStore>,
+        }
+    }
+
+    #[must_use]
+    pub fn with_cas(mut self, cas: Rc<dyn CasStore>) -> Self {
+        self.cas = cas;
+        self
+    }
+
+    #[must_use]
+    pub fn with_entries(mut self, entries: Rc<dyn EntryStore>) -> Self {
+        self.entries = entries;
+        self
+    }
+
+    #[must_use]
+    pub fn with_settings(mut self, settings: Rc<dyn SettingsStore>) -> Self {
+        self.settings = settings;
+        self
+    }
+
+    #[must_use]
+    pub fn with_heads(mut self, heads: Rc<dyn HeadStore>) -> Self {
+        self.heads = heads;
+        self
+    }
+
+    #[must_use]
+    pub fn with_keys(mut self, keys: Rc<dyn KeyStore>) -> Self {
+        self.keys = keys;
+        self
+    }
+
+    /// Applies `op` to one of the transactional stores, in the order they are committed.
+    fn apply_to(&self, store: usize, op: Op) -> Result<()> {
+        match store {
+            0 => apply(&*self.cas, op),
+            1 => apply(&*self.entries, op),
+            2 => apply(&*self.settings, op),
+            _ => apply(&*self.heads, op),
+        }
+    }
+
+    /// The address of one of the transactional stores, to tell which are the same backend.
+    fn address(&self, store: usize) -> *const () {
+        match store {
+            0 => Rc::as_ptr(&self.cas).cast::<()>(),
+            1 => Rc::as_ptr(&self.entries).cast::<()>(),
+            2 => Rc::as_ptr(&self.settings).cast::<()>(),
+            _ => Rc::as_ptr(&self.heads).cast::<()>(),
+        }
+    }
+
+    /// The transactional stores to apply operations to, in order, leaving out any that are the
+    /// same backend as a later one.
+    fn distinct(&self) -> Vec<usize> {
+        (0..TRANSACTIONAL)
+            .filter(|&store| {
+                (store + 1..TRANSACTIONAL).all(|later| self.address(later) != self.address(store))
+            })
+            .collect()
+    }
+
+    /// Rolls back `stores`, ignoring failures since there is already an error to report.
+    fn roll_back(&self, stores: &[usize]) {
+        for &store in stores {
+            let _ = self.apply_to(store, Op::Rollback);
+        }
+    }
+}
+
+impl Transactional for CompositeJournal {
+    fn begin_transaction(&self) -> Result<()> {
+        let stores = self.distinct();
+
+        for (i, &store) in stores.iter().enumerate() {
+            if let Err(e) = self.apply_to(store, Op::Begin) {
+                self.roll_back(&stores[..i]);
+                return Err(e);
+            }
+        }
+
+        Ok(())
+    }
+
+    fn commit_transaction(&self) -> Result<()> {
+        let stores = self.distinct();
+
+        for (i, &store) in stores.iter().enumerate() {
+            if let Err(e) = self.apply_to(store, Op::Commit) {
+                self.roll_back(&stores[i..]);
+                return Err(e);
+            }
+        }
+
+        Ok(())
+    }
+
+    fn rollback_transaction(&self) -> Result<()> {
+        let mut result = Ok(());
+
+        for store in self.distinct() {
+            let rolled_back = self.apply_to(store, Op::Rollback);
+
+            if result.is_ok() {
+                result = rolled_back;
+            }
+        }
+
+        result
+    }
+}
+
+impl SettingsStore for CompositeJournal {
+    fn settings_get(&self, key: &str) -> Result<Option<Vec<u8>>> {
+        self.settings.settings_get(key)
+    }
+
+    fn settings_set(&self, key: &str, value: &[u8]) -> Result<()> {
+        self.settings.settings_set(key, value)
+    }
+}
+
+impl KeyStore for CompositeJournal {
+    fn pubkey(&self) -> Result<DevicePublicKey> {
+        self.keys.pubkey()
+    }
+
+    fn privkey(&self) -> Result<sign::SecretKey> {
+        self.keys.privkey()
+    }
+}
+
+impl HeadStore for CompositeJournal {
+    fn heads(&self) -> Result<HashMap<(ApplicationId, DevicePublicKey), JournalKey>> {
+        self.heads.heads()
+    }
+
+    fn update_head(
+        &self,
+        device: DevicePublicKey,
+        appid: ApplicationId,
+        key: JournalKey,
+    ) -> Result<()> {
+        self.heads.update_head(device, appid, key)
+    }
+
+    fn update_head_if(
+        &self,
+        device: DevicePublicKey,
+        appid: ApplicationId,
+        expected: Option<JournalKey>,
+        key: JournalKey,
+    ) -> Result<()> {
+        self.heads.update_head_if(device, appid, expected, key)
+    }
+}
+
+impl EntryStore for CompositeJournal {
+    fn get_signed(&self, key: JournalKey) -> Result<Option<Signed>> {
+        self.entries.get_signed(key)
+    }
+
+    fn put_signed_with(&self, signed: &Signed, algorithm: HashAlgorithm) -> Result<JournalKey> {
+        self.entries.put_signed_with(signed, algorithm)
+    }
+
+    fn children(&self, key: JournalKey) -> Result<Vec<JournalKey>> {
+        self.entries.children(key)
+    }
+
+    fn entry_list(&self) -> Result<Vec<JournalKey>> {
+        self.entries.entry_list()
+    }
+
+    fn entry_ids(&self) -> Result<Vec<std::result::Result<JournalKey, Vec<u8>>>> {
+        self.entries.entry_ids()
+    }
+}
+
+impl CasStore for CompositeJournal {
+    fn cas_get(&self, key: CASKey) -> Result<Option<CASObj>> {
+        self.cas.cas_get(key)
+    }
+
+    fn cas_put_with(&self, obj: CASObj, algorithm: HashAlgorithm) -> Result<CASKey> {
+        self.cas.cas_put_with(obj, algorithm)
+    }
+
+    fn cas_list(&self) -> Result<Vec<CASKey>> {
+        self.cas.cas_list()
+    }
+
+    fn cas_ids(&self) -> Result<Vec<std::result::Result<CASKey, Vec<u8>>>> {
+        self.cas.cas_ids()
+    }
+
+    fn cas_delete(&self, key: CASKey) -> Result<()> {
+        self.cas.cas_delete(key)
+    }
+
+    fn cas_written(&self, key: CASKey) -> Result<Option<u64>> {
+        self.cas.cas_written(key)
+    }
+}
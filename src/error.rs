@@ -0,0 +1,94 @@
+use crate::JournalKey;
+use derive_more::Display;
+use std::io;
+
+pub type Result<T, E = JournalError> = std::result::Result<T, E>;
+
+/// Everything that can go wrong when reading or writing a [`Journal`](crate::Journal).
+#[derive(Debug, Display)]
+pub enum JournalError {
+    /// The underlying store failed, for example because the database is locked.
+    #[display(fmt = "storage error: {}", _0)]
+    Storage(rusqlite::Error),
+
+    /// Reading or writing files failed, for a journal kept in a directory.
+    #[display(fmt = "i/o error: {}", _0)]
+    Io(io::Error),
+
+    /// Something stored could not be decoded.
+    #[display(fmt = "failed to decode: {}", _0)]
+    Decode(serde_cbor::Error),
+
+    /// An entry's signature does not match its contents or claimed author.
+    #[display(fmt = "bad signature on entry {:?}", _0)]
+    Signature(JournalKey),
+
+    /// A setting that is required to exist, such as this device's keys, is missing.
+    #[display(fmt = "missing setting {}", _0)]
+    MissingKey(String),
+
+    /// A conditional head update found the head had already been moved by another writer.
+    #[display(fmt = "head moved: expected {:?}, found {:?}", expected, found)]
+    HeadConflict {
+        expected: Option<JournalKey>,
+        found: Option<JournalKey>,
+    },
+
+    /// The database was written by a newer version with a schema this version doesn't know.
+    #[display(
+        fmt = "database schema version {} is newer than this version supports",
+        _0
+    )]
+    SchemaTooNew(u32),
+
+    /// Stored data is inconsistent, such as a head that points at a missing entry.
+    #[display(fmt = "integrity error: {}", _0)]
+    Integrity(String),
+
+    /// This device's clock can't advance without overflowing its counter, which only happens
+    /// after observing a value with an implausibly large counter.
+    #[display(fmt = "clock counter exhausted")]
+    ClockExhausted,
+}
+
+impl std::error::Error for JournalError {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        match self {
+            JournalError::Storage(e) => Some(e),
+            JournalError::Io(e) => Some(e),
+            JournalError::Decode(e) => Some(e),
+            _ => None,
+        }
+    }
+}
+
+impl From<rusqlite::Error> for JournalError {
+    fn from(e: rusqlite::Error) -> Self {
+        JournalError::Storage(e)
+    }
+}
+
+impl From<io::Error> for JournalError {
+    fn from(e: io::Error) -> Self {
+        JournalError::Io(e)
+    }
+}
+
+impl From<serde_cbor::Error> for JournalError {
+    fn from(e: serde_cbor::Error) -> Self {
+        JournalError::Decode(e)
+    }
+}
+
+impl From<JournalError> for io::Error {
+    fn from(e: JournalError) -> Self {
+        io::Error::new(io::ErrorKind::Other, e)
+    }
+}
+
+impl wasmi::HostError for JournalError {}
+
+/// An error for data from a file or peer that is malformed.
+pub(crate) fn invalid(msg: String) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, msg)
+}
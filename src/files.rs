@@ -0,0 +1,930 @@
+//! A [`Journal`] kept as plain files in a directory, like git's loose objects.
+//!
+//! The layout under the journal's directory is:
+//!
+//! - `format`: the layout version, see [`FileJournal::FORMAT_VERSION`].
+//! - `objects/<algorithm>/<xx>/<rest>`: each object's CBOR, named by its key in hex, split after
+//!   the first two digits so that no one directory gets too large. The contents hash to the name.
+//! - `entries/<algorithm>/<xx>/<rest>`: each signed entry's CBOR, named the same way.
+//! - `children/<parent path>/<child path>`: an empty file for each entry and parent, with both
+//!   keys laid out as above, so an entry's children can be found without reading every entry.
+//! - `packs/<name>.pack`: [packs](crate::pack) holding objects and entries that are not stored
+//!   loose. [`FileJournal::repack`] moves everything into a single pack.
+//! - `heads/<application>/<device>`: each head's entry key in its `algorithm:hex` form.
+//! - `settings/<name>`: each setting's raw value.
+//! - `lock`: locked by a writer while it changes anything. Since the lock is released when the
+//!   writer's process exits, however it exits, a leftover file never blocks later writers.
+//!
+//! Every file is written to `tmp` first and renamed into place, so readers only ever see complete
+//! files.
+
+use crate::pack::{Pack, PackWriter};
+use crate::{
+    store, transaction, ApplicationId, CASKey, CASObj, CasStore, DevicePublicKey, EntryStore,
+    HashAlgorithm, HeadStore, JournalError, JournalKey, KeyStore, Multihash, Result, SettingsStore,
+    Signed, Transactional,
+};
+use fs2::FileExt;
+use sodiumoxide::crypto::sign;
+use std::cell::{Ref, RefCell};
+use std::collections::{HashMap, HashSet};
+use std::fmt::Write as _;
+use std::fs::{self, File, OpenOptions};
+use std::io::{self, ErrorKind, Write};
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::thread;
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
+use uuid::Uuid;
+
+/// How long to wait for another writer to release the lock before giving up.
+const LOCK_TIMEOUT: Duration = Duration::from_secs(5);
+
+/// Distinguishes temporary files written by the same process.
+static TMP_COUNTER: AtomicUsize = AtomicUsize::new(0);
+
+/// A [`Journal`] stored as files in a directory, see the [module docs](self) for the layout.
+///
+/// Writes made inside a transaction are held in memory and only written out when the outermost
+/// transaction commits, with objects and entries first and heads last, so that a crash part way
+/// through never leaves a head pointing at something missing. Writes outside a transaction are
+/// each applied as their own transaction.
+///
+/// A lock on the `lock` file serializes writers, including ones in other processes. It is held
+/// for the whole of each outermost transaction.
+#[derive(Debug)]
+pub struct FileJournal {
+    root: PathBuf,
+
+    /// The writes of each open transaction, innermost last.
+    pending: RefCell<Vec<Changes>>,
+
+    /// Held while any transaction is open.
+    lock: RefCell<Option<Lock>>,
+
+    /// The packs in `packs`, opened when first needed.
+    packs: RefCell<Option<Vec<OpenPack>>>,
+}
+
+#[derive(Debug)]
+struct OpenPack {
+    name: String,
+    pack: Pack<File>,
+}
+
+/// What [`FileJournal::repack`] did.
+#[derive(Clone, Copy, PartialEq, Eq, Default, Debug)]
+pub struct RepackStats {
+    /// How many objects and entries the new pack holds.
+    pub objects: usize,
+    pub entries: usize,
+
+    /// How many loose files and old packs it replaced.
+    pub loose_removed: usize,
+    pub packs_removed: usize,
+}
+
+#[derive(Default, Debug)]
+struct Changes {
+    settings: HashMap<String, Vec<u8>>,
+    heads: HashMap<(ApplicationId, DevicePublicKey), JournalKey>,
+    entries: HashMap<JournalKey, Vec<u8>>,
+
+    /// Pairs of parent and child.
+    links: Vec<(JournalKey, JournalKey)>,
+
+    /// Objects to write, or to delete if `None`.
+    objects: HashMap<CASKey, Option<Vec<u8>>>,
+}
+
+impl Changes {
+    /// Adds `inner`'s writes, which were made after these, on top of them.
+    fn merge(&mut self, inner: Changes) {
+        self.settings.extend(inner.settings);
+        self.heads.extend(inner.heads);
+        self.entries.extend(inner.entries);
+        self.links.extend(inner.links);
+        self.objects.extend(inner.objects);
+    }
+}
+
+/// An exclusive lock on the lock file, released when dropped.
+#[derive(Debug)]
+struct Lock(File);
+
+impl Lock {
+    fn acquire(path: &Path) -> Result<Self> {
+        let file = OpenOptions::new()
+            .write(true)
+            .create(true)
+            .truncate(false)
+            .open(path)?;
+        let started = Instant::now();
+
+        loop {
+            match file.try_lock_exclusive() {
+                Ok(()) => return Ok(Lock(file)),
+                Err(ref e) if e.kind() == fs2::lock_contended_error().kind() => {
+                    if started.elapsed() > LOCK_TIMEOUT {
+                        return Err(JournalError::Io(io::Error::new(
+                            ErrorKind::TimedOut,
+                            format!("{} is held by another writer", path.display()),
+                        )));
+                    }
+
+                    thread::sleep(Duration::from_millis(10));
+                }
+                Err(e) => return Err(e.into()),
+            }
+        }
+    }
+}
+
+impl Drop for Lock {
+    fn drop(&mut self) {
+        let _ = self.0.unlock();
+    }
+}
+
+fn hex(data: &[u8]) -> String {
+    data.iter().fold(String::new(), |mut s, b| {
+        let _ = write!(s, "{:02x}", b);
+        s
+    })
+}
+
+fn from_hex(s: &str) -> Option<Vec<u8>> {
+    if s.len() % 2 == 1 {
+        return None;
+    }
+
+    s.as_bytes()
+        .chunks(2)
+        .map(|pair| u8::from_str_radix(std::str::from_utf8(pair).ok()?, 16).ok())
+        .collect()
+}
+
+/// Where the file for `hash` lives under `dir`.
+fn hash_path(dir: &Path, hash: Multihash) -> PathBuf {
+    let digest = hex(hash.digest());
+
+    dir.join(hash.algorithm().name())
+        .join(&digest[..2])
+        .join(&digest[2..])
+}
+
+/// Reads the names of the files directly in `dir`, which need not exist.
+fn read_names(dir: &Path) -> Result<Vec<String>> {
+    let entries = match fs::read_dir(dir) {
+        Ok(entries) => entries,
+        Err(e) if e.kind() == ErrorKind::NotFound => return Ok(Vec::new()),
+        Err(e) => return Err(e.into()),
+    };
+
+    let mut names = Vec::new();
+
+    for entry in entries {
+        // Anything not named by this journal, such as an editor's backup file, is skipped.
+        if let Ok(name) = entry?.file_name().into_string() {
+            names.push(name);
+        }
+    }
+
+    Ok(names)
+}
+
+/// Every hash with a file under `dir`, laid out as [`hash_path`] does.
+fn hash_list(dir: &Path) -> Result<Vec<Multihash>> {
+    let mut hashes = Vec::new();
+
+    for name in read_names(dir)? {
+        let algorithm = match HashAlgorithm::from_name(&name) {
+            Some(algorithm) => algorithm,
+            None => continue,
+        };
+
+        for prefix in read_names(&dir.join(&name))? {
+            for rest in read_names(&dir.join(&name).join(&prefix))? {
+                let digest = from_hex(&format!("{}{}", prefix, rest))
+                    .filter(|digest| digest.len() == crate::hash::DIGEST_LEN);
+
+                if let Some(digest) = digest {
+                    let mut bytes = [0; crate::hash::DIGEST_LEN];
+                    bytes.copy_from_slice(&digest);
+
+                    hashes.push(Multihash::new(algorithm, bytes));
+                }
+            }
+        }
+    }
+
+    Ok(hashes)
+}
+
+/// Reads a whole file, or `None` if it doesn't exist.
+fn read_file(path: &Path) -> Result<Option<Vec<u8>>> {
+    match fs::read(path) {
+        Ok(data) => Ok(Some(data)),
+        Err(e) if e.kind() == ErrorKind::NotFound => Ok(None),
+        Err(e) => Err(e.into()),
+    }
+}
+
+/// Setting names are escaped so that any name is a single, safe file name.
+fn setting_file_name(name: &str) -> String {
+    name.bytes()
+        .map(|b| {
+            if b.is_ascii_alphanumeric() || b == b'_' || b == b'-' {
+                (b as char).to_string()
+            } else {
+                format!("%{:02x}", b)
+            }
+        })
+        .collect()
+}
+
+fn now() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .unwrap_or_default()
+        .as_secs()
+}
+
+impl FileJournal {
+    /// The version of the on-disk layout this build reads and writes.
+    pub const FORMAT_VERSION: u32 = 1;
+
+    /// Opens the journal in the directory `path`, creating it with a freshly generated device
+    /// keypair if it doesn't exist.
+    pub fn new(path: &Path) -> Result<Self> {
+        fs::create_dir_all(path.join("tmp"))?;
+
+        let journal = Self {
+            root: path.to_path_buf(),
+            pending: RefCell::default(),
+            lock: RefCell::default(),
+            packs: RefCell::default(),
+        };
+
+        let format_path = path.join("format");
+
+        if let Some(format) = read_file(&format_path)? {
+            let version: u32 = String::from_utf8_lossy(&format)
+                .trim()
+                .parse()
+                .map_err(|_| JournalError::Integrity("malformed format file".to_string()))?;
+
+            if version > Self::FORMAT_VERSION {
+                return Err(JournalError::SchemaTooNew(version));
+            }
+        } else {
+            journal.write_file(
+                &format_path,
+                format!("{}\n", Self::FORMAT_VERSION).as_bytes(),
+            )?;
+        }
+
+        if journal.settings_get("PrivateKey")?.is_none() {
+            transaction(&journal, |journal| {
+                // Another process may have created the keys while this one waited for the lock.
+                if journal.settings_get("PrivateKey")?.is_none() {
+                    let (pubkey, privkey) = sign::gen_keypair();
+
+                    journal.settings_set("PublicKey", &pubkey[..])?;
+                    journal.settings_set("PrivateKey", &privkey[..])?;
+                }
+
+                Ok(())
+            })?;
+        }
+
+        Ok(journal)
+    }
+
+    fn object_path(&self, key: CASKey) -> PathBuf {
+        hash_path(&self.root.join("objects"), key.hash())
+    }
+
+    fn entry_path(&self, key: JournalKey) -> PathBuf {
+        hash_path(&self.root.join("entries"), key.hash())
+    }
+
+    fn children_path(&self, key: JournalKey) -> PathBuf {
+        hash_path(&self.root.join("children"), key.hash())
+    }
+
+    fn head_path(&self, appid: ApplicationId, device: DevicePublicKey) -> PathBuf {
+        self.root
+            .join("heads")
+            .join(appid.0.to_hyphenated().to_string())
+            .join(hex(&(device.0).0))
+    }
+
+    fn setting_path(&self, name: &str) -> PathBuf {
+        self.root.join("settings").join(setting_file_name(name))
+    }
+
+    /// A file name no other writer will use, even in another process.
+    fn unique_name() -> String {
+        format!(
+            "{}-{}-{}",
+            now(),
+            std::process::id(),
+            TMP_COUNTER.fetch_add(1, Ordering::Relaxed)
+        )
+    }
+
+    /// Writes `data` to `path` by way of a temporary file, replacing anything already there.
+    fn write_file(&self, path: &Path, data: &[u8]) -> Result<()> {
+        let tmp = self.root.join("tmp").join(Self::unique_name());
+
+        let mut file = File::create(&tmp)?;
+        file.write_all(data)?;
+        file.sync_all()?;
+
+        if let Some(parent) = path.parent() {
+            fs::create_dir_all(parent)?;
+        }
+
+        fs::rename(&tmp, path).map_err(|e| {
+            let _ = fs::remove_file(&tmp);
+            e.into()
+        })
+    }
+
+    /// Makes a write, as part of the open transaction if there is one and as its own otherwise.
+    fn change(&self, f: impl FnOnce(&mut Changes)) -> Result<()> {
+        if self.pending.borrow().is_empty() {
+            return transaction(self, |journal| journal.change(f));
+        }
+
+        f(self
+            .pending
+            .borrow_mut()
+            .last_mut()
+            .expect("checked there is a transaction"));
+
+        Ok(())
+    }
+
+    /// The newest pending value `f` finds in any open transaction.
+    fn find_pending<T>(&self, f: impl Fn(&Changes) -> Option<T>) -> Option<T> {
+        self.pending.borrow().iter().rev().find_map(f)
+    }
+
+    fn pack_names(&self) -> Result<Vec<String>> {
+        let mut names: Vec<String> = read_names(&self.root.join("packs"))?
+            .into_iter()
+            .filter(|name| Path::new(name).extension() == Some("pack".as_ref()))
+            .collect();
+
+        names.sort();
+
+        Ok(names)
+    }
+
+    /// The open packs, reopening them all if `refresh` is set or they haven't been opened yet.
+    fn packs(&self, refresh: bool) -> Result<Ref<'_, Vec<OpenPack>>> {
+        if refresh || self.packs.borrow().is_none() {
+            let mut packs = Vec::new();
+
+            for name in self.pack_names()? {
+                let file = File::open(self.root.join("packs").join(&name))?;
+
+                packs.push(OpenPack {
+                    name,
+                    pack: Pack::open(file)?,
+                });
+            }
+
+            *self.packs.borrow_mut() = Some(packs);
+        }
+
+        Ok(Ref::map(self.packs.borrow(), |packs| {
+            packs.as_ref().expect("packs were just opened")
+        }))
+    }
+
+    /// Looks something up in the packs with `f`. If it isn't found and another writer has since
+    /// added or replaced packs, looks again in the new ones.
+    fn find_packed<T>(&self, f: impl Fn(&OpenPack) -> Result<Option<T>>) -> Result<Option<T>> {
+        for refresh in &[false, true] {
+            let packs = self.packs(*refresh)?;
+
+            for pack in packs.iter() {
+                if let Some(found) = f(pack)? {
+                    return Ok(Some(found));
+                }
+            }
+
+            let names: Vec<&String> = packs.iter().map(|pack| &pack.name).collect();
+
+            if names == self.pack_names()?.iter().collect::<Vec<_>>() {
+                break;
+            }
+        }
+
+        Ok(None)
+    }
+
+    /// Writes a pack with `f`, then moves it into `packs`. Returns its name, or `None` if `f`
+    /// added nothing and so no pack was kept.
+    fn write_pack(
+        &self,
+        f: impl FnOnce(&mut PackWriter<File>) -> Result<()>,
+    ) -> Result<Option<String>> {
+        let tmp = self.root.join("tmp").join(Self::unique_name());
+
+        let result = File::create(&tmp)
+            .map_err(JournalError::from)
+            .and_then(PackWriter::new)
+            .and_then(|mut writer| {
+                f(&mut writer)?;
+                let empty = writer.is_empty();
+                writer.finish()?.sync_all()?;
+                Ok(empty)
+            });
+
+        match result {
+            Ok(false) => {
+                let name = format!("{}.pack", Self::unique_name());
+
+                fs::create_dir_all(self.root.join("packs"))?;
+                fs::rename(&tmp, self.root.join("packs").join(&name))?;
+
+                Ok(Some(name))
+            }
+            Ok(true) => {
+                fs::remove_file(&tmp)?;
+                Ok(None)
+            }
+            Err(e) => {
+                let _ = fs::remove_file(&tmp);
+                Err(e)
+            }
+        }
+    }
+
+    /// Copies everything in `pack` except the objects in `except` into `writer`.
+    fn copy_pack(
+        pack: &Pack<File>,
+        writer: &mut PackWriter<File>,
+        except: &HashSet<CASKey>,
+    ) -> Result<()> {
+        for key in pack.objects() {
+            if !except.contains(&key) {
+                if let Some(obj) = pack.object(key)? {
+                    writer.add_object(key, &obj)?;
+                }
+            }
+        }
+
+        for key in pack.entries() {
+            if let Some(signed) = pack.entry(key)? {
+                writer.add_entry(key, &signed)?;
+            }
+        }
+
+        Ok(())
+    }
+
+    /// Replaces each pack holding any of `deleted` with one without them.
+    fn remove_packed(&self, deleted: &HashSet<CASKey>) -> Result<()> {
+        let affected: Vec<String> = self
+            .packs(true)?
+            .iter()
+            .filter(|open| deleted.iter().any(|&key| open.pack.contains_object(key)))
+            .map(|open| open.name.clone())
+            .collect();
+
+        for name in affected {
+            let path = self.root.join("packs").join(&name);
+            let pack = Pack::open(File::open(&path)?)?;
+
+            self.write_pack(|writer| Self::copy_pack(&pack, writer, deleted))?;
+
+            fs::remove_file(&path)?;
+        }
+
+        *self.packs.borrow_mut() = None;
+
+        Ok(())
+    }
+
+    /// Moves every loose object and entry, and everything in existing packs, into a single new
+    /// pack, then removes the files and packs it replaces. Can't be called inside a transaction.
+    pub fn repack(&self) -> Result<RepackStats> {
+        if !self.pending.borrow().is_empty() {
+            return Err(JournalError::Integrity(
+                "can't repack inside a transaction".to_string(),
+            ));
+        }
+
+        // The transaction writes nothing itself, but holds the lock while files are replaced.
+        transaction(self, |journal| {
+            let objects = hash_list(&journal.root.join("objects"))?;
+            let entries = hash_list(&journal.root.join("entries"))?;
+            let old_packs = journal.pack_names()?;
+
+            let mut stats = RepackStats::default();
+
+            journal.write_pack(|writer| {
+                for open in journal.packs(true)?.iter() {
+                    Self::copy_pack(&open.pack, writer, &HashSet::new())?;
+                }
+
+                for &hash in &objects {
+                    let key = CASKey::new(hash);
+
+                    if let Some(data) = read_file(&journal.object_path(key))? {
+                        writer.add_object(key, &serde_cbor::from_slice(&data)?)?;
+                    }
+                }
+
+                for &hash in &entries {
+                    let key = JournalKey::new(hash);
+
+                    if let Some(data) = read_file(&journal.entry_path(key))? {
+                        writer.add_entry(key, &serde_cbor::from_slice(&data)?)?;
+                    }
+                }
+
+                Ok(())
+            })?;
+
+            for &hash in &objects {
+                fs::remove_file(journal.object_path(CASKey::new(hash)))?;
+            }
+
+            for &hash in &entries {
+                fs::remove_file(journal.entry_path(JournalKey::new(hash)))?;
+            }
+
+            for name in &old_packs {
+                fs::remove_file(journal.root.join("packs").join(name))?;
+            }
+
+            let packs = journal.packs(true)?;
+
+            stats.objects = packs.iter().map(|open| open.pack.objects().len()).sum();
+            stats.entries = packs.iter().map(|open| open.pack.entries().len()).sum();
+            stats.loose_removed = objects.len() + entries.len();
+            stats.packs_removed = old_packs.len();
+
+            Ok(stats)
+        })
+    }
+
+    fn disk_heads(&self) -> Result<HashMap<(ApplicationId, DevicePublicKey), JournalKey>> {
+        let mut heads = HashMap::new();
+        let dir = self.root.join("heads");
+
+        for app in read_names(&dir)? {
+            let appid = match Uuid::parse_str(&app) {
+                Ok(uuid) => ApplicationId(uuid),
+                Err(_) => continue,
+            };
+
+            for device in read_names(&dir.join(&app))? {
+                let pubkey = match from_hex(&device).and_then(|d| sign::PublicKey::from_slice(&d)) {
+                    Some(pubkey) => DevicePublicKey(pubkey),
+                    None => continue,
+                };
+
+                let data = match read_file(&dir.join(&app).join(&device))? {
+                    Some(data) => data,
+                    None => continue,
+                };
+
+                let key = String::from_utf8_lossy(&data).trim().parse().map_err(|_| {
+                    JournalError::Integrity(format!("malformed head {}/{}", app, device))
+                })?;
+
+                heads.insert((appid, pubkey), key);
+            }
+        }
+
+        Ok(heads)
+    }
+
+    /// Writes out everything in `changes`, heads last.
+    fn flush(&self, changes: Changes) -> Result<()> {
+        let mut deleted = HashSet::new();
+
+        for (key, data) in changes.objects {
+            // Rewriting an existing object is what updates the time it was written.
+            if let Some(data) = data {
+                self.write_file(&self.object_path(key), &data)?;
+                continue;
+            }
+
+            match fs::remove_file(self.object_path(key)) {
+                Err(e) if e.kind() != ErrorKind::NotFound => return Err(e.into()),
+                _ => {}
+            }
+
+            deleted.insert(key);
+        }
+
+        if !deleted.is_empty() {
+            self.remove_packed(&deleted)?;
+        }
+
+        for (key, data) in changes.entries {
+            let path = self.entry_path(key);
+
+            if !path.exists() {
+                self.write_file(&path, &data)?;
+            }
+        }
+
+        for (parent, child) in changes.links {
+            let path = hash_path(&self.children_path(parent), child.hash());
+
+            if !path.exists() {
+                self.write_file(&path, &[])?;
+            }
+        }
+
+        for (name, value) in changes.settings {
+            self.write_file(&self.setting_path(&name), &value)?;
+        }
+
+        for ((appid, device), key) in changes.heads {
+            self.write_file(
+                &self.head_path(appid, device),
+                format!("{}\n", key).as_bytes(),
+            )?;
+        }
+
+        Ok(())
+    }
+}
+
+impl Transactional for FileJournal {
+    fn begin_transaction(&self) -> Result<()> {
+        if self.pending.borrow().is_empty() {
+            *self.lock.borrow_mut() = Some(Lock::acquire(&self.root.join("lock"))?);
+        }
+
+        self.pending.borrow_mut().push(Changes::default());
+
+        Ok(())
+    }
+
+    fn commit_transaction(&self) -> Result<()> {
+        let changes = self
+            .pending
+            .borrow_mut()
+            .pop()
+            .ok_or_else(|| JournalError::Integrity("no transaction to commit".to_string()))?;
+
+        if let Some(outer) = self.pending.borrow_mut().last_mut() {
+            outer.merge(changes);
+            return Ok(());
+        }
+
+        // Whether or not it succeeds the transaction is over, so the lock is released after.
+        let result = self.flush(changes);
+
+        self.lock.borrow_mut().take();
+
+        result
+    }
+
+    fn rollback_transaction(&self) -> Result<()> {
+        self.pending
+            .borrow_mut()
+            .pop()
+            .ok_or_else(|| JournalError::Integrity("no transaction to roll back".to_string()))?;
+
+        if self.pending.borrow().is_empty() {
+            self.lock.borrow_mut().take();
+        }
+
+        Ok(())
+    }
+}
+
+impl SettingsStore for FileJournal {
+    fn settings_get(&self, key: &str) -> Result<Option<Vec<u8>>> {
+        match self.find_pending(|c| c.settings.get(key).cloned()) {
+            Some(value) => Ok(Some(value)),
+            None => read_file(&self.setting_path(key)),
+        }
+    }
+
+    fn settings_set(&self, key: &str, value: &[u8]) -> Result<()> {
+        self.change(|c| {
+            c.settings.insert(key.to_string(), value.to_vec());
+        })
+    }
+}
+
+impl KeyStore for FileJournal {
+    fn pubkey(&self) -> Result<DevicePublicKey> {
+        store::settings_pubkey(self)
+    }
+
+    fn privkey(&self) -> Result<sign::SecretKey> {
+        store::settings_privkey(self)
+    }
+}
+
+impl HeadStore for FileJournal {
+    fn heads(&self) -> Result<HashMap<(ApplicationId, DevicePublicKey), JournalKey>> {
+        let mut heads = self.disk_heads()?;
+
+        for changes in self.pending.borrow().iter() {
+            heads.extend(&changes.heads);
+        }
+
+        Ok(heads)
+    }
+
+    fn update_head(
+        &self,
+        device: DevicePublicKey,
+        appid: ApplicationId,
+        key: JournalKey,
+    ) -> Result<()> {
+        self.change(|c| {
+            c.heads.insert((appid, device), key);
+        })
+    }
+
+    fn update_head_if(
+        &self,
+        device: DevicePublicKey,
+        appid: ApplicationId,
+        expected: Option<JournalKey>,
+        key: JournalKey,
+    ) -> Result<()> {
+        // Holding the lock for the whole transaction keeps the head from moving in between.
+        transaction(self, |journal| {
+            let found = journal.heads()?.get(&(appid, device)).copied();
+
+            if found != expected {
+                return Err(JournalError::HeadConflict { expected, found });
+            }
+
+            journal.update_head(device, appid, key)
+        })
+    }
+}
+
+impl EntryStore for FileJournal {
+    fn get_signed(&self, key: JournalKey) -> Result<Option<Signed>> {
+        let data = match self.find_pending(|c| c.entries.get(&key).cloned()) {
+            Some(data) => Some(data),
+            None => read_file(&self.entry_path(key))?,
+        };
+
+        match data {
+            Some(data) => Ok(Some(serde_cbor::from_slice(&data)?)),
+            None => self.find_packed(|open| open.pack.entry(key)),
+        }
+    }
+
+    fn put_signed_with(&self, signed: &Signed, algorithm: HashAlgorithm) -> Result<JournalKey> {
+        let signed_ser = serde_cbor::to_vec(signed)?;
+
+        let key = JournalKey::new(algorithm.digest(&signed_ser));
+
+        let entry = signed.verify().ok_or(JournalError::Signature(key))?;
+
+        self.change(|c| {
+            c.entries.insert(key, signed_ser);
+            c.links
+                .extend(entry.parents().iter().map(|&parent| (parent, key)));
+        })?;
+
+        Ok(key)
+    }
+
+    fn children(&self, key: JournalKey) -> Result<Vec<JournalKey>> {
+        let mut children: Vec<JournalKey> = hash_list(&self.children_path(key))?
+            .into_iter()
+            .map(JournalKey::new)
+            .collect();
+
+        for changes in self.pending.borrow().iter() {
+            for &(parent, child) in &changes.links {
+                if parent == key && !children.contains(&child) {
+                    children.push(child);
+                }
+            }
+        }
+
+        Ok(children)
+    }
+
+    fn entry_list(&self) -> Result<Vec<JournalKey>> {
+        let mut entries: HashSet<JournalKey> = hash_list(&self.root.join("entries"))?
+            .into_iter()
+            .map(JournalKey::new)
+            .collect();
+
+        for open in self.packs(true)?.iter() {
+            entries.extend(open.pack.entries());
+        }
+
+        for changes in self.pending.borrow().iter() {
+            entries.extend(changes.entries.keys());
+        }
+
+        Ok(entries.into_iter().collect())
+    }
+}
+
+impl CasStore for FileJournal {
+    fn cas_get(&self, key: CASKey) -> Result<Option<CASObj>> {
+        let data = match self.find_pending(|c| c.objects.get(&key).cloned()) {
+            Some(None) => return Ok(None),
+            Some(data) => data,
+            None => read_file(&self.object_path(key))?,
+        };
+
+        match data {
+            Some(data) => Ok(Some(serde_cbor::from_slice(&data)?)),
+            None => self.find_packed(|open| open.pack.object(key)),
+        }
+    }
+
+    fn cas_put_with(&self, obj: CASObj, algorithm: HashAlgorithm) -> Result<CASKey> {
+        let data = serde_cbor::to_vec(&obj)?;
+
+        let key = CASKey::new(algorithm.digest(&data));
+
+        self.change(|c| {
+            c.objects.insert(key, Some(data));
+        })?;
+
+        Ok(key)
+    }
+
+    fn cas_list(&self) -> Result<Vec<CASKey>> {
+        let mut objects: HashSet<CASKey> = hash_list(&self.root.join("objects"))?
+            .into_iter()
+            .map(CASKey::new)
+            .collect();
+
+        for open in self.packs(true)?.iter() {
+            objects.extend(open.pack.objects());
+        }
+
+        for changes in self.pending.borrow().iter() {
+            for (&key, data) in &changes.objects {
+                if data.is_some() {
+                    objects.insert(key);
+                } else {
+                    objects.remove(&key);
+                }
+            }
+        }
+
+        Ok(objects.into_iter().collect())
+    }
+
+    fn cas_delete(&self, key: CASKey) -> Result<()> {
+        self.change(|c| {
+            c.objects.insert(key, None);
+        })
+    }
+
+    fn cas_written(&self, key: CASKey) -> Result<Option<u64>> {
+        match self.find_pending(|c| c.objects.get(&key).map(Option::is_some)) {
+            Some(true) => return Ok(Some(now())),
+            Some(false) => return Ok(None),
+            None => {}
+        }
+
+        // Packed objects count as written when their pack was.
+        let path = if self.object_path(key).exists() {
+            self.object_path(key)
+        } else {
+            let packed = self.find_packed(|open| {
+                Ok(if open.pack.contains_object(key) {
+                    Some(open.name.clone())
+                } else {
+                    None
+                })
+            })?;
+
+            match packed {
+                Some(name) => self.root.join("packs").join(name),
+                None => return Ok(None),
+            }
+        };
+
+        match fs::metadata(path) {
+            Ok(metadata) => Ok(metadata
+                .modified()?
+                .duration_since(UNIX_EPOCH)
+                .ok()
+                .map(|d| d.as_secs())),
+            Err(e) if e.kind() == ErrorKind::NotFound => Ok(None),
+            Err(e) => Err(e.into()),
+        }
+    }
+}
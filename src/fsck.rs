@@ -0,0 +1,379 @@
+//! Checking a journal for corruption and inconsistencies.
+//!
+//! [`verify`] reads everything stored and reports every problem it finds rather than stopping at
+//! the first. Each [`Problem`] displays as a single line: a kind followed by `name=value` fields,
+//! with keys in their `algorithm:hex` form, so reports are easy to process with other tools.
+
+use crate::{
+    ApplicationId, CASKey, CASObj, DevicePublicKey, Journal, JournalError, JournalKey, Result,
+    Signed,
+};
+use std::collections::HashSet;
+use std::fmt;
+
+#[derive(Clone, PartialEq, Eq, Debug)]
+pub enum Problem {
+    /// An object is stored under an id that doesn't decode as a key.
+    UndecodableObjectId { id: Vec<u8> },
+
+    /// An entry is stored under an id that doesn't decode as a key.
+    UndecodableEntryId { id: Vec<u8> },
+
+    /// An object could not be read back, for example because it doesn't decode.
+    UnreadableObject { key: CASKey, error: String },
+
+    /// An object's contents don't hash to the key it is stored under.
+    ObjectHashMismatch { key: CASKey, actual: CASKey },
+
+    /// An object links to an object that isn't stored.
+    DanglingLink { object: CASKey, link: CASKey },
+
+    /// An entry could not be read back, for example because it doesn't decode.
+    UnreadableEntry { key: JournalKey, error: String },
+
+    /// An entry's contents don't hash to the key it is stored under.
+    EntryHashMismatch { key: JournalKey, actual: JournalKey },
+
+    /// An entry's signature doesn't match its contents or claimed author.
+    BadSignature { key: JournalKey },
+
+    /// An entry has a parent that isn't stored.
+    DanglingParent {
+        entry: JournalKey,
+        parent: JournalKey,
+    },
+
+    /// An entry's state object isn't stored.
+    MissingState { entry: JournalKey, state: CASKey },
+
+    /// A head points at an entry that isn't stored.
+    MissingHead {
+        application: ApplicationId,
+        device: DevicePublicKey,
+        entry: JournalKey,
+    },
+
+    /// A head points at an entry signed by a different device than the head belongs to.
+    WrongHeadDevice {
+        application: ApplicationId,
+        device: DevicePublicKey,
+        entry: JournalKey,
+        signer: DevicePublicKey,
+    },
+
+    /// A head points at an entry for a different application than the head belongs to.
+    WrongHeadApplication {
+        application: ApplicationId,
+        device: DevicePublicKey,
+        entry: JournalKey,
+        actual: ApplicationId,
+    },
+}
+
+struct Hex<'a>(&'a [u8]);
+
+impl fmt::Display for Hex<'_> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        for byte in self.0 {
+            write!(f, "{:02x}", byte)?;
+        }
+
+        Ok(())
+    }
+}
+
+impl fmt::Display for Problem {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Problem::UndecodableObjectId { id } => {
+                write!(f, "undecodable-object-id id={}", Hex(id))
+            }
+            Problem::UndecodableEntryId { id } => {
+                write!(f, "undecodable-entry-id id={}", Hex(id))
+            }
+            Problem::UnreadableObject { key, error } => {
+                write!(f, "unreadable-object key={} error={:?}", key, error)
+            }
+            Problem::ObjectHashMismatch { key, actual } => {
+                write!(f, "object-hash-mismatch key={} actual={}", key, actual)
+            }
+            Problem::DanglingLink { object, link } => {
+                write!(f, "dangling-link object={} link={}", object, link)
+            }
+            Problem::UnreadableEntry { key, error } => {
+                write!(f, "unreadable-entry key={} error={:?}", key, error)
+            }
+            Problem::EntryHashMismatch { key, actual } => {
+                write!(f, "entry-hash-mismatch key={} actual={}", key, actual)
+            }
+            Problem::BadSignature { key } => write!(f, "bad-signature key={}", key),
+            Problem::DanglingParent { entry, parent } => {
+                write!(f, "dangling-parent entry={} parent={}", entry, parent)
+            }
+            Problem::MissingState { entry, state } => {
+                write!(f, "missing-state entry={} state={}", entry, state)
+            }
+            Problem::MissingHead {
+                application,
+                device,
+                entry,
+            } => write!(
+                f,
+                "missing-head application={} device={} entry={}",
+                application.0,
+                Hex(&(device.0).0),
+                entry
+            ),
+            Problem::WrongHeadDevice {
+                application,
+                device,
+                entry,
+                signer,
+            } => write!(
+                f,
+                "wrong-head-device application={} device={} entry={} signer={}",
+                application.0,
+                Hex(&(device.0).0),
+                entry,
+                Hex(&(signer.0).0)
+            ),
+            Problem::WrongHeadApplication {
+                application,
+                device,
+                entry,
+                actual,
+            } => write!(
+                f,
+                "wrong-head-application application={} device={} entry={} actual={}",
+                application.0,
+                Hex(&(device.0).0),
+                entry,
+                actual.0
+            ),
+        }
+    }
+}
+
+#[derive(Clone, PartialEq, Eq, Default, Debug)]
+pub struct Report {
+    pub objects_checked: usize,
+    pub entries_checked: usize,
+    pub heads_checked: usize,
+    pub problems: Vec<Problem>,
+}
+
+impl Report {
+    pub fn is_ok(&self) -> bool {
+        self.problems.is_empty()
+    }
+}
+
+/// Sorts the result of reading one stored item: problems with the item itself are returned as
+/// `Ok(Err(..))` to be reported, while failures of the store are passed on.
+fn readable<T>(result: Result<Option<T>>) -> Result<std::result::Result<Option<T>, String>> {
+    match result {
+        Ok(value) => Ok(Ok(value)),
+        Err(JournalError::Storage(e)) => Err(JournalError::Storage(e)),
+        Err(JournalError::Io(e)) => Err(JournalError::Io(e)),
+        Err(e) => Ok(Err(e.to_string())),
+    }
+}
+
+/// Checks every object, entry and head in `journal`.
+///
+/// Fails only if the journal itself can't be read. Anything wrong with what is stored is
+/// reported in the [`Report`] instead.
+pub fn verify(journal: &dyn Journal) -> Result<Report> {
+    let mut report = Report::default();
+
+    let mut objects = HashSet::new();
+
+    for id in journal.cas_ids()? {
+        match id {
+            Ok(key) => {
+                objects.insert(key);
+            }
+            Err(id) => {
+                report.objects_checked += 1;
+                report.problems.push(Problem::UndecodableObjectId { id });
+            }
+        }
+    }
+
+    let mut entries = HashSet::new();
+
+    for id in journal.entry_ids()? {
+        match id {
+            Ok(key) => {
+                entries.insert(key);
+            }
+            Err(id) => {
+                report.entries_checked += 1;
+                report.problems.push(Problem::UndecodableEntryId { id });
+            }
+        }
+    }
+
+    verify_objects(journal, &objects, &mut report)?;
+    verify_entries(journal, &entries, &objects, &mut report)?;
+    verify_heads(journal, &mut report)?;
+
+    Ok(report)
+}
+
+/// Whether `key` is one of the listed `objects`, or can otherwise be read, such as from an
+/// [archive](crate::overlay::Archive) that is not listed.
+fn has_object(journal: &dyn Journal, objects: &HashSet<CASKey>, key: CASKey) -> Result<bool> {
+    if objects.contains(&key) {
+        return Ok(true);
+    }
+
+    Ok(!matches!(readable(journal.cas_get(key))?, Ok(None)))
+}
+
+fn has_entry(
+    journal: &dyn Journal,
+    entries: &HashSet<JournalKey>,
+    key: JournalKey,
+) -> Result<bool> {
+    if entries.contains(&key) {
+        return Ok(true);
+    }
+
+    Ok(!matches!(readable(journal.get_signed(key))?, Ok(None)))
+}
+
+fn verify_objects(
+    journal: &dyn Journal,
+    objects: &HashSet<CASKey>,
+    report: &mut Report,
+) -> Result<()> {
+    for &key in objects {
+        report.objects_checked += 1;
+
+        let obj: CASObj = match readable(journal.cas_get(key))? {
+            Ok(Some(obj)) => obj,
+            Ok(None) => continue,
+            Err(error) => {
+                report
+                    .problems
+                    .push(Problem::UnreadableObject { key, error });
+                continue;
+            }
+        };
+
+        let actual = obj.key_with(key.algorithm());
+
+        if actual != key {
+            report
+                .problems
+                .push(Problem::ObjectHashMismatch { key, actual });
+        }
+
+        for &link in &obj.links {
+            if !has_object(journal, objects, link)? {
+                report
+                    .problems
+                    .push(Problem::DanglingLink { object: key, link });
+            }
+        }
+    }
+
+    Ok(())
+}
+
+fn verify_entries(
+    journal: &dyn Journal,
+    entries: &HashSet<JournalKey>,
+    objects: &HashSet<CASKey>,
+    report: &mut Report,
+) -> Result<()> {
+    for &key in entries {
+        report.entries_checked += 1;
+
+        let signed: Signed = match readable(journal.get_signed(key))? {
+            Ok(Some(signed)) => signed,
+            Ok(None) => continue,
+            Err(error) => {
+                report
+                    .problems
+                    .push(Problem::UnreadableEntry { key, error });
+                continue;
+            }
+        };
+
+        let actual = signed.key_with(key.algorithm());
+
+        if actual != key {
+            report
+                .problems
+                .push(Problem::EntryHashMismatch { key, actual });
+        }
+
+        let entry = if let Some(entry) = signed.verify() {
+            entry
+        } else {
+            report.problems.push(Problem::BadSignature { key });
+            continue;
+        };
+
+        for &parent in entry.parents() {
+            if !has_entry(journal, entries, parent)? {
+                report
+                    .problems
+                    .push(Problem::DanglingParent { entry: key, parent });
+            }
+        }
+
+        if !has_object(journal, objects, entry.new_state())? {
+            report.problems.push(Problem::MissingState {
+                entry: key,
+                state: entry.new_state(),
+            });
+        }
+    }
+
+    Ok(())
+}
+
+fn verify_heads(journal: &dyn Journal, report: &mut Report) -> Result<()> {
+    for ((application, device), entry) in journal.heads()? {
+        report.heads_checked += 1;
+
+        let signed = match readable(journal.get_signed(entry))? {
+            Ok(Some(signed)) => signed,
+            Ok(None) => {
+                report.problems.push(Problem::MissingHead {
+                    application,
+                    device,
+                    entry,
+                });
+                continue;
+            }
+            // Unreadable entries were already reported with the other entries.
+            Err(_) => continue,
+        };
+
+        if signed.device() != device {
+            report.problems.push(Problem::WrongHeadDevice {
+                application,
+                device,
+                entry,
+                signer: signed.device(),
+            });
+        }
+
+        if let Some(signed_entry) = signed.verify() {
+            if signed_entry.application_id() != application {
+                report.problems.push(Problem::WrongHeadApplication {
+                    application,
+                    device,
+                    entry,
+                    actual: signed_entry.application_id(),
+                });
+            }
+        }
+    }
+
+    Ok(())
+}
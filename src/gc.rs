@@ -0,0 +1,107 @@
+//! Removing CAS objects that no head can reach any more.
+
+use crate::{transaction, CASKey, Journal, Result};
+use std::collections::HashSet;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub struct GcOptions {
+    /// Report what would be removed without removing anything.
+    pub dry_run: bool,
+
+    /// Objects written more recently than this are kept even if unreachable, since an application
+    /// may be about to commit a state that refers to them.
+    pub grace_period: Duration,
+}
+
+impl Default for GcOptions {
+    fn default() -> Self {
+        Self {
+            dry_run: false,
+            grace_period: Duration::from_secs(60 * 60),
+        }
+    }
+}
+
+#[derive(Clone, PartialEq, Eq, Default, Debug)]
+pub struct GcReport {
+    /// How many stored objects are reachable from some head.
+    pub reachable: usize,
+
+    /// Unreachable objects that were removed, or would be on a dry run.
+    pub swept: Vec<CASKey>,
+
+    /// Unreachable objects kept because they are inside the grace period.
+    pub recent: Vec<CASKey>,
+}
+
+/// Marks every object reachable from any head, through entry parents, entry states and object
+/// links, then sweeps every other object that is older than the grace period.
+pub fn gc(journal: &dyn Journal, options: &GcOptions) -> Result<GcReport> {
+    let reachable = mark(journal)?;
+
+    let now = SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .unwrap_or_default()
+        .as_secs();
+    let cutoff = now.saturating_sub(options.grace_period.as_secs());
+
+    let mut report = GcReport::default();
+
+    for key in journal.cas_list()? {
+        if reachable.contains(&key) {
+            report.reachable += 1;
+            continue;
+        }
+
+        match journal.cas_written(key)? {
+            Some(written) if written > cutoff => report.recent.push(key),
+            _ => report.swept.push(key),
+        }
+    }
+
+    if !options.dry_run {
+        transaction(journal, |journal| {
+            for &key in &report.swept {
+                journal.cas_delete(key)?;
+            }
+
+            Ok(())
+        })?;
+    }
+
+    Ok(report)
+}
+
+/// Everything reachable from the journal's heads. Missing entries and objects are skipped, since
+/// there is nothing to keep for them.
+fn mark(journal: &dyn Journal) -> Result<HashSet<CASKey>> {
+    let mut seen_entries = HashSet::new();
+    let mut entry_queue: Vec<_> = journal.heads()?.values().copied().collect();
+
+    let mut reachable = HashSet::new();
+    let mut object_queue = Vec::new();
+
+    while let Some(key) = entry_queue.pop() {
+        if !seen_entries.insert(key) {
+            continue;
+        }
+
+        if let Some(entry) = journal.get(key)? {
+            entry_queue.extend(entry.parents);
+            object_queue.push(entry.new_state);
+        }
+    }
+
+    while let Some(key) = object_queue.pop() {
+        if !reachable.insert(key) {
+            continue;
+        }
+
+        if let Some(obj) = journal.cas_get(key)? {
+            object_queue.extend(obj.links);
+        }
+    }
+
+    Ok(reachable)
+}
@@ -0,0 +1,274 @@
+//! Hashes that say which algorithm produced them, in the style of multihash.
+//!
+//! Keys were once bare SHA-256 digests, so a bare 32 byte digest, in storage or in CBOR, is read
+//! as SHA-256, and SHA-256 hashes are still written that way so that every existing key and
+//! signature stays valid. Hashes from other algorithms are written as multihash bytes: the
+//! algorithm's code and the digest length as varints, then the digest.
+
+use derive_more::Display;
+use serde::de::{self, Deserializer, SeqAccess, Visitor};
+use serde::{Deserialize, Serialize, Serializer};
+use sodiumoxide::crypto::generichash;
+use sodiumoxide::crypto::hash::sha256;
+use std::fmt;
+use std::str::FromStr;
+
+/// The length of every supported digest.
+pub const DIGEST_LEN: usize = 32;
+
+#[derive(Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash, Debug)]
+pub enum HashAlgorithm {
+    Sha256,
+    Blake2b256,
+}
+
+impl HashAlgorithm {
+    /// Every supported algorithm.
+    pub const ALL: &'static [HashAlgorithm] = &[HashAlgorithm::Sha256, HashAlgorithm::Blake2b256];
+
+    /// The algorithm's multihash code.
+    pub fn code(self) -> u64 {
+        match self {
+            HashAlgorithm::Sha256 => 0x12,
+            HashAlgorithm::Blake2b256 => 0xb220,
+        }
+    }
+
+    pub fn from_code(code: u64) -> Option<Self> {
+        Self::ALL.iter().copied().find(|a| a.code() == code)
+    }
+
+    /// The algorithm's multihash name, as used in the string form of keys.
+    pub fn name(self) -> &'static str {
+        match self {
+            HashAlgorithm::Sha256 => "sha2-256",
+            HashAlgorithm::Blake2b256 => "blake2b-256",
+        }
+    }
+
+    pub fn from_name(name: &str) -> Option<Self> {
+        Self::ALL.iter().copied().find(|a| a.name() == name)
+    }
+
+    /// Hashes `data` with this algorithm.
+    pub fn digest(self, data: &[u8]) -> Multihash {
+        let mut digest = [0; DIGEST_LEN];
+
+        match self {
+            HashAlgorithm::Sha256 => digest.copy_from_slice(sha256::hash(data).as_ref()),
+            HashAlgorithm::Blake2b256 => {
+                let mut state = generichash::State::new(Some(DIGEST_LEN), None)
+                    .expect("32 bytes is a valid blake2b digest length");
+                state.update(data).expect("failed to hash");
+                digest.copy_from_slice(state.finalize().expect("failed to hash").as_ref());
+            }
+        }
+
+        Multihash {
+            algorithm: self,
+            digest,
+        }
+    }
+}
+
+impl Default for HashAlgorithm {
+    /// SHA-256, since every device understands it.
+    fn default() -> Self {
+        HashAlgorithm::Sha256
+    }
+}
+
+impl fmt::Display for HashAlgorithm {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(self.name())
+    }
+}
+
+/// A digest together with the algorithm that produced it.
+#[derive(Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub struct Multihash {
+    algorithm: HashAlgorithm,
+    digest: [u8; DIGEST_LEN],
+}
+
+#[derive(Debug, Display, Clone, PartialEq, Eq)]
+#[display(fmt = "invalid key {:?}", _0)]
+pub struct ParseKeyError(String);
+
+impl std::error::Error for ParseKeyError {}
+
+// Each byte holds the next 7 bits, so the truncating casts are intended.
+#[allow(clippy::cast_possible_truncation)]
+pub(crate) fn write_varint(out: &mut Vec<u8>, mut value: u64) {
+    while value >= 0x80 {
+        out.push((value as u8 & 0x7f) | 0x80);
+        value >>= 7;
+    }
+
+    out.push(value as u8);
+}
+
+pub(crate) fn read_varint(data: &mut &[u8]) -> Option<u64> {
+    let mut value: u64 = 0;
+
+    for shift in (0..64).step_by(7) {
+        let (&byte, rest) = data.split_first()?;
+        *data = rest;
+
+        value |= u64::from(byte & 0x7f) << shift;
+
+        if byte & 0x80 == 0 {
+            return Some(value);
+        }
+    }
+
+    None
+}
+
+impl Multihash {
+    pub fn new(algorithm: HashAlgorithm, digest: [u8; DIGEST_LEN]) -> Self {
+        Self { algorithm, digest }
+    }
+
+    pub fn algorithm(&self) -> HashAlgorithm {
+        self.algorithm
+    }
+
+    pub fn digest(&self) -> &[u8; DIGEST_LEN] {
+        &self.digest
+    }
+
+    /// The full multihash encoding, tagged even for SHA-256.
+    pub fn to_multihash(&self) -> Vec<u8> {
+        let mut out = Vec::with_capacity(DIGEST_LEN + 4);
+
+        write_varint(&mut out, self.algorithm.code());
+        write_varint(&mut out, DIGEST_LEN as u64);
+        out.extend_from_slice(&self.digest);
+
+        out
+    }
+
+    /// Decodes a full multihash encoding.
+    pub fn from_multihash(mut data: &[u8]) -> Option<Self> {
+        let algorithm = HashAlgorithm::from_code(read_varint(&mut data)?)?;
+
+        if read_varint(&mut data)? != DIGEST_LEN as u64 || data.len() != DIGEST_LEN {
+            return None;
+        }
+
+        let mut digest = [0; DIGEST_LEN];
+        digest.copy_from_slice(data);
+
+        Some(Self { algorithm, digest })
+    }
+
+    /// The form keys are stored in: a bare digest for SHA-256, and a multihash otherwise.
+    pub fn to_bytes(&self) -> Vec<u8> {
+        match self.algorithm {
+            HashAlgorithm::Sha256 => self.digest.to_vec(),
+            HashAlgorithm::Blake2b256 => self.to_multihash(),
+        }
+    }
+
+    /// Decodes what [`to_bytes`](Multihash::to_bytes) produces, or any full multihash.
+    pub fn from_bytes(data: &[u8]) -> Option<Self> {
+        if data.len() == DIGEST_LEN {
+            let mut digest = [0; DIGEST_LEN];
+            digest.copy_from_slice(data);
+
+            return Some(Self::new(HashAlgorithm::Sha256, digest));
+        }
+
+        Self::from_multihash(data)
+    }
+}
+
+impl fmt::Display for Multihash {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}:", self.algorithm)?;
+
+        for byte in &self.digest {
+            write!(f, "{:02x}", byte)?;
+        }
+
+        Ok(())
+    }
+}
+
+impl fmt::Debug for Multihash {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        fmt::Display::fmt(self, f)
+    }
+}
+
+impl FromStr for Multihash {
+    type Err = ParseKeyError;
+
+    /// Parses the `algorithm:hex` form that keys display as.
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let err = || ParseKeyError(s.to_string());
+
+        let mut parts = s.splitn(2, ':');
+        let algorithm = parts
+            .next()
+            .and_then(HashAlgorithm::from_name)
+            .ok_or_else(err)?;
+        let hex = parts.next().ok_or_else(err)?;
+
+        if hex.len() != DIGEST_LEN * 2 || !hex.is_ascii() {
+            return Err(err());
+        }
+
+        let mut digest = [0; DIGEST_LEN];
+
+        for (byte, pair) in digest.iter_mut().zip(hex.as_bytes().chunks(2)) {
+            let pair = std::str::from_utf8(pair).map_err(|_| err())?;
+            *byte = u8::from_str_radix(pair, 16).map_err(|_| err())?;
+        }
+
+        Ok(Self { algorithm, digest })
+    }
+}
+
+impl Serialize for Multihash {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        match self.algorithm {
+            HashAlgorithm::Sha256 => self.digest.serialize(serializer),
+            HashAlgorithm::Blake2b256 => serializer.serialize_bytes(&self.to_multihash()),
+        }
+    }
+}
+
+struct MultihashVisitor;
+
+impl<'de> Visitor<'de> for MultihashVisitor {
+    type Value = Multihash;
+
+    fn expecting(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "a {} byte sha256 digest or a multihash", DIGEST_LEN)
+    }
+
+    fn visit_seq<A: SeqAccess<'de>>(self, mut seq: A) -> Result<Self::Value, A::Error> {
+        let mut digest = [0; DIGEST_LEN];
+
+        for (i, byte) in digest.iter_mut().enumerate() {
+            *byte = seq
+                .next_element()?
+                .ok_or_else(|| de::Error::invalid_length(i, &self))?;
+        }
+
+        Ok(Multihash::new(HashAlgorithm::Sha256, digest))
+    }
+
+    fn visit_bytes<E: de::Error>(self, v: &[u8]) -> Result<Self::Value, E> {
+        Multihash::from_multihash(v)
+            .ok_or_else(|| E::invalid_value(de::Unexpected::Bytes(v), &self))
+    }
+}
+
+impl<'de> Deserialize<'de> for Multihash {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        deserializer.deserialize_any(MultihashVisitor)
+    }
+}
@@ -0,0 +1,330 @@
+//! Walking the parent links between journal entries.
+
+use crate::{
+    ApplicationId, DevicePublicKey, Journal, JournalEntry, JournalError, JournalKey, Result,
+};
+use std::collections::{HashMap, HashSet, VecDeque};
+
+/// Whether `ancestor` is reachable from `descendant` by following parents. An entry counts as its
+/// own ancestor.
+pub fn is_ancestor(
+    journal: &dyn Journal,
+    ancestor: JournalKey,
+    descendant: JournalKey,
+) -> Result<bool> {
+    let mut seen = HashSet::new();
+    let mut queue = vec![descendant];
+
+    while let Some(key) = queue.pop() {
+        if key == ancestor {
+            return Ok(true);
+        }
+
+        if !seen.insert(key) {
+            continue;
+        }
+
+        if let Some(entry) = journal.get(key)? {
+            queue.extend(entry.parents);
+        }
+    }
+
+    Ok(false)
+}
+
+/// Finds the closest common ancestor of `a` and `b`, if they share any history.
+pub fn merge_base(
+    journal: &dyn Journal,
+    a: JournalKey,
+    b: JournalKey,
+) -> Result<Option<JournalKey>> {
+    let mut ancestors_of_a = HashSet::new();
+    let mut queue = vec![a];
+
+    while let Some(key) = queue.pop() {
+        if ancestors_of_a.insert(key) {
+            if let Some(entry) = journal.get(key)? {
+                queue.extend(entry.parents);
+            }
+        }
+    }
+
+    let mut seen = HashSet::new();
+    let mut queue = VecDeque::new();
+    queue.push_back(b);
+
+    while let Some(key) = queue.pop_front() {
+        if ancestors_of_a.contains(&key) {
+            return Ok(Some(key));
+        }
+
+        if seen.insert(key) {
+            if let Some(entry) = journal.get(key)? {
+                queue.extend(entry.parents);
+            }
+        }
+    }
+
+    Ok(None)
+}
+
+/// The heads of other devices for `appid` that this device's head has not yet incorporated.
+pub fn divergent_heads(journal: &dyn Journal, appid: ApplicationId) -> Result<Vec<JournalKey>> {
+    let ours = journal.this_head(appid)?;
+    let pubkey = journal.pubkey()?;
+
+    let mut theirs = Vec::new();
+
+    for ((a, device), key) in journal.heads()? {
+        if a != appid || device == pubkey {
+            continue;
+        }
+
+        let merged = match ours {
+            Some(ours) => is_ancestor(journal, key, ours)?,
+            None => false,
+        };
+
+        if !merged {
+            theirs.push(key);
+        }
+    }
+
+    theirs.sort();
+    theirs.dedup();
+
+    Ok(theirs)
+}
+
+/// How another device's head relates to this device's, as found by [`relate`].
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub enum Relation {
+    /// Ours already includes theirs, so there is nothing to do.
+    Included,
+
+    /// Theirs descends from ours, or we have no head, so theirs can be taken on as it is.
+    FastForward,
+
+    /// Each has entries the other lacks, so they need merging.
+    Diverged,
+}
+
+/// Decides what to do about `theirs`, another device's head, given `ours`, this device's head if
+/// it has one.
+pub fn relate(
+    journal: &dyn Journal,
+    ours: Option<JournalKey>,
+    theirs: JournalKey,
+) -> Result<Relation> {
+    let ours = match ours {
+        Some(ours) => ours,
+        None => return Ok(Relation::FastForward),
+    };
+
+    if is_ancestor(journal, theirs, ours)? {
+        Ok(Relation::Included)
+    } else if is_ancestor(journal, ours, theirs)? {
+        Ok(Relation::FastForward)
+    } else {
+        Ok(Relation::Diverged)
+    }
+}
+
+/// Sorts `heads` from oldest to newest by their entries' hybrid logical clocks, so the last one is
+/// the winner under a last-writer-wins policy. Every device sorts the same heads the same way:
+/// entries without a clock come first, and ties are broken by key.
+///
+/// Fails if any of `heads` is missing.
+pub fn order_heads(journal: &dyn Journal, heads: &[JournalKey]) -> Result<Vec<JournalKey>> {
+    let mut ordered = Vec::with_capacity(heads.len());
+
+    for &key in heads {
+        let entry = journal
+            .get(key)?
+            .ok_or_else(|| JournalError::Integrity(format!("head {:?} is missing", key)))?;
+
+        ordered.push((entry.clock(), key));
+    }
+
+    ordered.sort();
+    ordered.dedup();
+
+    Ok(ordered.into_iter().map(|(_, key)| key).collect())
+}
+
+/// The most recent ancestor of `from` (including itself) authored at or before `time`, in
+/// milliseconds since the Unix epoch. This answers "what did the state look like then", as far as
+/// the authoring devices' clocks can be trusted.
+///
+/// Entries without a timestamp are never chosen.
+pub fn as_of(journal: &dyn Journal, from: JournalKey, time: u64) -> Result<Option<LogEntry>> {
+    let mut best: Option<(u64, LogEntry)> = None;
+
+    for log_entry in Log::new(journal, from) {
+        let log_entry = log_entry?;
+
+        let timestamp = match log_entry.entry.timestamp() {
+            Some(timestamp) if timestamp <= time => timestamp,
+            _ => continue,
+        };
+
+        let newer = match &best {
+            Some((best_time, _)) => timestamp > *best_time,
+            None => true,
+        };
+
+        if newer {
+            best = Some((timestamp, log_entry));
+        }
+    }
+
+    Ok(best.map(|(_, log_entry)| log_entry))
+}
+
+/// One entry yielded while walking history with a [`Log`].
+#[derive(Clone, PartialEq, Eq, Debug)]
+pub struct LogEntry {
+    pub key: JournalKey,
+
+    /// The device that signed this entry.
+    pub device: DevicePublicKey,
+
+    pub entry: JournalEntry,
+
+    /// The length of the shortest parent path from where the walk started.
+    pub depth: usize,
+}
+
+/// Walks the ancestors of an entry, starting with the entry itself, in topological order: every
+/// entry is yielded before any of its parents.
+pub struct Log<'a> {
+    journal: &'a dyn Journal,
+    from: JournalKey,
+    max_depth: Option<usize>,
+    stop_at: HashSet<JournalKey>,
+    order: Option<VecDeque<LogEntry>>,
+}
+
+impl<'a> Log<'a> {
+    pub fn new(journal: &'a dyn Journal, from: JournalKey) -> Self {
+        Self {
+            journal,
+            from,
+            max_depth: None,
+            stop_at: HashSet::new(),
+            order: None,
+        }
+    }
+
+    /// Only walk entries at most `depth` parents away from the start.
+    #[must_use]
+    pub fn max_depth(mut self, depth: usize) -> Self {
+        self.max_depth = Some(depth);
+        self
+    }
+
+    /// Don't yield `key` or walk past it, such as a merge base found with [`merge_base`].
+    #[must_use]
+    pub fn stop_at(mut self, key: JournalKey) -> Self {
+        self.stop_at.insert(key);
+        self
+    }
+
+    /// Reads every entry the walk covers, then orders them.
+    fn load(&self) -> Result<VecDeque<LogEntry>> {
+        let mut entries: HashMap<JournalKey, LogEntry> = HashMap::new();
+        let mut queue = VecDeque::new();
+
+        if !self.stop_at.contains(&self.from) {
+            queue.push_back((self.from, 0));
+        }
+
+        while let Some((key, depth)) = queue.pop_front() {
+            if entries.contains_key(&key) {
+                continue;
+            }
+
+            let signed = match self.journal.get_signed(key)? {
+                Some(signed) => signed,
+                None => continue,
+            };
+
+            let entry = signed.verify().ok_or(JournalError::Signature(key))?;
+
+            let walk_parents = match self.max_depth {
+                Some(max) => depth < max,
+                None => true,
+            };
+
+            if walk_parents {
+                for &parent in &entry.parents {
+                    if !self.stop_at.contains(&parent) {
+                        queue.push_back((parent, depth + 1));
+                    }
+                }
+            }
+
+            entries.insert(
+                key,
+                LogEntry {
+                    key,
+                    device: signed.device(),
+                    entry,
+                    depth,
+                },
+            );
+        }
+
+        let mut children: HashMap<JournalKey, usize> = HashMap::new();
+
+        for log_entry in entries.values() {
+            for parent in &log_entry.entry.parents {
+                if entries.contains_key(parent) {
+                    *children.entry(*parent).or_default() += 1;
+                }
+            }
+        }
+
+        let mut order = VecDeque::with_capacity(entries.len());
+        let mut ready = vec![self.from];
+
+        while let Some(key) = ready.pop() {
+            let log_entry = match entries.remove(&key) {
+                Some(log_entry) => log_entry,
+                None => continue,
+            };
+
+            for parent in log_entry.entry.parents.iter().rev() {
+                if let Some(count) = children.get_mut(parent) {
+                    *count -= 1;
+
+                    if *count == 0 {
+                        ready.push(*parent);
+                    }
+                }
+            }
+
+            order.push_back(log_entry);
+        }
+
+        Ok(order)
+    }
+}
+
+impl Iterator for Log<'_> {
+    type Item = Result<LogEntry>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        if self.order.is_none() {
+            match self.load() {
+                Ok(order) => self.order = Some(order),
+                Err(e) => {
+                    self.order = Some(VecDeque::new());
+                    return Some(Err(e));
+                }
+            }
+        }
+
+        self.order.as_mut()?.pop_front().map(Ok)
+    }
+}
@@ -1,76 +1,145 @@
 #![deny(clippy::all)]
 #![deny(clippy::pedantic)]
+#![allow(clippy::missing_errors_doc)]
 
 use rusqlite::params;
-use rusqlite::types::{FromSql, FromSqlError, FromSqlResult, ValueRef};
+use rusqlite::types::{FromSql, FromSqlError, FromSqlResult, ToSql, ToSqlOutput, ValueRef};
 use rusqlite::OptionalExtension;
 use serde::{Deserialize, Serialize};
-use sodiumoxide::crypto::hash::sha256;
 use sodiumoxide::crypto::sign;
-use std::collections::HashMap;
+use std::cell::Cell;
+use std::collections::{BTreeMap, HashMap};
 use std::convert::{TryFrom, TryInto};
 use std::fmt;
+use std::str::FromStr;
+use std::time::{SystemTime, UNIX_EPOCH};
 use uuid::Uuid;
 
-/// A 32 byte key type used to reference journal entries. Similar to a git commit.
-#[derive(Serialize, Deserialize, Clone, Copy, PartialEq, Eq, Hash)]
-pub struct JournalKey(pub [u8; 32]);
-
-impl fmt::Debug for JournalKey {
-    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
-        write!(f, "JournalKey(")?;
+mod clock;
+mod codec;
+mod composite;
+mod error;
+mod files;
+mod hash;
+mod memory;
+mod migrations;
+mod shared;
+mod staged;
+pub mod store;
+
+pub use clock::Hlc;
+pub use composite::CompositeJournal;
+pub use error::{JournalError, Result};
+pub use files::{FileJournal, RepackStats};
+pub use hash::{HashAlgorithm, Multihash, ParseKeyError};
+pub use memory::MemoryJournal;
+pub use migrations::SCHEMA_VERSION;
+pub use overlay::OverlayJournal;
+pub use shared::SharedSqliteJournal;
+pub use staged::StagedJournal;
+pub use store::{
+    CasStore, EntryStore, HeadStore, Identity, KeyStore, SettingsStore, Transactional,
+};
+
+pub mod bundle;
+pub mod chunk;
+pub mod fsck;
+pub mod gc;
+pub mod history;
+pub mod overlay;
+pub mod pack;
+pub mod stream;
+pub mod sync;
+
+/// A key type used to reference journal entries, the hash of the signed entry. Similar to a git
+/// commit.
+#[derive(Serialize, Deserialize, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub struct JournalKey(Multihash);
+
+/// The methods and conversions shared by [`JournalKey`] and [`CASKey`], which differ only in what
+/// they refer to.
+macro_rules! key_type {
+    ($name:ident) => {
+        impl $name {
+            pub fn new(hash: Multihash) -> Self {
+                $name(hash)
+            }
+
+            pub fn hash(&self) -> Multihash {
+                self.0
+            }
+
+            pub fn algorithm(&self) -> HashAlgorithm {
+                self.0.algorithm()
+            }
+
+            /// The form this key is stored in, see [`Multihash::to_bytes`].
+            pub fn to_bytes(&self) -> Vec<u8> {
+                self.0.to_bytes()
+            }
+
+            pub fn from_bytes(data: &[u8]) -> Option<Self> {
+                Multihash::from_bytes(data).map($name)
+            }
+        }
 
-        for i in &self.0 {
-            write!(f, "{:x}", i)?;
+        impl fmt::Debug for $name {
+            fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+                write!(f, concat!(stringify!($name), "({})"), self.0)
+            }
         }
 
-        write!(f, ")")?;
+        /// Shows the algorithm and the digest in hex, as `algorithm:digest`.
+        impl fmt::Display for $name {
+            fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+                fmt::Display::fmt(&self.0, f)
+            }
+        }
 
-        Ok(())
-    }
-}
+        impl FromStr for $name {
+            type Err = ParseKeyError;
 
-impl FromSql for JournalKey {
-    fn column_result(value: ValueRef) -> FromSqlResult<Self> {
-        let b = value.as_blob()?;
+            fn from_str(s: &str) -> std::result::Result<Self, Self::Err> {
+                s.parse().map($name)
+            }
+        }
 
-        let r = <[u8; 32]>::try_from(b).map_err(|x| FromSqlError::Other(Box::new(x)));
+        impl FromSql for $name {
+            fn column_result(value: ValueRef) -> FromSqlResult<Self> {
+                Self::from_bytes(value.as_blob()?).ok_or(FromSqlError::InvalidType)
+            }
+        }
 
-        Ok(Self(r?))
-    }
+        impl ToSql for $name {
+            fn to_sql(&self) -> rusqlite::Result<ToSqlOutput<'_>> {
+                Ok(ToSqlOutput::from(self.to_bytes()))
+            }
+        }
+    };
 }
 
-impl FromSql for CASKey {
-    fn column_result(value: ValueRef) -> FromSqlResult<Self> {
-        let b = value.as_blob()?;
-
-        let r = <[u8; 32]>::try_from(b).map_err(|x| FromSqlError::Other(Box::new(x)));
-
-        Ok(Self(r?))
-    }
-}
+key_type!(JournalKey);
+key_type!(CASKey);
 
 impl wasmi::LittleEndianConvert for CASKey {
     fn into_little_endian(self, buffer: &mut [u8]) {
-        buffer.copy_from_slice(&self.0);
+        buffer.copy_from_slice(&self.to_bytes());
     }
 
     fn from_little_endian(buffer: &[u8]) -> Result<Self, wasmi::ValueError> {
-        Ok(Self {
-            0: <[u8; 32]>::try_from(buffer).unwrap(),
-        })
+        Self::from_bytes(buffer).ok_or(wasmi::ValueError::InvalidLittleEndianBuffer)
     }
 }
 
 /// A key type used to wrap a [`sign::PublicKey`] to refer to a device.
-#[derive(Clone, Copy, PartialEq, Eq, Hash, Debug)]
+#[derive(Serialize, Deserialize, Clone, Copy, PartialEq, Eq, Hash, Debug)]
 pub struct DevicePublicKey(sign::PublicKey);
 
 impl FromSql for DevicePublicKey {
     fn column_result(value: ValueRef) -> FromSqlResult<Self> {
         let b = value.as_blob()?;
 
-        let k = sign::PublicKey::from_slice(b).unwrap();
+        let k = sign::PublicKey::from_slice(b).ok_or(FromSqlError::InvalidType)?;
 
         Ok(Self(k))
     }
@@ -80,288 +149,671 @@ impl FromSql for ApplicationId {
     fn column_result(value: ValueRef) -> FromSqlResult<Self> {
         let b = value.as_blob()?;
 
-        let uuid: Uuid = Uuid::from_slice(b).unwrap();
+        let uuid = Uuid::from_slice(b).map_err(|x| FromSqlError::Other(Box::new(x)))?;
 
         Ok(Self(uuid))
     }
 }
 
-static_assertions::assert_obj_safe!(label; Journal);
-
-pub trait Journal {
-    fn settings_get(&self, key: &str) -> Option<Vec<u8>>;
-    fn settings_set(&self, key: &str, value: &[u8]);
-
-    fn pubkey(&self) -> DevicePublicKey {
-        let key = self.settings_get("PublicKey").unwrap();
-        DevicePublicKey(sign::PublicKey::from_slice(&key).unwrap())
+static_assertions::assert_obj_safe!(Journal);
+
+/// A store of signed entries and content addressed objects for one device, made of the separate
+/// stores in [`store`].
+///
+/// Every method can fail with a [`JournalError`] rather than panicking, so that a bad row or a
+/// locked database can be reported by the caller.
+///
+/// This is implemented for everything that implements all the stores, and only adds methods
+/// built on them.
+pub trait Journal: SettingsStore + KeyStore + HeadStore + EntryStore + CasStore {
+    fn this_head(&self, application_id: ApplicationId) -> Result<Option<JournalKey>> {
+        Ok(self
+            .heads()?
+            .get(&(application_id, self.pubkey()?))
+            .copied())
     }
 
-    fn privkey(&self) -> sign::SecretKey {
-        let key = self.settings_get("PrivateKey").unwrap();
-        sign::SecretKey::from_slice(&key).unwrap()
+    fn put(
+        &self,
+        entry: JournalEntry,
+        keypair: (sign::SecretKey, sign::PublicKey),
+    ) -> Result<JournalKey> {
+        self.put_signed(&Signed::sign(&entry, &keypair.0, keypair.1))
     }
 
-    fn this_head(&self, application_id: ApplicationId) -> Option<JournalKey> {
-        Some(*self.heads().get(&(application_id, self.pubkey()))?)
+    /// Stores an already signed entry, such as one received from another device, under its
+    /// [`hash_algorithm`](SettingsStore::hash_algorithm) key. Fails if the signature is not
+    /// valid.
+    fn put_signed(&self, signed: &Signed) -> Result<JournalKey> {
+        self.put_signed_with(signed, self.hash_algorithm()?)
     }
 
-    fn heads(&self) -> HashMap<(ApplicationId, DevicePublicKey), JournalKey>;
-    fn update_head(&self, device: DevicePublicKey, appid: ApplicationId, key: JournalKey);
-
-    fn get(&self, key: JournalKey) -> Option<JournalEntry>;
-    fn put(&self, entry: JournalEntry, keypair: (sign::SecretKey, sign::PublicKey)) -> JournalKey;
+    /// Stores an object under its [`hash_algorithm`](SettingsStore::hash_algorithm) key.
+    fn cas_put(&self, obj: CASObj) -> Result<CASKey> {
+        self.cas_put_with(obj, self.hash_algorithm()?)
+    }
 
-    fn cas_get(&self, key: CASKey) -> Option<CASObj>;
-    fn cas_put(&self, obj: CASObj) -> CASKey;
-    fn cas_list(&self) -> Vec<CASKey>;
+    fn get_state(&self, appid: ApplicationId) -> Result<Option<CASKey>> {
+        let head = match self.this_head(appid)? {
+            Some(head) => head,
+            None => return Ok(None),
+        };
 
-    fn get_state(&self, appid: ApplicationId) -> Option<CASKey> {
-        let head = self.this_head(appid)?;
+        let head_entry = self.get(head)?.ok_or_else(|| {
+            JournalError::Integrity(format!("head points at missing entry {:?}", head))
+        })?;
 
-        let head_entry = self.get(head)?;
+        Ok(Some(head_entry.new_state))
+    }
 
-        Some(head_entry.new_state)
+    fn commit_self(&self, application_id: ApplicationId, new_state: CASKey) -> Result<JournalKey> {
+        self.commit_merge(application_id, new_state, &[])
     }
 
-    fn commit_self(&self, application_id: ApplicationId, new_state: CASKey) -> JournalKey {
-        let head = self.heads().get(&(application_id, self.pubkey())).cloned();
+    /// Commits `new_state` on top of this device's head and the `merged` entries, which are
+    /// usually the heads of other devices whose changes `new_state` incorporates.
+    fn commit_merge(
+        &self,
+        application_id: ApplicationId,
+        new_state: CASKey,
+        merged: &[JournalKey],
+    ) -> Result<JournalKey> {
+        self.commit_with(application_id, new_state, merged, CommitInfo::default())
+    }
 
-        let mut parents = vec![];
+    /// Like [`commit_merge`](Journal::commit_merge), but records `info` in the new entry along
+    /// with the time it was authored.
+    fn commit_with(
+        &self,
+        application_id: ApplicationId,
+        new_state: CASKey,
+        merged: &[JournalKey],
+        info: CommitInfo,
+    ) -> Result<JournalKey> {
+        transaction(self, |journal| {
+            let head = journal.this_head(application_id)?;
+
+            let mut parents = vec![];
+
+            if let Some(head) = head {
+                parents.push(head);
+            }
+
+            parents.extend(merged.iter().filter(|&&k| Some(k) != head));
+
+            // Parents normally reached this journal through sync, which already advanced the
+            // clock past them, but observing them again keeps entries ahead of their ancestors
+            // however they arrived.
+            for &parent in &parents {
+                if let Some(clock) = journal.get(parent)?.and_then(|p| p.clock) {
+                    clock::observe(journal, clock)?;
+                }
+            }
+
+            let clock = clock::tick(journal)?;
+
+            let timestamp = SystemTime::now()
+                .duration_since(UNIX_EPOCH)
+                .map(|d| d.as_millis().try_into().unwrap_or(u64::MAX))
+                .ok();
+
+            let entry = JournalEntry {
+                version: ENTRY_VERSION,
+                application_id,
+                new_state,
+                parents,
+                timestamp,
+                message: info.message,
+                metadata: info.metadata,
+                clock: Some(clock),
+            };
+
+            let pubkey = journal.pubkey()?;
+
+            let put_entry = journal.put(entry, (journal.privkey()?, pubkey.0))?;
+
+            journal.update_head_if(pubkey, application_id, head, put_entry)?;
+
+            Ok(put_entry)
+        })
+    }
+}
 
-        if let Some(head) = head {
-            parents.push(head);
+impl<T> Journal for T where T: SettingsStore + KeyStore + HeadStore + EntryStore + CasStore {}
+
+/// Runs `f` inside a transaction, committing if it succeeds and rolling back if it fails.
+pub fn transaction<J, T, F>(journal: &J, f: F) -> Result<T>
+where
+    J: Transactional + ?Sized,
+    F: FnOnce(&J) -> Result<T>,
+{
+    journal.begin_transaction()?;
+
+    match f(journal).and_then(|value| journal.commit_transaction().map(|()| value)) {
+        Ok(value) => Ok(value),
+        Err(e) => {
+            // The original error is more useful than any from rolling back.
+            let _ = journal.rollback_transaction();
+            Err(e)
         }
-
-        let entry = JournalEntry {
-            parents,
-            application_id,
-            new_state,
-        };
-
-        let put_entry = self.put(entry, (self.privkey(), self.pubkey().0));
-
-        self.update_head(self.pubkey(), application_id, put_entry);
-
-        put_entry
     }
 }
 
 #[derive(Debug)]
 pub struct SqliteJournal {
     db: rusqlite::Connection,
+
+    /// How many transactions are open on `db`.
+    depth: Cell<usize>,
+}
+
+/// What [`SqliteJournal::recompress`] did.
+#[derive(Clone, Copy, PartialEq, Eq, Default, Debug)]
+pub struct RecompressStats {
+    /// How many uncompressed objects were looked at.
+    pub examined: usize,
+
+    /// How many of them are now stored compressed.
+    pub compressed: usize,
+
+    /// The total size of the examined objects' bodies before and after.
+    pub bytes_before: u64,
+    pub bytes_after: u64,
 }
 
 impl SqliteJournal {
-    pub fn new(path: &str) -> Self {
-        let db = rusqlite::Connection::open(path).expect("failed to create database");
+    pub fn new(path: &str) -> Result<Self> {
+        let mut db = rusqlite::Connection::open(path)?;
 
         db.set_prepared_statement_cache_capacity(32);
 
-        db.execute_batch(
-            "
+        // Other connections to the same database, from other threads or processes, may be
+        // writing. Wait for them instead of failing straight away.
+        db.busy_timeout(std::time::Duration::from_secs(5))?;
+
+        db.execute_batch("PRAGMA journal_mode=WAL;")?;
+
+        migrations::migrate(&mut db)?;
+
+        let journal = Self {
+            db,
+            depth: Cell::new(0),
+        };
 
-        PRAGMA journal_mode=WAL;
+        if journal.settings_get("PrivateKey")?.is_none() {
+            let (pubkey, privkey) = sign::gen_keypair();
 
-        CREATE TABLE IF NOT EXISTS settings (
-            id BLOB NOT NULL PRIMARY KEY,
-            value BLOB NOT NULL
-        );
+            journal.settings_set("PublicKey", &pubkey[..])?;
+            journal.settings_set("PrivateKey", &privkey[..])?;
+        }
 
-        CREATE TABLE IF NOT EXISTS heads (
-            application_id BLOB NOT NULL,
-            device_id BLOB NOT NULL,
-            entry_id BLOB NOT NULL,
-            PRIMARY KEY (application_id, device_id)
-        );
+        Ok(journal)
+    }
 
-        CREATE TABLE IF NOT EXISTS links (
-            parent BLOB NOT NULL,
-            child BLOB NOT NULL,
-        )
+    /// Whether a transaction begun on this connection has not yet ended.
+    pub(crate) fn in_transaction(&self) -> bool {
+        self.depth.get() > 0
+    }
 
-        CREATE TABLE IF NOT EXISTS entries (
-            id BLOB NOT NULL PRIMARY KEY,
-            inner BLOB NOT NULL
-        );
+    /// The ids in `table` as stored, whether or not they decode as keys.
+    fn raw_ids(&self, table: &str) -> Result<Vec<Vec<u8>>> {
+        Ok(self
+            .db
+            .prepare(&format!("SELECT CAST(id AS BLOB) FROM {}", table))?
+            .query_map(params!(), |row| row.get(0))?
+            .collect::<rusqlite::Result<_>>()?)
+    }
 
-        CREATE TABLE IF NOT EXISTS cas (
-            id BLOB NOT NULL PRIMARY KEY,
-            content BLOB NOT NULL
-        );",
-        )
-        .unwrap();
+    /// Compresses objects that were stored uncompressed, such as those written before
+    /// compression was supported. Keys are unaffected.
+    pub fn recompress(&self) -> Result<RecompressStats> {
+        let keys: Vec<Vec<u8>> = self
+            .db
+            .prepare("SELECT id FROM cas WHERE codec = ?1")?
+            .query_map(params!(codec::Codec::None as i64), |row| row.get(0))?
+            .collect::<rusqlite::Result<_>>()?;
 
-        let journal = Self { db };
+        transaction(self, |journal| {
+            let mut stats = RecompressStats::default();
 
-        if journal.settings_get("PrivateKey").is_none() {
-            let (pubkey, privkey) = sign::gen_keypair();
+            for key in keys {
+                let data: Vec<u8> = journal
+                    .db
+                    .prepare_cached("SELECT content FROM cas WHERE id = ?1")?
+                    .query_row(params!(key), |row| row.get(0))?;
 
-            journal.settings_set("PublicKey", &pubkey[..]);
-            journal.settings_set("PrivateKey", &privkey[..]);
-        }
+                stats.examined += 1;
+                stats.bytes_before += data.len() as u64;
+
+                let (codec, content) = codec::compress(data)?;
 
-        journal
+                stats.bytes_after += content.len() as u64;
+
+                if codec != codec::Codec::None {
+                    stats.compressed += 1;
+
+                    journal
+                        .db
+                        .prepare_cached("UPDATE cas SET content = ?1, codec = ?2 WHERE id = ?3")?
+                        .execute(params!(content, codec as i64, key))?;
+                }
+            }
+
+            Ok(stats)
+        })
     }
 }
 
-#[derive(Serialize, Deserialize, Debug)]
+#[derive(Serialize, Deserialize, Clone, Debug)]
 pub struct CASObj {
     pub links: Vec<CASKey>,
     pub data: Vec<u8>,
 }
 
-impl Journal for SqliteJournal {
-    fn settings_get(&self, key: &str) -> Option<Vec<u8>> {
-        self.db
-            .prepare_cached("SELECT value FROM settings WHERE id=?1")
-            .unwrap()
+impl CASObj {
+    /// The key this object is stored under with the default algorithm, the hash of its
+    /// serialized form.
+    pub fn key(&self) -> CASKey {
+        self.key_with(HashAlgorithm::default())
+    }
+
+    pub fn key_with(&self, algorithm: HashAlgorithm) -> CASKey {
+        let data = serde_cbor::to_vec(self).expect("failed to serialize");
+
+        CASKey(algorithm.digest(&data))
+    }
+}
+
+impl Transactional for SqliteJournal {
+    // The outermost transaction takes the write lock straight away, so that it waits for other
+    // writers up front rather than failing when it first writes. Nested ones are savepoints.
+
+    fn begin_transaction(&self) -> Result<()> {
+        if self.depth.get() == 0 {
+            self.db.execute_batch("BEGIN IMMEDIATE")?;
+        } else {
+            self.db.execute_batch("SAVEPOINT journal_transaction")?;
+        }
+
+        self.depth.set(self.depth.get() + 1);
+
+        Ok(())
+    }
+
+    fn commit_transaction(&self) -> Result<()> {
+        if self.depth.get() == 1 {
+            self.db.execute_batch("COMMIT")?;
+        } else {
+            self.db.execute_batch("RELEASE journal_transaction")?;
+        }
+
+        self.depth.set(self.depth.get() - 1);
+
+        Ok(())
+    }
+
+    fn rollback_transaction(&self) -> Result<()> {
+        // Whatever happens the transaction is over, since a failed rollback can't be retried.
+        let depth = self.depth.replace(self.depth.get().saturating_sub(1));
+
+        if depth == 1 {
+            self.db.execute_batch("ROLLBACK")?;
+        } else {
+            self.db
+                .execute_batch("ROLLBACK TO journal_transaction; RELEASE journal_transaction")?;
+        }
+
+        Ok(())
+    }
+}
+
+impl SettingsStore for SqliteJournal {
+    fn settings_get(&self, key: &str) -> Result<Option<Vec<u8>>> {
+        Ok(self
+            .db
+            .prepare_cached("SELECT value FROM settings WHERE id=?1")?
             .query_row(params!(key), |row| row.get(0))
-            .optional()
-            .unwrap()
+            .optional()?)
     }
 
-    fn settings_set(&self, key: &str, value: &[u8]) {
+    fn settings_set(&self, key: &str, value: &[u8]) -> Result<()> {
         self.db
-            .prepare_cached("INSERT INTO settings VALUES (?1, ?2)")
-            .unwrap()
-            .execute(params!(key, value))
-            .unwrap();
+            .prepare_cached("INSERT OR REPLACE INTO settings VALUES (?1, ?2)")?
+            .execute(params!(key, value))?;
+
+        Ok(())
     }
+}
 
-    fn heads(&self) -> HashMap<(ApplicationId, DevicePublicKey), JournalKey> {
-        self.db
-            .prepare_cached("SELECT application_id, device_id, entry_id FROM heads")
-            .unwrap()
+impl KeyStore for SqliteJournal {
+    fn pubkey(&self) -> Result<DevicePublicKey> {
+        store::settings_pubkey(self)
+    }
+
+    fn privkey(&self) -> Result<sign::SecretKey> {
+        store::settings_privkey(self)
+    }
+}
+
+impl HeadStore for SqliteJournal {
+    fn heads(&self) -> Result<HashMap<(ApplicationId, DevicePublicKey), JournalKey>> {
+        Ok(self
+            .db
+            .prepare_cached("SELECT application_id, device_id, entry_id FROM heads")?
             .query_map(params!(), |row| {
                 Ok(((row.get(0)?, row.get(1)?), row.get(2)?))
-            })
-            .unwrap()
-            .map(Result::unwrap)
-            .collect()
+            })?
+            .collect::<rusqlite::Result<_>>()?)
     }
 
-    fn update_head(&self, device: DevicePublicKey, appid: ApplicationId, key: JournalKey) {
+    fn update_head(
+        &self,
+        device: DevicePublicKey,
+        appid: ApplicationId,
+        key: JournalKey,
+    ) -> Result<()> {
         self.db
-            .prepare_cached("INSERT OR REPLACE INTO heads VALUES (?, ?, ?)")
-            .unwrap()
-            .execute(params!(appid.0, &device.0[..], &key.0[..]))
-            .unwrap();
-    }
+            .prepare_cached("INSERT OR REPLACE INTO heads VALUES (?, ?, ?)")?
+            .execute(params!(appid.0, &device.0[..], key))?;
 
-    fn get(&self, key: JournalKey) -> Option<JournalEntry> {
-        let result: Vec<u8> = self
-            .db
-            .prepare_cached("SELECT inner FROM entries WHERE id = ?1")
-            .unwrap()
-            .query_row(params!(&key.0[..]), |row| row.get(0))
-            .optional()
-            .unwrap()?;
+        Ok(())
+    }
 
-        let des: Signed = serde_cbor::from_slice(&result).unwrap();
+    fn update_head_if(
+        &self,
+        device: DevicePublicKey,
+        appid: ApplicationId,
+        expected: Option<JournalKey>,
+        key: JournalKey,
+    ) -> Result<()> {
+        let changed = match expected {
+            Some(expected) => self
+                .db
+                .prepare_cached(
+                    "UPDATE heads SET entry_id = ?4
+                    WHERE application_id = ?1 AND device_id = ?2 AND entry_id = ?3",
+                )?
+                .execute(params!(appid.0, &device.0[..], expected, key))?,
+            None => self
+                .db
+                .prepare_cached("INSERT OR IGNORE INTO heads VALUES (?, ?, ?)")?
+                .execute(params!(appid.0, &device.0[..], key))?,
+        };
 
-        let inner_journal_entry = sign::verify(&des.inner_signed, &des.from).unwrap();
+        if changed == 0 {
+            let found = self.heads()?.get(&(appid, device)).copied();
 
-        let final_des: JournalEntry = serde_cbor::from_slice(&inner_journal_entry).unwrap();
+            return Err(JournalError::HeadConflict { expected, found });
+        }
 
-        Some(final_des)
+        Ok(())
     }
+}
+
+impl EntryStore for SqliteJournal {
+    fn get_signed(&self, key: JournalKey) -> Result<Option<Signed>> {
+        let result: Option<Vec<u8>> = self
+            .db
+            .prepare_cached("SELECT inner FROM entries WHERE id = ?1")?
+            .query_row(params!(key), |row| row.get(0))
+            .optional()?;
 
-    fn put(&self, entry: JournalEntry, keypair: (sign::SecretKey, sign::PublicKey)) -> JournalKey {
-        let ser = serde_cbor::to_vec(&entry).unwrap();
+        match result {
+            Some(result) => Ok(Some(serde_cbor::from_slice(&result)?)),
+            None => Ok(None),
+        }
+    }
 
-        let signed = Signed {
-            from: keypair.1,
-            inner_signed: sign::sign(&ser, &keypair.0),
-        };
+    fn put_signed_with(&self, signed: &Signed, algorithm: HashAlgorithm) -> Result<JournalKey> {
+        let signed_ser = serde_cbor::to_vec(signed)?;
 
-        let signed_ser = serde_cbor::to_vec(&signed).unwrap();
+        let key = JournalKey(algorithm.digest(&signed_ser));
 
-        let digest = sha256::hash(&signed_ser);
+        let entry = signed.verify().ok_or(JournalError::Signature(key))?;
 
         self.db
-            .prepare_cached("INSERT OR IGNORE INTO entries VALUES (?1, ?2)")
-            .unwrap()
-            .execute(params!(&digest[..], &signed_ser))
-            .unwrap();
+            .prepare_cached("INSERT OR IGNORE INTO entries VALUES (?1, ?2)")?
+            .execute(params!(key, &signed_ser))?;
+
+        for parent in &entry.parents {
+            self.db
+                .prepare_cached("INSERT OR IGNORE INTO links VALUES (?1, ?2)")?
+                .execute(params!(parent, key))?;
+        }
 
-        JournalKey(digest.as_ref().try_into().unwrap())
+        Ok(key)
     }
 
-    fn cas_get(&self, key: CASKey) -> Option<CASObj> {
-        let data: Vec<u8> = self.db
-            .prepare_cached("SELECT content FROM cas WHERE id = ?1")
-            .unwrap()
-            .query_row(params!(&key.0[..]), |row| row.get(0))
-            .optional()
-            .unwrap()?;
+    fn children(&self, key: JournalKey) -> Result<Vec<JournalKey>> {
+        Ok(self
+            .db
+            .prepare_cached("SELECT child FROM links WHERE parent = ?1")?
+            .query_map(params!(key), |row| row.get(0))?
+            .collect::<rusqlite::Result<_>>()?)
+    }
 
-        let ret = serde_cbor::from_slice(&data).expect("failed to deserialize");
+    fn entry_list(&self) -> Result<Vec<JournalKey>> {
+        Ok(self
+            .db
+            .prepare_cached("SELECT id FROM entries")?
+            .query_map(params!(), |row| row.get(0))?
+            .collect::<rusqlite::Result<_>>()?)
+    }
 
-        return Some(ret);
+    fn entry_ids(&self) -> Result<Vec<std::result::Result<JournalKey, Vec<u8>>>> {
+        Ok(self
+            .raw_ids("entries")?
+            .into_iter()
+            .map(|id| JournalKey::from_bytes(&id).ok_or(id))
+            .collect())
     }
+}
 
-    fn cas_put(&self, obj: CASObj) -> CASKey {
+impl CasStore for SqliteJournal {
+    fn cas_get(&self, key: CASKey) -> Result<Option<CASObj>> {
+        let row: Option<(i64, Vec<u8>)> = self
+            .db
+            .prepare_cached("SELECT codec, content FROM cas WHERE id = ?1")?
+            .query_row(params!(key), |row| Ok((row.get(0)?, row.get(1)?)))
+            .optional()?;
+
+        match row {
+            Some((codec, data)) => Ok(Some(serde_cbor::from_slice(&codec::decompress(
+                codec, data,
+            )?)?)),
+            None => Ok(None),
+        }
+    }
 
-        let data = serde_cbor::to_vec(&obj).expect("failed to serialize");
+    fn cas_put_with(&self, obj: CASObj, algorithm: HashAlgorithm) -> Result<CASKey> {
+        let data = serde_cbor::to_vec(&obj)?;
 
-        let digest = sha256::hash(&data);
+        let key = CASKey(algorithm.digest(&data));
+
+        let exists = self
+            .db
+            .prepare_cached("SELECT 1 FROM cas WHERE id = ?1")?
+            .exists(params!(key))?;
+
+        // The check only saves compressing objects that are already stored. Another connection
+        // may store the same object in between, so the insert must still tolerate duplicates.
+        if !exists {
+            let (codec, content) = codec::compress(data)?;
+
+            self.db
+                .prepare_cached(
+                    "INSERT OR IGNORE INTO cas (id, content, codec) VALUES (?1, ?2, ?3)",
+                )?
+                .execute(params!(key, content, codec as i64))?;
+        }
 
         self.db
-            .prepare_cached("INSERT OR IGNORE INTO cas VALUES (?1, ?2)")
-            .unwrap()
-            .execute(params!(&digest[..], data))
-            .unwrap();
+            .prepare_cached(
+                "INSERT OR REPLACE INTO cas_written VALUES (?1, strftime('%s', 'now'))",
+            )?
+            .execute(params!(key))?;
 
-        CASKey(digest.as_ref().try_into().unwrap())
+        Ok(key)
     }
 
-    fn cas_list(&self) -> Vec<CASKey> {
+    fn cas_list(&self) -> Result<Vec<CASKey>> {
+        Ok(self
+            .db
+            .prepare_cached("SELECT id FROM cas")?
+            .query_map(params!(), |row| row.get(0))?
+            .collect::<rusqlite::Result<_>>()?)
+    }
+
+    fn cas_ids(&self) -> Result<Vec<std::result::Result<CASKey, Vec<u8>>>> {
+        Ok(self
+            .raw_ids("cas")?
+            .into_iter()
+            .map(|id| CASKey::from_bytes(&id).ok_or(id))
+            .collect())
+    }
+
+    fn cas_delete(&self, key: CASKey) -> Result<()> {
+        self.db
+            .prepare_cached("DELETE FROM cas WHERE id = ?1")?
+            .execute(params!(key))?;
+
         self.db
-            .prepare_cached("SELECT id FROM cas")
-            .unwrap()
-            .query_map(params!(), |row| row.get(0))
-            .unwrap()
-            .map(Result::unwrap)
-            .collect()
+            .prepare_cached("DELETE FROM cas_written WHERE id = ?1")?
+            .execute(params!(key))?;
+
+        Ok(())
+    }
+
+    fn cas_written(&self, key: CASKey) -> Result<Option<u64>> {
+        let written: Option<i64> = self
+            .db
+            .prepare_cached("SELECT written FROM cas_written WHERE id = ?1")?
+            .query_row(params!(key), |row| row.get(0))
+            .optional()?;
+
+        Ok(written.map(|w| u64::try_from(w).unwrap_or(0)))
     }
 }
 
+/// A [`JournalEntry`] signed by the device that wrote it, in the form it is stored and transferred.
 #[derive(Serialize, Deserialize, Clone, PartialEq, Eq, Hash, Debug)]
-struct Signed {
+pub struct Signed {
     from: sign::PublicKey,
     inner_signed: Vec<u8>,
 }
 
+impl Signed {
+    pub fn sign(entry: &JournalEntry, privkey: &sign::SecretKey, pubkey: sign::PublicKey) -> Self {
+        let ser = serde_cbor::to_vec(entry).unwrap();
+
+        Self {
+            from: pubkey,
+            inner_signed: sign::sign(&ser, privkey),
+        }
+    }
+
+    /// Checks the signature, returning the entry if it is valid.
+    pub fn verify(&self) -> Option<JournalEntry> {
+        let inner = sign::verify(&self.inner_signed, &self.from).ok()?;
+
+        serde_cbor::from_slice(&inner).ok()
+    }
+
+    /// The key this entry is stored under with the default algorithm, the hash of its serialized
+    /// form.
+    pub fn key(&self) -> JournalKey {
+        self.key_with(HashAlgorithm::default())
+    }
+
+    pub fn key_with(&self, algorithm: HashAlgorithm) -> JournalKey {
+        let signed_ser = serde_cbor::to_vec(self).expect("failed to serialize");
+
+        JournalKey(algorithm.digest(&signed_ser))
+    }
+
+    pub fn device(&self) -> DevicePublicKey {
+        DevicePublicKey(self.from)
+    }
+}
+
 #[derive(Serialize, Deserialize, Copy, Clone, PartialEq, Eq, Hash, Debug)]
 pub struct ApplicationId(pub Uuid);
 
+/// The entry format written by this version. Entries from before versioning decode as version 0,
+/// with no timestamp, message or metadata, and version 1 entries have no clock.
+pub const ENTRY_VERSION: u32 = 2;
+
+/// Optional details to record in a commit, beyond the new state itself.
+#[derive(Clone, PartialEq, Eq, Default, Debug)]
+pub struct CommitInfo {
+    /// A human readable description of the change.
+    pub message: Option<String>,
+
+    /// Arbitrary application-defined values, such as the name of the edited document.
+    pub metadata: BTreeMap<String, Vec<u8>>,
+}
+
+// Every field added after the first version must have a serde default, so older entries still
+// decode. Unknown fields from newer versions are ignored.
 #[derive(Serialize, Deserialize, Clone, PartialEq, Eq, Hash, Debug)]
 pub struct JournalEntry {
+    #[serde(default)]
+    version: u32,
     application_id: ApplicationId,
     new_state: CASKey,
     parents: Vec<JournalKey>,
+    #[serde(default)]
+    timestamp: Option<u64>,
+    #[serde(default)]
+    message: Option<String>,
+    #[serde(default)]
+    metadata: BTreeMap<String, Vec<u8>>,
+    #[serde(default)]
+    clock: Option<Hlc>,
 }
 
-#[derive(Copy, Serialize, Deserialize, Clone, PartialEq, Eq, Hash)]
-pub struct CASKey([u8; 32]);
+impl JournalEntry {
+    /// The format version this entry was written with, see [`ENTRY_VERSION`].
+    pub fn version(&self) -> u32 {
+        self.version
+    }
 
-impl CASKey {
-    pub fn new(array: [u8; 32]) -> Self {
-        CASKey(array)
+    pub fn application_id(&self) -> ApplicationId {
+        self.application_id
     }
-}
 
-impl fmt::Debug for CASKey {
-    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
-        write!(f, "CASKey(")?;
+    pub fn new_state(&self) -> CASKey {
+        self.new_state
+    }
 
-        for i in &self.0 {
-            write!(f, "{:x}", i)?;
-        }
+    /// The entries this one was committed on top of. Empty for the first entry of a device's
+    /// history, and more than one for a merge.
+    pub fn parents(&self) -> &[JournalKey] {
+        &self.parents
+    }
 
-        write!(f, ")")?;
+    /// When the entry was authored, in milliseconds since the Unix epoch, according to the
+    /// authoring device's clock. `None` for entries from before timestamps were recorded.
+    pub fn timestamp(&self) -> Option<u64> {
+        self.timestamp
+    }
 
-        Ok(())
+    pub fn message(&self) -> Option<&str> {
+        self.message.as_deref()
+    }
+
+    pub fn metadata(&self) -> &BTreeMap<String, Vec<u8>> {
+        &self.metadata
+    }
+
+    /// The authoring device's hybrid logical clock when the entry was committed. Normally greater
+    /// than the clocks of the entry's ancestors, but nothing enforces that for entries from other
+    /// devices. `None` for entries from before clocks were kept.
+    pub fn clock(&self) -> Option<Hlc> {
+        self.clock
     }
 }
+
+/// A key type used to reference objects, the hash of the serialized object.
+#[derive(Copy, Serialize, Deserialize, Clone, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub struct CASKey(Multihash);
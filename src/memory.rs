@@ -0,0 +1,239 @@
+//! Journals held entirely in memory.
+
+use crate::{
+    store, ApplicationId, CASKey, CASObj, CasStore, DevicePublicKey, EntryStore, HashAlgorithm,
+    HeadStore, JournalError, JournalKey, KeyStore, Result, SettingsStore, Signed, Transactional,
+};
+use sodiumoxide::crypto::sign;
+use std::cell::RefCell;
+use std::collections::HashMap;
+use std::time::{SystemTime, UNIX_EPOCH};
+
+/// A [`Journal`] held entirely in memory, for tests and simulations.
+///
+/// Entries and objects are serialized and hashed exactly as [`SqliteJournal`](crate::SqliteJournal)
+/// does, so the same content gets the same keys in either.
+#[derive(Debug)]
+pub struct MemoryJournal {
+    state: RefCell<State>,
+
+    /// Copies of `state` taken when each open transaction began, innermost last.
+    snapshots: RefCell<Vec<State>>,
+}
+
+#[derive(Clone, Default, Debug)]
+struct State {
+    settings: HashMap<String, Vec<u8>>,
+    heads: HashMap<(ApplicationId, DevicePublicKey), JournalKey>,
+    entries: HashMap<JournalKey, Vec<u8>>,
+    children: HashMap<JournalKey, Vec<JournalKey>>,
+    cas: HashMap<CASKey, Vec<u8>>,
+    cas_written: HashMap<CASKey, u64>,
+}
+
+impl MemoryJournal {
+    /// Creates an empty journal with a freshly generated device keypair.
+    pub fn new() -> Self {
+        let (pubkey, privkey) = sign::gen_keypair();
+
+        let mut state = State::default();
+        state
+            .settings
+            .insert("PublicKey".to_string(), pubkey[..].to_vec());
+        state
+            .settings
+            .insert("PrivateKey".to_string(), privkey[..].to_vec());
+
+        Self {
+            state: RefCell::new(state),
+            snapshots: RefCell::default(),
+        }
+    }
+}
+
+impl Default for MemoryJournal {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl Transactional for MemoryJournal {
+    fn begin_transaction(&self) -> Result<()> {
+        let snapshot = self.state.borrow().clone();
+
+        self.snapshots.borrow_mut().push(snapshot);
+
+        Ok(())
+    }
+
+    fn commit_transaction(&self) -> Result<()> {
+        self.snapshots
+            .borrow_mut()
+            .pop()
+            .ok_or_else(|| JournalError::Integrity("no transaction to commit".to_string()))?;
+
+        Ok(())
+    }
+
+    fn rollback_transaction(&self) -> Result<()> {
+        let snapshot =
+            self.snapshots.borrow_mut().pop().ok_or_else(|| {
+                JournalError::Integrity("no transaction to roll back".to_string())
+            })?;
+
+        *self.state.borrow_mut() = snapshot;
+
+        Ok(())
+    }
+}
+
+impl SettingsStore for MemoryJournal {
+    fn settings_get(&self, key: &str) -> Result<Option<Vec<u8>>> {
+        Ok(self.state.borrow().settings.get(key).cloned())
+    }
+
+    fn settings_set(&self, key: &str, value: &[u8]) -> Result<()> {
+        self.state
+            .borrow_mut()
+            .settings
+            .insert(key.to_string(), value.to_vec());
+
+        Ok(())
+    }
+}
+
+impl KeyStore for MemoryJournal {
+    fn pubkey(&self) -> Result<DevicePublicKey> {
+        store::settings_pubkey(self)
+    }
+
+    fn privkey(&self) -> Result<sign::SecretKey> {
+        store::settings_privkey(self)
+    }
+}
+
+impl HeadStore for MemoryJournal {
+    fn heads(&self) -> Result<HashMap<(ApplicationId, DevicePublicKey), JournalKey>> {
+        Ok(self.state.borrow().heads.clone())
+    }
+
+    fn update_head(
+        &self,
+        device: DevicePublicKey,
+        appid: ApplicationId,
+        key: JournalKey,
+    ) -> Result<()> {
+        self.state.borrow_mut().heads.insert((appid, device), key);
+
+        Ok(())
+    }
+
+    fn update_head_if(
+        &self,
+        device: DevicePublicKey,
+        appid: ApplicationId,
+        expected: Option<JournalKey>,
+        key: JournalKey,
+    ) -> Result<()> {
+        let mut state = self.state.borrow_mut();
+
+        let found = state.heads.get(&(appid, device)).copied();
+
+        if found != expected {
+            return Err(JournalError::HeadConflict { expected, found });
+        }
+
+        state.heads.insert((appid, device), key);
+
+        Ok(())
+    }
+}
+
+impl EntryStore for MemoryJournal {
+    fn get_signed(&self, key: JournalKey) -> Result<Option<Signed>> {
+        match self.state.borrow().entries.get(&key) {
+            Some(data) => Ok(Some(serde_cbor::from_slice(data)?)),
+            None => Ok(None),
+        }
+    }
+
+    fn put_signed_with(&self, signed: &Signed, algorithm: HashAlgorithm) -> Result<JournalKey> {
+        let signed_ser = serde_cbor::to_vec(signed)?;
+
+        let key = JournalKey::new(algorithm.digest(&signed_ser));
+
+        let entry = signed.verify().ok_or(JournalError::Signature(key))?;
+
+        let mut state = self.state.borrow_mut();
+
+        if state.entries.contains_key(&key) {
+            return Ok(key);
+        }
+
+        state.entries.insert(key, signed_ser);
+
+        for parent in entry.parents() {
+            state.children.entry(*parent).or_default().push(key);
+        }
+
+        Ok(key)
+    }
+
+    fn children(&self, key: JournalKey) -> Result<Vec<JournalKey>> {
+        Ok(self
+            .state
+            .borrow()
+            .children
+            .get(&key)
+            .cloned()
+            .unwrap_or_default())
+    }
+
+    fn entry_list(&self) -> Result<Vec<JournalKey>> {
+        Ok(self.state.borrow().entries.keys().copied().collect())
+    }
+}
+
+impl CasStore for MemoryJournal {
+    fn cas_get(&self, key: CASKey) -> Result<Option<CASObj>> {
+        match self.state.borrow().cas.get(&key) {
+            Some(data) => Ok(Some(serde_cbor::from_slice(data)?)),
+            None => Ok(None),
+        }
+    }
+
+    fn cas_put_with(&self, obj: CASObj, algorithm: HashAlgorithm) -> Result<CASKey> {
+        let data = serde_cbor::to_vec(&obj)?;
+
+        let key = CASKey::new(algorithm.digest(&data));
+
+        let now = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .unwrap_or_default()
+            .as_secs();
+
+        let mut state = self.state.borrow_mut();
+
+        state.cas.entry(key).or_insert(data);
+        state.cas_written.insert(key, now);
+
+        Ok(key)
+    }
+
+    fn cas_list(&self) -> Result<Vec<CASKey>> {
+        Ok(self.state.borrow().cas.keys().copied().collect())
+    }
+
+    fn cas_delete(&self, key: CASKey) -> Result<()> {
+        let mut state = self.state.borrow_mut();
+
+        state.cas.remove(&key);
+        state.cas_written.remove(&key);
+
+        Ok(())
+    }
+
+    fn cas_written(&self, key: CASKey) -> Result<Option<u64>> {
+        Ok(self.state.borrow().cas_written.get(&key).copied())
+    }
+}
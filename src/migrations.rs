@@ -0,0 +1,121 @@
+//! Upgrading the schema of [`SqliteJournal`](crate::SqliteJournal) databases.
+//!
+//! The schema version is kept in the `user_version` pragma. `MIGRATIONS[n]` upgrades a database
+//! from version `n` to `n + 1`, so new steps must only ever be appended.
+
+use crate::{JournalError, Result, Signed};
+use rusqlite::{params, Connection};
+
+enum Step {
+    Sql(&'static str),
+    Run(fn(&Connection) -> Result<()>),
+}
+
+const MIGRATIONS: &[Step] = &[
+    // Databases from before versioning were created with `CREATE TABLE IF NOT EXISTS`, so this
+    // step brings both those and empty databases to the same layout.
+    Step::Sql(
+        "
+    CREATE TABLE IF NOT EXISTS settings (
+        id BLOB NOT NULL PRIMARY KEY,
+        value BLOB NOT NULL
+    );
+
+    CREATE TABLE IF NOT EXISTS heads (
+        application_id BLOB NOT NULL,
+        device_id BLOB NOT NULL,
+        entry_id BLOB NOT NULL,
+        PRIMARY KEY (application_id, device_id)
+    );
+
+    CREATE TABLE IF NOT EXISTS links (
+        parent BLOB NOT NULL,
+        child BLOB NOT NULL,
+        PRIMARY KEY (parent, child)
+    );
+
+    CREATE TABLE IF NOT EXISTS entries (
+        id BLOB NOT NULL PRIMARY KEY,
+        inner BLOB NOT NULL
+    );
+
+    CREATE TABLE IF NOT EXISTS cas (
+        id BLOB NOT NULL PRIMARY KEY,
+        content BLOB NOT NULL
+    );
+
+    CREATE TABLE IF NOT EXISTS cas_written (
+        id BLOB NOT NULL PRIMARY KEY,
+        written INTEGER NOT NULL
+    );
+    ",
+    ),
+    // Object bodies may be compressed, tagged with the codec used. Existing rows are
+    // uncompressed.
+    Step::Sql(
+        "
+    ALTER TABLE cas ADD COLUMN codec INTEGER NOT NULL DEFAULT 0;
+    ",
+    ),
+    // Entries stored before parent links were recorded have none.
+    Step::Run(backfill_links),
+];
+
+/// The schema version this build of the crate reads and writes.
+// There will never be anywhere near `u32::MAX` steps.
+#[allow(clippy::cast_possible_truncation)]
+pub const SCHEMA_VERSION: u32 = MIGRATIONS.len() as u32;
+
+/// Brings `db` up to [`SCHEMA_VERSION`], running each missing step in its own transaction.
+///
+/// Refuses to touch databases written by a newer version, since their layout is unknown.
+pub(crate) fn migrate(db: &mut Connection) -> Result<()> {
+    let version: u32 = db.query_row("PRAGMA user_version", params!(), |row| row.get(0))?;
+
+    if version > SCHEMA_VERSION {
+        return Err(JournalError::SchemaTooNew(version));
+    }
+
+    for (from, step) in MIGRATIONS.iter().enumerate().skip(version as usize) {
+        let tx = db.transaction()?;
+
+        match step {
+            Step::Sql(sql) => tx.execute_batch(sql)?,
+            Step::Run(run) => run(&tx)?,
+        }
+
+        tx.execute_batch(&format!("PRAGMA user_version = {}", from + 1))?;
+
+        tx.commit()?;
+    }
+
+    Ok(())
+}
+
+/// Records the parent links of every stored entry. Entries that can't be decoded or whose
+/// signatures are bad are skipped, and left for [`fsck`](crate::fsck) to report.
+fn backfill_links(db: &Connection) -> Result<()> {
+    let mut entries = db.prepare("SELECT id, inner FROM entries")?;
+    let mut insert = db.prepare("INSERT OR IGNORE INTO links VALUES (?1, ?2)")?;
+
+    let mut rows = entries.query(params!())?;
+
+    while let Some(row) = rows.next()? {
+        let id: Vec<u8> = row.get(0)?;
+        let inner: Vec<u8> = row.get(1)?;
+
+        let entry = match serde_cbor::from_slice::<Signed>(&inner)
+            .ok()
+            .and_then(|s| s.verify())
+        {
+            Some(entry) => entry,
+            None => continue,
+        };
+
+        for parent in entry.parents() {
+            insert.execute(params!(parent, id))?;
+        }
+    }
+
+    Ok(())
+}
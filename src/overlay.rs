@@ -0,0 +1,244 @@
+//! Journals that keep old history somewhere else.
+//!
+//! An [`OverlayJournal`] writes everything to a primary journal, but when an entry or object is
+//! not found there it looks in one or more read-only [archives](Archive), such as a large
+//! database kept on a server or a [bundle](crate::bundle) file. History that has been moved into
+//! an archive still resolves when something walks back into it.
+
+use crate::pack::Pack;
+use crate::{
+    ApplicationId, CASKey, CASObj, CasStore, DevicePublicKey, EntryStore, HashAlgorithm, HeadStore,
+    Journal, JournalError, JournalKey, KeyStore, Result, SettingsStore, Signed, Transactional,
+};
+use sodiumoxide::crypto::sign;
+use std::collections::HashMap;
+use std::io::{Read, Seek};
+use std::rc::Rc;
+
+static_assertions::assert_obj_safe!(Archive);
+
+/// A read-only source of entries and objects.
+///
+/// Every journal is one, and so is a [`Pack`], which is what [`bundle::open`](crate::bundle::open)
+/// gives.
+pub trait Archive {
+    fn archived_entry(&self, key: JournalKey) -> Result<Option<Signed>>;
+    fn archived_object(&self, key: CASKey) -> Result<Option<CASObj>>;
+}
+
+impl<T: EntryStore + CasStore + ?Sized> Archive for T {
+    fn archived_entry(&self, key: JournalKey) -> Result<Option<Signed>> {
+        self.get_signed(key)
+    }
+
+    fn archived_object(&self, key: CASKey) -> Result<Option<CASObj>> {
+        self.cas_get(key)
+    }
+}
+
+impl<R: Read + Seek> Archive for Pack<R> {
+    fn archived_entry(&self, key: JournalKey) -> Result<Option<Signed>> {
+        self.entry(key)
+    }
+
+    fn archived_object(&self, key: CASKey) -> Result<Option<CASObj>> {
+        self.object(key)
+    }
+}
+
+/// A [`Journal`] that writes to a primary journal and falls back to archives on reads.
+///
+/// ```ignore
+/// let journal = OverlayJournal::new(Rc::new(SqliteJournal::new("sqlite.db")?))
+///     .with_archive(Rc::new(bundle::open(File::open("2019.bundle")?)?));
+/// ```
+///
+/// Only [`get_signed`](EntryStore::get_signed) (and so [`get`](EntryStore::get)) and
+/// [`cas_get`](CasStore::cas_get) look in the archives, trying each in the order they were added.
+/// What they find there must hash to the key asked for, and entries must be validly signed, or the
+/// read fails with [`JournalError::Integrity`], so archives need not be trusted. It is not copied
+/// into the primary journal unless [`cache_archived`](OverlayJournal::cache_archived) is used.
+/// Everything else, including [`children`](EntryStore::children) and the entry and object
+/// listings, only sees the primary journal, so [`gc`](crate::gc) never deletes from an archive and
+/// [`fsck`](crate::fsck) checks archived history only where it is reachable from a head.
+pub struct OverlayJournal {
+    primary: Rc<dyn Journal>,
+    archives: Vec<Rc<dyn Archive>>,
+    cache: bool,
+}
+
+impl OverlayJournal {
+    /// An overlay on `primary` with no archives yet.
+    pub fn new<J: Journal + 'static>(primary: Rc<J>) -> Self {
+        Self {
+            primary,
+            archives: Vec::new(),
+            cache: false,
+        }
+    }
+
+    /// Adds an archive to look in after the primary journal and any archives already added.
+    #[must_use]
+    pub fn with_archive(mut self, archive: Rc<dyn Archive>) -> Self {
+        self.archives.push(archive);
+        self
+    }
+
+    /// Stores entries and objects found in archives in the primary journal, so that each is only
+    /// looked up once. This suits archives that are slow to read, such as a
+    /// [`Remote`](crate::sync::Remote).
+    #[must_use]
+    pub fn cache_archived(mut self) -> Self {
+        self.cache = true;
+        self
+    }
+}
+
+impl Transactional for OverlayJournal {
+    fn begin_transaction(&self) -> Result<()> {
+        self.primary.begin_transaction()
+    }
+
+    fn commit_transaction(&self) -> Result<()> {
+        self.primary.commit_transaction()
+    }
+
+    fn rollback_transaction(&self) -> Result<()> {
+        self.primary.rollback_transaction()
+    }
+}
+
+impl SettingsStore for OverlayJournal {
+    fn settings_get(&self, key: &str) -> Result<Option<Vec<u8>>> {
+        self.primary.settings_get(key)
+    }
+
+    fn settings_set(&self, key: &str, value: &[u8]) -> Result<()> {
+        self.primary.settings_set(key, value)
+    }
+}
+
+impl KeyStore for OverlayJournal {
+    fn pubkey(&self) -> Result<DevicePublicKey> {
+        self.primary.pubkey()
+    }
+
+    fn privkey(&self) -> Result<sign::SecretKey> {
+        self.primary.privkey()
+    }
+}
+
+impl HeadStore for OverlayJournal {
+    fn heads(&self) -> Result<HashMap<(ApplicationId, DevicePublicKey), JournalKey>> {
+        self.primary.heads()
+    }
+
+    fn update_head(
+        &self,
+        device: DevicePublicKey,
+        appid: ApplicationId,
+        key: JournalKey,
+    ) -> Result<()> {
+        self.primary.update_head(device, appid, key)
+    }
+
+    fn update_head_if(
+        &self,
+        device: DevicePublicKey,
+        appid: ApplicationId,
+        expected: Option<JournalKey>,
+        key: JournalKey,
+    ) -> Result<()> {
+        self.primary.update_head_if(device, appid, expected, key)
+    }
+}
+
+impl EntryStore for OverlayJournal {
+    fn get_signed(&self, key: JournalKey) -> Result<Option<Signed>> {
+        if let Some(signed) = self.primary.get_signed(key)? {
+            return Ok(Some(signed));
+        }
+
+        for archive in &self.archives {
+            if let Some(signed) = archive.archived_entry(key)? {
+                if signed.key_with(key.algorithm()) != key || signed.verify().is_none() {
+                    return Err(JournalError::Integrity(format!(
+                        "archive has a bad entry for {:?}",
+                        key
+                    )));
+                }
+
+                if self.cache {
+                    self.primary.put_signed_with(&signed, key.algorithm())?;
+                }
+
+                return Ok(Some(signed));
+            }
+        }
+
+        Ok(None)
+    }
+
+    fn put_signed_with(&self, signed: &Signed, algorithm: HashAlgorithm) -> Result<JournalKey> {
+        self.primary.put_signed_with(signed, algorithm)
+    }
+
+    fn children(&self, key: JournalKey) -> Result<Vec<JournalKey>> {
+        self.primary.children(key)
+    }
+
+    fn entry_list(&self) -> Result<Vec<JournalKey>> {
+        self.primary.entry_list()
+    }
+
+    fn entry_ids(&self) -> Result<Vec<std::result::Result<JournalKey, Vec<u8>>>> {
+        self.primary.entry_ids()
+    }
+}
+
+impl CasStore for OverlayJournal {
+    fn cas_get(&self, key: CASKey) -> Result<Option<CASObj>> {
+        if let Some(obj) = self.primary.cas_get(key)? {
+            return Ok(Some(obj));
+        }
+
+        for archive in &self.archives {
+            if let Some(obj) = archive.archived_object(key)? {
+                if obj.key_with(key.algorithm()) != key {
+                    return Err(JournalError::Integrity(format!(
+                        "archive has a bad object for {:?}",
+                        key
+                    )));
+                }
+
+                if self.cache {
+                    self.primary.cas_put_with(obj.clone(), key.algorithm())?;
+                }
+
+                return Ok(Some(obj));
+            }
+        }
+
+        Ok(None)
+    }
+
+    fn cas_put_with(&self, obj: CASObj, algorithm: HashAlgorithm) -> Result<CASKey> {
+        self.primary.cas_put_with(obj, algorithm)
+    }
+
+    fn cas_list(&self) -> Result<Vec<CASKey>> {
+        self.primary.cas_list()
+    }
+
+    fn cas_ids(&self) -> Result<Vec<std::result::Result<CASKey, Vec<u8>>>> {
+        self.primary.cas_ids()
+    }
+
+    fn cas_delete(&self, key: CASKey) -> Result<()> {
+        self.primary.cas_delete(key)
+    }
+
+    fn cas_written(&self, key: CASKey) -> Result<Option<u64>> {
+        self.primary.cas_written(key)
+    }
+}
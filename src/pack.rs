@@ -0,0 +1,503 @@
+//! Pack files: many objects and signed entries in one file, with an index for finding each.
+//!
+//! A pack is the magic and a version byte, then one record per object or entry, then the index,
+//! then the index's offset as a big endian `u64`. Each record is:
+//!
+//! - its kind, `0` for an object or `1` for an entry,
+//! - the length of its key as a byte, then the key as a multihash,
+//! - how the body is stored, `0` for whole or `1` for a delta against an earlier record,
+//! - the codec the body is compressed with,
+//! - for a delta, the offset of the record it is against, as a big endian `u64`,
+//! - the length of the body as a big endian `u32`, then the body.
+//!
+//! Bodies are the same CBOR the journals store and hash. Deltas are only ever against whole
+//! records, so reading any record takes at most two reads. The index is a big endian `u32` count,
+//! then for each record its kind, key length, key and offset.
+//!
+//! Keys are whatever the writer claims. Anyone reading a pack from elsewhere must check that
+//! bodies hash to their keys.
+
+use crate::codec;
+use crate::hash::{read_varint, write_varint};
+use crate::{CASKey, CASObj, JournalError, JournalKey, Multihash, Result, Signed};
+use std::cell::RefCell;
+use std::collections::{HashMap, HashSet, VecDeque};
+use std::convert::TryFrom;
+use std::io::{Read, Seek, SeekFrom, Write};
+
+/// Written at the start of every pack, followed by the format version.
+pub const MAGIC: &[u8; 14] = b"distcomp-pack\n";
+const VERSION: u8 = 1;
+
+/// How many recent whole objects each new object is tried as a delta against.
+const WINDOW: usize = 10;
+
+/// A delta is only kept if the whole body is at least this many times its size.
+const MAX_DELTA_RATIO: usize = 2;
+
+/// The length of the runs of bytes deltas look for in their base.
+const BLOCK: usize = 8;
+
+/// Bodies larger than this are always stored whole and never used as a base. Large objects are
+/// normally split into chunks before they get here, and diffing them against the whole window
+/// would take far longer than it could save.
+const MAX_DELTA_BODY: usize = 1 << 20;
+
+/// The largest body a pack may hold, once inflated and any delta applied. Packs come from peers
+/// and bundle files, so nothing in one is allowed to make a reader allocate more than this.
+pub const MAX_BODY_LEN: usize = 256 << 20;
+
+const WHOLE: u8 = 0;
+const DELTA: u8 = 1;
+
+#[derive(Clone, Copy, PartialEq, Eq, Hash, Debug)]
+enum Kind {
+    Object = 0,
+    Entry = 1,
+}
+
+impl Kind {
+    fn from_u8(kind: u8) -> Result<Self> {
+        match kind {
+            0 => Ok(Kind::Object),
+            1 => Ok(Kind::Entry),
+            _ => Err(malformed(&format!("unknown record kind {}", kind))),
+        }
+    }
+}
+
+fn malformed(msg: &str) -> JournalError {
+    JournalError::Integrity(format!("malformed pack: {}", msg))
+}
+
+/// Encodes `target` as copies from `base` and inserted bytes. Matching runs are found by looking
+/// up each position of `target` in an index of `base`'s aligned blocks, then extended both ways.
+fn diff(base: &[u8], target: &[u8]) -> Vec<u8> {
+    let mut out = Vec::new();
+
+    let insert = |out: &mut Vec<u8>, data: &[u8]| {
+        if !data.is_empty() {
+            out.push(1);
+            write_varint(out, data.len() as u64);
+            out.extend_from_slice(data);
+        }
+    };
+
+    let mut blocks = HashMap::new();
+
+    for start in (0..base.len().saturating_sub(BLOCK - 1)).step_by(BLOCK) {
+        blocks.entry(&base[start..start + BLOCK]).or_insert(start);
+    }
+
+    let mut pos = 0;
+    let mut pending = 0;
+
+    while pos + BLOCK <= target.len() {
+        let start = if let Some(&start) = blocks.get(&target[pos..pos + BLOCK]) {
+            start
+        } else {
+            pos += 1;
+            continue;
+        };
+
+        let mut len = BLOCK;
+
+        while start + len < base.len()
+            && pos + len < target.len()
+            && base[start + len] == target[pos + len]
+        {
+            len += 1;
+        }
+
+        let mut back = 0;
+
+        while back < pos - pending
+            && back < start
+            && base[start - back - 1] == target[pos - back - 1]
+        {
+            back += 1;
+        }
+
+        insert(&mut out, &target[pending..pos - back]);
+
+        out.push(0);
+        write_varint(&mut out, (start - back) as u64);
+        write_varint(&mut out, (len + back) as u64);
+
+        pos += len;
+        pending = pos;
+    }
+
+    insert(&mut out, &target[pending..]);
+
+    out
+}
+
+/// Undoes [`diff`], or returns `None` if `delta` is malformed, doesn't fit `base` or would produce
+/// more than [`MAX_BODY_LEN`] bytes.
+fn apply(base: &[u8], mut delta: &[u8]) -> Option<Vec<u8>> {
+    let mut out = Vec::new();
+
+    while let Some((&op, rest)) = delta.split_first() {
+        delta = rest;
+
+        match op {
+            0 => {
+                let start = usize::try_from(read_varint(&mut delta)?).ok()?;
+                let len = usize::try_from(read_varint(&mut delta)?).ok()?;
+
+                if out.len().checked_add(len)? > MAX_BODY_LEN {
+                    return None;
+                }
+
+                out.extend_from_slice(base.get(start..start.checked_add(len)?)?);
+            }
+            1 => {
+                let len = usize::try_from(read_varint(&mut delta)?).ok()?;
+
+                if len > delta.len() || out.len().checked_add(len)? > MAX_BODY_LEN {
+                    return None;
+                }
+
+                let (data, rest) = delta.split_at(len);
+                out.extend_from_slice(data);
+                delta = rest;
+            }
+            _ => return None,
+        }
+    }
+
+    Some(out)
+}
+
+/// Writes a pack, choosing deltas for objects as they are added.
+pub struct PackWriter<W> {
+    writer: W,
+    offset: u64,
+    index: Vec<(Kind, Multihash, u64)>,
+    seen: HashSet<(Kind, Multihash)>,
+
+    /// The offsets and bodies of the most recent whole objects, oldest first.
+    window: VecDeque<(u64, Vec<u8>)>,
+}
+
+impl<W: Write> PackWriter<W> {
+    pub fn new(mut writer: W) -> Result<Self> {
+        writer.write_all(MAGIC)?;
+        writer.write_all(&[VERSION])?;
+
+        Ok(Self {
+            writer,
+            offset: MAGIC.len() as u64 + 1,
+            index: Vec::new(),
+            seen: HashSet::new(),
+            window: VecDeque::new(),
+        })
+    }
+
+    /// How many bytes of the pack have been written so far, not counting the index.
+    pub fn size(&self) -> u64 {
+        self.offset
+    }
+
+    /// How many records have been added.
+    pub fn len(&self) -> usize {
+        self.index.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.index.is_empty()
+    }
+
+    /// Adds `obj` under `key`. Objects already added are skipped.
+    pub fn add_object(&mut self, key: CASKey, obj: &CASObj) -> Result<()> {
+        if !self.seen.insert((Kind::Object, key.hash())) {
+            return Ok(());
+        }
+
+        let body = serde_cbor::to_vec(obj)?;
+
+        let delta = self
+            .window
+            .iter()
+            .filter(|_| body.len() <= MAX_DELTA_BODY)
+            .map(|(offset, base)| (*offset, diff(base, &body)))
+            .min_by_key(|(_, delta)| delta.len())
+            .filter(|(_, delta)| delta.len() * MAX_DELTA_RATIO <= body.len());
+
+        if let Some((base, delta)) = delta {
+            return self.write_record(Kind::Object, key.hash(), Some(base), delta);
+        }
+
+        let offset = self.offset;
+
+        if body.len() > MAX_DELTA_BODY {
+            return self.write_record(Kind::Object, key.hash(), None, body);
+        }
+
+        self.write_record(Kind::Object, key.hash(), None, body.clone())?;
+
+        if self.window.len() == WINDOW {
+            self.window.pop_front();
+        }
+
+        self.window.push_back((offset, body));
+
+        Ok(())
+    }
+
+    /// Adds `signed` under `key`. Entries already added are skipped.
+    pub fn add_entry(&mut self, key: JournalKey, signed: &Signed) -> Result<()> {
+        if !self.seen.insert((Kind::Entry, key.hash())) {
+            return Ok(());
+        }
+
+        self.write_record(Kind::Entry, key.hash(), None, serde_cbor::to_vec(signed)?)
+    }
+
+    fn write_record(
+        &mut self,
+        kind: Kind,
+        key: Multihash,
+        base: Option<u64>,
+        body: Vec<u8>,
+    ) -> Result<()> {
+        if body.len() > MAX_BODY_LEN {
+            return Err(JournalError::Integrity(
+                "record too large to pack".to_string(),
+            ));
+        }
+
+        let (codec, body) = codec::compress(body)?;
+
+        let key_bytes = key.to_multihash();
+
+        let mut header = vec![kind as u8];
+        header.push(u8::try_from(key_bytes.len()).expect("keys are short"));
+        header.extend_from_slice(&key_bytes);
+
+        match base {
+            Some(base) => {
+                header.extend_from_slice(&[DELTA, codec as u8]);
+                header.extend_from_slice(&base.to_be_bytes());
+            }
+            None => header.extend_from_slice(&[WHOLE, codec as u8]),
+        }
+
+        let len = u32::try_from(body.len()).expect("bodies are limited to MAX_BODY_LEN");
+        header.extend_from_slice(&len.to_be_bytes());
+
+        self.writer.write_all(&header)?;
+        self.writer.write_all(&body)?;
+
+        self.index.push((kind, key, self.offset));
+        self.offset += (header.len() + body.len()) as u64;
+
+        Ok(())
+    }
+
+    /// Writes the index and returns the underlying writer.
+    pub fn finish(mut self) -> Result<W> {
+        let index_offset = self.offset;
+
+        let count = u32::try_from(self.index.len())
+            .map_err(|_| JournalError::Integrity("too many records to pack".to_string()))?;
+
+        let mut index = count.to_be_bytes().to_vec();
+
+        for (kind, key, offset) in &self.index {
+            let key_bytes = key.to_multihash();
+
+            index.push(*kind as u8);
+            index.push(u8::try_from(key_bytes.len()).expect("keys are short"));
+            index.extend_from_slice(&key_bytes);
+            index.extend_from_slice(&offset.to_be_bytes());
+        }
+
+        index.extend_from_slice(&index_offset.to_be_bytes());
+
+        self.writer.write_all(&index)?;
+        self.writer.flush()?;
+
+        Ok(self.writer)
+    }
+}
+
+/// A pack opened for reading. Only the index is read up front; records are read as they are
+/// asked for.
+#[derive(Debug)]
+pub struct Pack<R> {
+    reader: RefCell<R>,
+
+    /// Where the pack starts in `reader`, which offsets in the pack are relative to.
+    start: u64,
+
+    /// Where the records end and the index starts, relative to `start`.
+    index_offset: u64,
+    index: HashMap<(Kind, Multihash), u64>,
+}
+
+/// Where `offset`, read from the pack, lies in a reader the pack starts `start` bytes into.
+fn offset_in(start: u64, offset: u64) -> Result<u64> {
+    start
+        .checked_add(offset)
+        .ok_or_else(|| malformed(&format!("offset {} out of range", offset)))
+}
+
+fn read_array<R: Read, A: AsMut<[u8]> + Default>(reader: &mut R) -> Result<A> {
+    let mut buf = A::default();
+    reader.read_exact(buf.as_mut())?;
+    Ok(buf)
+}
+
+fn read_key<R: Read>(reader: &mut R) -> Result<Multihash> {
+    let [len] = read_array::<_, [u8; 1]>(reader)?;
+
+    let mut key = vec![0; usize::from(len)];
+    reader.read_exact(&mut key)?;
+
+    Multihash::from_multihash(&key).ok_or_else(|| malformed("bad key"))
+}
+
+impl<R: Read + Seek> Pack<R> {
+    /// Checks the header and reads the index.
+    pub fn open(reader: R) -> Result<Self> {
+        Self::open_at(reader, 0)
+    }
+
+    /// Opens a pack that starts `start` bytes into `reader` and runs to its end, such as the one
+    /// inside a [bundle](crate::bundle).
+    pub fn open_at(mut reader: R, start: u64) -> Result<Self> {
+        let mut header = [0; MAGIC.len() + 1];
+        reader.seek(SeekFrom::Start(start))?;
+        reader.read_exact(&mut header)?;
+
+        if &header[..MAGIC.len()] != MAGIC {
+            return Err(malformed("not a pack"));
+        }
+
+        if header[MAGIC.len()] != VERSION {
+            return Err(malformed(&format!(
+                "unsupported version {}",
+                header[MAGIC.len()]
+            )));
+        }
+
+        reader.seek(SeekFrom::End(-8))?;
+        let index_offset = u64::from_be_bytes(read_array(&mut reader)?);
+
+        reader.seek(SeekFrom::Start(offset_in(start, index_offset)?))?;
+        let count = u32::from_be_bytes(read_array(&mut reader)?);
+
+        let mut index = HashMap::new();
+
+        for _ in 0..count {
+            let [kind] = read_array::<_, [u8; 1]>(&mut reader)?;
+            let key = read_key(&mut reader)?;
+            let offset = u64::from_be_bytes(read_array(&mut reader)?);
+
+            index.insert((Kind::from_u8(kind)?, key), offset);
+        }
+
+        Ok(Self {
+            reader: RefCell::new(reader),
+            start,
+            index_offset,
+            index,
+        })
+    }
+
+    pub fn len(&self) -> usize {
+        self.index.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.index.is_empty()
+    }
+
+    pub fn objects(&self) -> Vec<CASKey> {
+        self.keys(Kind::Object).map(CASKey::new).collect()
+    }
+
+    pub fn entries(&self) -> Vec<JournalKey> {
+        self.keys(Kind::Entry).map(JournalKey::new).collect()
+    }
+
+    fn keys(&self, kind: Kind) -> impl Iterator<Item = Multihash> + '_ {
+        self.index
+            .keys()
+            .filter(move |(k, _)| *k == kind)
+            .map(|&(_, key)| key)
+    }
+
+    pub fn contains_object(&self, key: CASKey) -> bool {
+        self.index.contains_key(&(Kind::Object, key.hash()))
+    }
+
+    pub fn contains_entry(&self, key: JournalKey) -> bool {
+        self.index.contains_key(&(Kind::Entry, key.hash()))
+    }
+
+    pub fn object(&self, key: CASKey) -> Result<Option<CASObj>> {
+        match self.index.get(&(Kind::Object, key.hash())) {
+            Some(&offset) => Ok(Some(serde_cbor::from_slice(&self.body(offset, true)?)?)),
+            None => Ok(None),
+        }
+    }
+
+    pub fn entry(&self, key: JournalKey) -> Result<Option<Signed>> {
+        match self.index.get(&(Kind::Entry, key.hash())) {
+            Some(&offset) => Ok(Some(serde_cbor::from_slice(&self.body(offset, true)?)?)),
+            None => Ok(None),
+        }
+    }
+
+    /// Reads the body of the record at `offset`, applying it to its base if it is a delta and
+    /// `resolve_delta` allows that.
+    fn body(&self, offset: u64, resolve_delta: bool) -> Result<Vec<u8>> {
+        let (base, codec, body) = {
+            let mut reader = self.reader.borrow_mut();
+
+            reader.seek(SeekFrom::Start(offset_in(self.start, offset)?))?;
+
+            let [_kind] = read_array::<_, [u8; 1]>(&mut *reader)?;
+            read_key(&mut *reader)?;
+            let [storage, codec] = read_array::<_, [u8; 2]>(&mut *reader)?;
+
+            let base = match storage {
+                WHOLE => None,
+                DELTA => Some(u64::from_be_bytes(read_array(&mut *reader)?)),
+                _ => return Err(malformed(&format!("unknown storage {}", storage))),
+            };
+
+            let len = u64::from(u32::from_be_bytes(read_array(&mut *reader)?));
+
+            let end = offset_in(reader.seek(SeekFrom::Current(0))?, len)?;
+
+            if end > offset_in(self.start, self.index_offset)? || len > MAX_BODY_LEN as u64 {
+                return Err(malformed("record runs past the end of the records"));
+            }
+
+            let mut body = Vec::new();
+            (&mut *reader).take(len).read_to_end(&mut body)?;
+
+            if body.len() as u64 != len {
+                return Err(malformed("record runs past the end of the pack"));
+            }
+
+            (base, codec, body)
+        };
+
+        let body = codec::decompress_at_most(i64::from(codec), body, MAX_BODY_LEN as u64)?;
+
+        match base {
+            None => Ok(body),
+            Some(_) if !resolve_delta => Err(malformed("delta against a delta")),
+            Some(base) => {
+                let base = self.body(base, false)?;
+
+                apply(&base, &body).ok_or_else(|| malformed("bad delta"))
+            }
+        }
+    }
+}
@@ -0,0 +1,182 @@
+//! Journals that can be shared between threads.
+
+use crate::{
+    store, ApplicationId, CASKey, CASObj, CasStore, DevicePublicKey, EntryStore, HashAlgorithm,
+    HeadStore, JournalKey, KeyStore, Result, SettingsStore, Signed, SqliteJournal, Transactional,
+};
+use sodiumoxide::crypto::sign;
+use std::collections::HashMap;
+use std::sync::{Mutex, MutexGuard, PoisonError};
+use std::thread::{self, ThreadId};
+
+/// A [`Journal`] over a sqlite database that can be shared between threads.
+///
+/// Connections are pooled: each call takes an idle one, opening another only if none is free, so
+/// readers don't block each other and there are never more connections than threads using the
+/// journal at once. A thread that begins a transaction keeps its connection until the transaction
+/// ends, so the transaction only ever sees that thread's writes. Writers from different threads are
+/// serialized by sqlite, waiting for each other rather than failing.
+///
+/// Since there are several connections, `path` must be a real file: `:memory:` would give each
+/// one its own empty database.
+#[derive(Debug)]
+pub struct SharedSqliteJournal {
+    path: String,
+
+    /// Connections no thread is using.
+    idle: Mutex<Vec<SqliteJournal>>,
+
+    /// Connections held by threads that are in a transaction.
+    held: Mutex<HashMap<ThreadId, SqliteJournal>>,
+}
+
+static_assertions::assert_impl_all!(SharedSqliteJournal: Send, Sync);
+
+fn lock<T>(mutex: &Mutex<T>) -> MutexGuard<'_, T> {
+    mutex.lock().unwrap_or_else(PoisonError::into_inner)
+}
+
+impl SharedSqliteJournal {
+    pub fn new(path: &str) -> Result<Self> {
+        // Opening the first connection here, before any other thread can, means the database is
+        // migrated and has its keypair before anything else races to create them.
+        let first = SqliteJournal::new(path)?;
+
+        Ok(Self {
+            path: path.to_string(),
+            idle: Mutex::new(vec![first]),
+            held: Mutex::default(),
+        })
+    }
+
+    fn with<T>(&self, f: impl FnOnce(&SqliteJournal) -> Result<T>) -> Result<T> {
+        let id = thread::current().id();
+
+        let held = lock(&self.held).remove(&id);
+        let idle = || lock(&self.idle).pop();
+
+        let journal = match held.or_else(idle) {
+            Some(journal) => journal,
+            None => SqliteJournal::new(&self.path)?,
+        };
+
+        // If `f` panics the connection is dropped rather than returned, which also rolls back any
+        // transaction it had open.
+        let result = f(&journal);
+
+        if journal.in_transaction() {
+            lock(&self.held).insert(id, journal);
+        } else {
+            lock(&self.idle).push(journal);
+        }
+
+        result
+    }
+}
+
+impl Transactional for SharedSqliteJournal {
+    fn begin_transaction(&self) -> Result<()> {
+        self.with(SqliteJournal::begin_transaction)
+    }
+
+    fn commit_transaction(&self) -> Result<()> {
+        self.with(SqliteJournal::commit_transaction)
+    }
+
+    fn rollback_transaction(&self) -> Result<()> {
+        self.with(SqliteJournal::rollback_transaction)
+    }
+}
+
+impl SettingsStore for SharedSqliteJournal {
+    fn settings_get(&self, key: &str) -> Result<Option<Vec<u8>>> {
+        self.with(|j| j.settings_get(key))
+    }
+
+    fn settings_set(&self, key: &str, value: &[u8]) -> Result<()> {
+        self.with(|j| j.settings_set(key, value))
+    }
+}
+
+impl KeyStore for SharedSqliteJournal {
+    fn pubkey(&self) -> Result<DevicePublicKey> {
+        store::settings_pubkey(self)
+    }
+
+    fn privkey(&self) -> Result<sign::SecretKey> {
+        store::settings_privkey(self)
+    }
+}
+
+impl HeadStore for SharedSqliteJournal {
+    fn heads(&self) -> Result<HashMap<(ApplicationId, DevicePublicKey), JournalKey>> {
+        self.with(SqliteJournal::heads)
+    }
+
+    fn update_head(
+        &self,
+        device: DevicePublicKey,
+        appid: ApplicationId,
+        key: JournalKey,
+    ) -> Result<()> {
+        self.with(|j| j.update_head(device, appid, key))
+    }
+
+    fn update_head_if(
+        &self,
+        device: DevicePublicKey,
+        appid: ApplicationId,
+        expected: Option<JournalKey>,
+        key: JournalKey,
+    ) -> Result<()> {
+        self.with(|j| j.update_head_if(device, appid, expected, key))
+    }
+}
+
+impl EntryStore for SharedSqliteJournal {
+    fn get_signed(&self, key: JournalKey) -> Result<Option<Signed>> {
+        self.with(|j| j.get_signed(key))
+    }
+
+    fn put_signed_with(&self, signed: &Signed, algorithm: HashAlgorithm) -> Result<JournalKey> {
+        self.with(|j| j.put_signed_with(signed, algorithm))
+    }
+
+    fn children(&self, key: JournalKey) -> Result<Vec<JournalKey>> {
+        self.with(|j| j.children(key))
+    }
+
+    fn entry_list(&self) -> Result<Vec<JournalKey>> {
+        self.with(SqliteJournal::entry_list)
+    }
+
+    fn entry_ids(&self) -> Result<Vec<std::result::Result<JournalKey, Vec<u8>>>> {
+        self.with(SqliteJournal::entry_ids)
+    }
+}
+
+impl CasStore for SharedSqliteJournal {
+    fn cas_get(&self, key: CASKey) -> Result<Option<CASObj>> {
+        self.with(|j| j.cas_get(key))
+    }
+
+    fn cas_put_with(&self, obj: CASObj, algorithm: HashAlgorithm) -> Result<CASKey> {
+        self.with(|j| j.cas_put_with(obj, algorithm))
+    }
+
+    fn cas_list(&self) -> Result<Vec<CASKey>> {
+        self.with(SqliteJournal::cas_list)
+    }
+
+    fn cas_ids(&self) -> Result<Vec<std::result::Result<CASKey, Vec<u8>>>> {
+        self.with(SqliteJournal::cas_ids)
+    }
+
+    fn cas_delete(&self, key: CASKey) -> Result<()> {
+        self.with(|j| j.cas_delete(key))
+    }
+
+    fn cas_written(&self, key: CASKey) -> Result<Option<u64>> {
+        self.with(|j| j.cas_written(key))
+    }
+}
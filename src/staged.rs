@@ -0,0 +1,339 @@
+//! Journals that hold back their writes until they are applied all at once.
+
+use crate::clock::{self, CLOCK_SETTING};
+use crate::{
+    transaction, ApplicationId, CASKey, CASObj, CasStore, DevicePublicKey, EntryStore,
+    HashAlgorithm, HeadStore, Journal, JournalError, JournalKey, KeyStore, Result, SettingsStore,
+    Signed, Transactional,
+};
+use sodiumoxide::crypto::sign;
+use std::cell::RefCell;
+use std::collections::{HashMap, HashSet};
+use std::rc::Rc;
+use std::time::{SystemTime, UNIX_EPOCH};
+
+/// A [`Journal`] that keeps every write to itself until [`apply`](StagedJournal::apply) writes
+/// them to a base journal in one short transaction.
+///
+/// This lets something slow, such as a guest application, make a series of commits without
+/// holding the base journal's write lock while it runs, and leaves the base journal untouched if
+/// it fails partway. Reads see the staged writes on top of the base journal.
+pub struct StagedJournal {
+    base: Rc<dyn Journal>,
+    stage: RefCell<Stage>,
+
+    /// Copies of `stage` taken when each open transaction began, innermost last.
+    snapshots: RefCell<Vec<Stage>>,
+}
+
+#[derive(Clone, Default)]
+struct Stage {
+    settings: HashMap<String, Vec<u8>>,
+
+    /// Each staged head, with the base journal's head when it was first staged.
+    heads: HashMap<(ApplicationId, DevicePublicKey), (Option<JournalKey>, JournalKey)>,
+
+    entries: HashMap<JournalKey, Signed>,
+    children: HashMap<JournalKey, Vec<JournalKey>>,
+
+    /// Each staged object, with when it was written in seconds since the Unix epoch.
+    objects: HashMap<CASKey, (CASObj, u64)>,
+
+    /// Objects deleted from the base journal.
+    deleted: HashSet<CASKey>,
+}
+
+impl StagedJournal {
+    pub fn new(base: Rc<dyn Journal>) -> Self {
+        Self {
+            base,
+            stage: RefCell::default(),
+            snapshots: RefCell::default(),
+        }
+    }
+
+    /// Writes everything staged to the base journal in one transaction, then clears the stage.
+    ///
+    /// Fails with [`JournalError::HeadConflict`], writing nothing and keeping the stage, if another
+    /// writer has moved one of the staged heads in the base journal since it was first read.
+    pub fn apply(&self) -> Result<()> {
+        let stage = self.stage.borrow().clone();
+
+        transaction(&*self.base, |base| {
+            // Heads go first, so that a conflict is found before anything else is written.
+            for (&(appid, device), &(expected, key)) in &stage.heads {
+                base.update_head_if(device, appid, expected, key)?;
+            }
+
+            for (&key, (obj, _)) in &stage.objects {
+                base.cas_put_with(obj.clone(), key.algorithm())?;
+            }
+
+            for &key in &stage.deleted {
+                base.cas_delete(key)?;
+            }
+
+            for (key, signed) in &stage.entries {
+                base.put_signed_with(signed, key.algorithm())?;
+            }
+
+            for (name, value) in &stage.settings {
+                if name == CLOCK_SETTING {
+                    // Another writer may have advanced the clock meanwhile, and it must never go
+                    // backwards.
+                    clock::observe(base, serde_cbor::from_slice(value)?)?;
+                } else {
+                    base.settings_set(name, value)?;
+                }
+            }
+
+            Ok(())
+        })?;
+
+        *self.stage.borrow_mut() = Stage::default();
+
+        Ok(())
+    }
+
+    fn head(&self, device: DevicePublicKey, appid: ApplicationId) -> Result<Option<JournalKey>> {
+        match self.stage.borrow().heads.get(&(appid, device)) {
+            Some(&(_, key)) => Ok(Some(key)),
+            None => Ok(self.base.heads()?.get(&(appid, device)).copied()),
+        }
+    }
+
+    fn stage_head(
+        &self,
+        device: DevicePublicKey,
+        appid: ApplicationId,
+        key: JournalKey,
+    ) -> Result<()> {
+        let expected = match self.stage.borrow().heads.get(&(appid, device)) {
+            Some(&(expected, _)) => expected,
+            None => self.base.heads()?.get(&(appid, device)).copied(),
+        };
+
+        self.stage
+            .borrow_mut()
+            .heads
+            .insert((appid, device), (expected, key));
+
+        Ok(())
+    }
+}
+
+impl Transactional for StagedJournal {
+    fn begin_transaction(&self) -> Result<()> {
+        let snapshot = self.stage.borrow().clone();
+
+        self.snapshots.borrow_mut().push(snapshot);
+
+        Ok(())
+    }
+
+    fn commit_transaction(&self) -> Result<()> {
+        self.snapshots
+            .borrow_mut()
+            .pop()
+            .ok_or_else(|| JournalError::Integrity("no transaction to commit".to_string()))?;
+
+        Ok(())
+    }
+
+    fn rollback_transaction(&self) -> Result<()> {
+        let snapshot =
+            self.snapshots.borrow_mut().pop().ok_or_else(|| {
+                JournalError::Integrity("no transaction to roll back".to_string())
+            })?;
+
+        *self.stage.borrow_mut() = snapshot;
+
+        Ok(())
+    }
+}
+
+impl SettingsStore for StagedJournal {
+    fn settings_get(&self, key: &str) -> Result<Option<Vec<u8>>> {
+        match self.stage.borrow().settings.get(key) {
+            Some(value) => Ok(Some(value.clone())),
+            None => self.base.settings_get(key),
+        }
+    }
+
+    fn settings_set(&self, key: &str, value: &[u8]) -> Result<()> {
+        self.stage
+            .borrow_mut()
+            .settings
+            .insert(key.to_string(), value.to_vec());
+
+        Ok(())
+    }
+}
+
+impl KeyStore for StagedJournal {
+    fn pubkey(&self) -> Result<DevicePublicKey> {
+        self.base.pubkey()
+    }
+
+    fn privkey(&self) -> Result<sign::SecretKey> {
+        self.base.privkey()
+    }
+}
+
+impl HeadStore for StagedJournal {
+    fn heads(&self) -> Result<HashMap<(ApplicationId, DevicePublicKey), JournalKey>> {
+        let mut heads = self.base.heads()?;
+
+        for (&head, &(_, key)) in &self.stage.borrow().heads {
+            heads.insert(head, key);
+        }
+
+        Ok(heads)
+    }
+
+    fn update_head(
+        &self,
+        device: DevicePublicKey,
+        appid: ApplicationId,
+        key: JournalKey,
+    ) -> Result<()> {
+        self.stage_head(device, appid, key)
+    }
+
+    fn update_head_if(
+        &self,
+        device: DevicePublicKey,
+        appid: ApplicationId,
+        expected: Option<JournalKey>,
+        key: JournalKey,
+    ) -> Result<()> {
+        let found = self.head(device, appid)?;
+
+        if found != expected {
+            return Err(JournalError::HeadConflict { expected, found });
+        }
+
+        self.stage_head(device, appid, key)
+    }
+}
+
+impl EntryStore for StagedJournal {
+    fn get_signed(&self, key: JournalKey) -> Result<Option<Signed>> {
+        match self.stage.borrow().entries.get(&key) {
+            Some(signed) => Ok(Some(signed.clone())),
+            None => self.base.get_signed(key),
+        }
+    }
+
+    fn put_signed_with(&self, signed: &Signed, algorithm: HashAlgorithm) -> Result<JournalKey> {
+        let key = signed.key_with(algorithm);
+
+        let entry = signed.verify().ok_or(JournalError::Signature(key))?;
+
+        let mut stage = self.stage.borrow_mut();
+
+        if stage.entries.contains_key(&key) {
+            return Ok(key);
+        }
+
+        stage.entries.insert(key, signed.clone());
+
+        for parent in entry.parents() {
+            stage.children.entry(*parent).or_default().push(key);
+        }
+
+        Ok(key)
+    }
+
+    fn children(&self, key: JournalKey) -> Result<Vec<JournalKey>> {
+        let mut children = self.base.children(key)?;
+
+        if let Some(staged) = self.stage.borrow().children.get(&key) {
+            for &child in staged {
+                if !children.contains(&child) {
+                    children.push(child);
+                }
+            }
+        }
+
+        Ok(children)
+    }
+
+    fn entry_list(&self) -> Result<Vec<JournalKey>> {
+        let mut entries = self.base.entry_list()?;
+
+        entries.extend(self.stage.borrow().entries.keys().copied());
+        entries.sort();
+        entries.dedup();
+
+        Ok(entries)
+    }
+}
+
+impl CasStore for StagedJournal {
+    fn cas_get(&self, key: CASKey) -> Result<Option<CASObj>> {
+        let stage = self.stage.borrow();
+
+        if let Some((obj, _)) = stage.objects.get(&key) {
+            return Ok(Some(obj.clone()));
+        }
+
+        if stage.deleted.contains(&key) {
+            return Ok(None);
+        }
+
+        self.base.cas_get(key)
+    }
+
+    fn cas_put_with(&self, obj: CASObj, algorithm: HashAlgorithm) -> Result<CASKey> {
+        let key = obj.key_with(algorithm);
+
+        let now = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .unwrap_or_default()
+            .as_secs();
+
+        let mut stage = self.stage.borrow_mut();
+
+        stage.deleted.remove(&key);
+        stage.objects.entry(key).or_insert((obj, now));
+
+        Ok(key)
+    }
+
+    fn cas_list(&self) -> Result<Vec<CASKey>> {
+        let stage = self.stage.borrow();
+
+        let mut objects = self.base.cas_list()?;
+
+        objects.retain(|key| !stage.deleted.contains(key));
+        objects.extend(stage.objects.keys().copied());
+        objects.sort();
+        objects.dedup();
+
+        Ok(objects)
+    }
+
+    fn cas_delete(&self, key: CASKey) -> Result<()> {
+        let mut stage = self.stage.borrow_mut();
+
+        stage.objects.remove(&key);
+        stage.deleted.insert(key);
+
+        Ok(())
+    }
+
+    fn cas_written(&self, key: CASKey) -> Result<Option<u64>> {
+        let stage = self.stage.borrow();
+
+        if let Some(&(_, written)) = stage.objects.get(&key) {
+            return Ok(Some(written));
+        }
+
+        if stage.deleted.contains(&key) {
+            return Ok(None);
+        }
+
+        self.base.cas_written(key)
+    }
+}
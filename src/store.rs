@@ -0,0 +1,187 @@
+//! The separate stores a [`Journal`](crate::Journal) is made of.
+//!
+//! Each backend implements all of them, but they can also be mixed, for example with
+//! [`CompositeJournal`](crate::CompositeJournal), to keep objects in one backend and heads in
+//! another, or to share one object store between several identities.
+
+use crate::{
+    ApplicationId, CASKey, CASObj, DevicePublicKey, HashAlgorithm, JournalEntry, JournalError,
+    JournalKey, Result, Signed,
+};
+use sodiumoxide::crypto::sign;
+use std::collections::HashMap;
+
+static_assertions::assert_obj_safe!(Transactional);
+static_assertions::assert_obj_safe!(SettingsStore);
+static_assertions::assert_obj_safe!(KeyStore);
+static_assertions::assert_obj_safe!(HeadStore);
+static_assertions::assert_obj_safe!(EntryStore);
+static_assertions::assert_obj_safe!(CasStore);
+
+/// A store whose writes can be grouped so that they are applied together or not at all.
+pub trait Transactional {
+    /// Starts grouping writes. Transactions can be nested, and each must be ended by a commit or
+    /// a rollback. See [`transaction`](crate::transaction) for the usual way of using these.
+    fn begin_transaction(&self) -> Result<()>;
+
+    /// Applies the writes since the matching [`begin_transaction`](Transactional::begin_transaction).
+    fn commit_transaction(&self) -> Result<()>;
+
+    /// Discards the writes since the matching
+    /// [`begin_transaction`](Transactional::begin_transaction).
+    fn rollback_transaction(&self) -> Result<()>;
+}
+
+/// Named byte strings, such as this device's clock.
+pub trait SettingsStore: Transactional {
+    fn settings_get(&self, key: &str) -> Result<Option<Vec<u8>>>;
+    fn settings_set(&self, key: &str, value: &[u8]) -> Result<()>;
+
+    /// The algorithm new entries and objects are keyed with, from the `HashAlgorithm` setting.
+    /// Defaults to SHA-256. Entries and objects keyed with any algorithm can be read regardless.
+    fn hash_algorithm(&self) -> Result<HashAlgorithm> {
+        match self.settings_get("HashAlgorithm")? {
+            Some(name) => std::str::from_utf8(&name)
+                .ok()
+                .and_then(HashAlgorithm::from_name)
+                .ok_or_else(|| JournalError::Integrity("unknown hash algorithm".to_string())),
+            None => Ok(HashAlgorithm::default()),
+        }
+    }
+}
+
+/// The keypair of the device that signs new entries.
+pub trait KeyStore {
+    fn pubkey(&self) -> Result<DevicePublicKey>;
+    fn privkey(&self) -> Result<sign::SecretKey>;
+}
+
+/// The latest entry of each device for each application.
+pub trait HeadStore: Transactional {
+    fn heads(&self) -> Result<HashMap<(ApplicationId, DevicePublicKey), JournalKey>>;
+    fn update_head(
+        &self,
+        device: DevicePublicKey,
+        appid: ApplicationId,
+        key: JournalKey,
+    ) -> Result<()>;
+
+    /// Moves a head to `key` only if it still points at `expected`, where `None` means the head
+    /// must not exist yet. Fails with [`JournalError::HeadConflict`] if another writer moved it
+    /// first, in which case the caller can re-read the head and retry or merge.
+    fn update_head_if(
+        &self,
+        device: DevicePublicKey,
+        appid: ApplicationId,
+        expected: Option<JournalKey>,
+        key: JournalKey,
+    ) -> Result<()>;
+}
+
+/// Signed entries, and which entries are children of which.
+pub trait EntryStore: Transactional {
+    fn get(&self, key: JournalKey) -> Result<Option<JournalEntry>> {
+        match self.get_signed(key)? {
+            Some(signed) => Ok(Some(signed.verify().ok_or(JournalError::Signature(key))?)),
+            None => Ok(None),
+        }
+    }
+
+    /// Gets an entry in the signed form it was stored in, without checking the signature.
+    fn get_signed(&self, key: JournalKey) -> Result<Option<Signed>>;
+
+    /// Stores an already signed entry, such as one received from another device, keyed with
+    /// `algorithm`. Fails if the signature is not valid. Entries received from elsewhere must be
+    /// stored under the algorithm their sender used, so that the keys referring to them still
+    /// match.
+    fn put_signed_with(&self, signed: &Signed, algorithm: HashAlgorithm) -> Result<JournalKey>;
+
+    /// The entries that have `key` as a parent.
+    fn children(&self, key: JournalKey) -> Result<Vec<JournalKey>>;
+
+    /// Every stored entry.
+    fn entry_list(&self) -> Result<Vec<JournalKey>>;
+
+    /// Like [`entry_list`](EntryStore::entry_list), but keeps going past stored ids that don't
+    /// decode as keys, giving their raw bytes instead, for [`fsck`](crate::fsck) to report.
+    /// Backends that can't hold such ids can keep the default.
+    fn entry_ids(&self) -> Result<Vec<std::result::Result<JournalKey, Vec<u8>>>> {
+        Ok(self.entry_list()?.into_iter().map(Ok).collect())
+    }
+}
+
+/// Content addressed objects.
+pub trait CasStore: Transactional {
+    fn cas_get(&self, key: CASKey) -> Result<Option<CASObj>>;
+
+    /// Stores an object keyed with `algorithm`.
+    fn cas_put_with(&self, obj: CASObj, algorithm: HashAlgorithm) -> Result<CASKey>;
+
+    fn cas_list(&self) -> Result<Vec<CASKey>>;
+
+    /// Like [`cas_list`](CasStore::cas_list), but keeps going past stored ids that don't decode as
+    /// keys, as [`entry_ids`](EntryStore::entry_ids) does.
+    fn cas_ids(&self) -> Result<Vec<std::result::Result<CASKey, Vec<u8>>>> {
+        Ok(self.cas_list()?.into_iter().map(Ok).collect())
+    }
+
+    /// Removes an object. Nothing checks that it is unreachable, see [`gc::gc`](crate::gc::gc)
+    /// for that.
+    fn cas_delete(&self, key: CASKey) -> Result<()>;
+
+    /// When an object was last written, in seconds since the Unix epoch, if that is known.
+    fn cas_written(&self, key: CASKey) -> Result<Option<u64>>;
+}
+
+/// A [`KeyStore`] holding a keypair directly, for example to sign with several identities while
+/// sharing the other stores.
+#[derive(Clone, Debug)]
+pub struct Identity {
+    pubkey: sign::PublicKey,
+    privkey: sign::SecretKey,
+}
+
+impl Identity {
+    pub fn new(pubkey: sign::PublicKey, privkey: sign::SecretKey) -> Self {
+        Self { pubkey, privkey }
+    }
+
+    /// Creates an identity with a freshly generated keypair.
+    pub fn generate() -> Self {
+        let (pubkey, privkey) = sign::gen_keypair();
+
+        Self { pubkey, privkey }
+    }
+}
+
+impl KeyStore for Identity {
+    fn pubkey(&self) -> Result<DevicePublicKey> {
+        Ok(DevicePublicKey(self.pubkey))
+    }
+
+    fn privkey(&self) -> Result<sign::SecretKey> {
+        Ok(self.privkey.clone())
+    }
+}
+
+/// Reads the keypair from the `PublicKey` and `PrivateKey` settings, which is where every backend
+/// in this crate keeps it.
+pub(crate) fn settings_pubkey(settings: &dyn SettingsStore) -> Result<DevicePublicKey> {
+    let key = settings
+        .settings_get("PublicKey")?
+        .ok_or_else(|| JournalError::MissingKey("PublicKey".to_string()))?;
+
+    let key = sign::PublicKey::from_slice(&key)
+        .ok_or_else(|| JournalError::Integrity("stored public key is malformed".to_string()))?;
+
+    Ok(DevicePublicKey(key))
+}
+
+pub(crate) fn settings_privkey(settings: &dyn SettingsStore) -> Result<sign::SecretKey> {
+    let key = settings
+        .settings_get("PrivateKey")?
+        .ok_or_else(|| JournalError::MissingKey("PrivateKey".to_string()))?;
+
+    sign::SecretKey::from_slice(&key)
+        .ok_or_else(|| JournalError::Integrity("stored private key is malformed".to_string()))
+}
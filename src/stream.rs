@@ -0,0 +1,287 @@
+//! Reading and writing objects incrementally, without holding all of a large object in memory.
+//!
+//! Objects are read and written in the same form as [`chunk::get`](crate::chunk::get) and
+//! [`chunk::put`](crate::chunk::put) use, so either API can read what the other wrote, and the
+//! same data gets the same key through both. At most one chunk is held in memory at a time.
+
+use crate::chunk::{self, Manifest, MAX_CHUNK};
+use crate::{CASKey, CASObj, CasStore, Journal, JournalError, Result};
+use std::convert::TryFrom;
+use std::io::{self, Read, Seek, SeekFrom, Write};
+use std::ops::Deref;
+
+/// Reads the data of an object, loading chunks as they are reached.
+///
+/// `J` is anything that derefs to a journal, such as `&dyn Journal` or `Rc<dyn Journal>`.
+pub struct ObjectReader<J> {
+    journal: J,
+    links: Vec<CASKey>,
+
+    /// The keys of the objects holding each chunk, and the offset each chunk starts at.
+    chunks: Vec<CASKey>,
+    starts: Vec<u64>,
+    len: u64,
+
+    /// The index and data of the chunk read most recently.
+    current: Option<(usize, Vec<u8>)>,
+    pos: u64,
+}
+
+impl<J> ObjectReader<J>
+where
+    J: Deref,
+    J::Target: Journal,
+{
+    /// Opens the object stored under `key`, or returns `None` if there is no such object.
+    pub fn open(journal: J, key: CASKey) -> Result<Option<Self>> {
+        let mut obj = match journal.cas_get(key)? {
+            Some(obj) => obj,
+            None => return Ok(None),
+        };
+
+        let manifest = match chunk::manifest(&obj)? {
+            Some(manifest) => manifest,
+            None => return Ok(Some(Self::whole(journal, key, obj))),
+        };
+
+        let chunks = obj.links.split_off(manifest.links);
+
+        let mut starts = Vec::with_capacity(manifest.lengths.len());
+        let mut len: u64 = 0;
+
+        for length in manifest.lengths {
+            starts.push(len);
+            len += length;
+        }
+
+        Ok(Some(Self {
+            journal,
+            links: obj.links,
+            chunks,
+            starts,
+            len,
+            current: None,
+            pos: 0,
+        }))
+    }
+
+    /// A reader over an object that was stored unchunked, and so is already in memory.
+    fn whole(journal: J, key: CASKey, obj: CASObj) -> Self {
+        Self {
+            journal,
+            links: obj.links,
+            chunks: vec![key],
+            starts: vec![0],
+            len: obj.data.len() as u64,
+            current: Some((0, obj.data)),
+            pos: 0,
+        }
+    }
+
+    /// The object's links.
+    pub fn links(&self) -> &[CASKey] {
+        &self.links
+    }
+
+    /// The length of the object's data.
+    pub fn len(&self) -> u64 {
+        self.len
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len == 0
+    }
+
+    /// Makes sure the chunk with index `index` is loaded, returning its data.
+    fn load(&mut self, index: usize) -> Result<&[u8]> {
+        let loaded = match &self.current {
+            Some((current, _)) => *current == index,
+            None => false,
+        };
+
+        if !loaded {
+            let key = self.chunks[index];
+
+            let chunk = self
+                .journal
+                .cas_get(key)?
+                .ok_or_else(|| JournalError::Integrity(format!("missing chunk {:?}", key)))?;
+
+            self.current = Some((index, chunk.data));
+        }
+
+        Ok(self.current.as_ref().map_or(&[][..], |(_, data)| data))
+    }
+}
+
+impl<J> Read for ObjectReader<J>
+where
+    J: Deref,
+    J::Target: Journal,
+{
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        if self.pos >= self.len || buf.is_empty() {
+            return Ok(0);
+        }
+
+        let index = match self.starts.binary_search(&self.pos) {
+            Ok(index) => index,
+            Err(index) => index - 1,
+        };
+
+        // Chunk lengths are limited to MAX_CHUNK, and whole objects are already in memory, so
+        // an offset within one always fits in a usize.
+        #[allow(clippy::cast_possible_truncation)]
+        let offset = (self.pos - self.starts[index]) as usize;
+
+        let data = self.load(index)?;
+        let available = data.get(offset..).unwrap_or(&[]);
+
+        if available.is_empty() {
+            return Err(JournalError::Integrity(
+                "chunk is shorter than its manifest says".to_string(),
+            )
+            .into());
+        }
+
+        let n = available.len().min(buf.len());
+        buf[..n].copy_from_slice(&available[..n]);
+
+        self.pos += n as u64;
+
+        Ok(n)
+    }
+}
+
+impl<J> Seek for ObjectReader<J>
+where
+    J: Deref,
+    J::Target: Journal,
+{
+    fn seek(&mut self, pos: SeekFrom) -> io::Result<u64> {
+        let (base, offset) = match pos {
+            SeekFrom::Start(offset) => {
+                self.pos = offset;
+                return Ok(offset);
+            }
+            SeekFrom::End(offset) => (self.len, offset),
+            SeekFrom::Current(offset) => (self.pos, offset),
+        };
+
+        let pos = if offset >= 0 {
+            u64::try_from(offset)
+                .ok()
+                .and_then(|offset| base.checked_add(offset))
+        } else {
+            offset
+                .checked_neg()
+                .and_then(|offset| u64::try_from(offset).ok())
+                .and_then(|offset| base.checked_sub(offset))
+        };
+
+        match pos {
+            Some(pos) => {
+                self.pos = pos;
+                Ok(pos)
+            }
+            None => Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                "invalid seek to a negative or overflowing position",
+            )),
+        }
+    }
+}
+
+/// Writes an object's data incrementally, storing each chunk as soon as its boundary is known.
+///
+/// Chunks are stored before the object that links to them, so a [`gc`](crate::gc::gc) with no
+/// grace period could remove them while the object is still being written.
+pub struct ObjectWriter<J> {
+    journal: J,
+    links: Vec<CASKey>,
+    gear: [u64; 256],
+
+    /// Data not yet stored as a chunk.
+    buf: Vec<u8>,
+
+    chunks: Vec<CASKey>,
+    lengths: Vec<u64>,
+}
+
+impl<J> ObjectWriter<J>
+where
+    J: Deref,
+    J::Target: Journal,
+{
+    /// Starts writing an object that links to `links`.
+    pub fn new(journal: J, links: Vec<CASKey>) -> Self {
+        Self {
+            journal,
+            links,
+            gear: chunk::gear_table(),
+            buf: Vec::new(),
+            chunks: Vec::new(),
+            lengths: Vec::new(),
+        }
+    }
+
+    fn put_chunk(&mut self, len: usize) -> Result<()> {
+        let data: Vec<u8> = self.buf.drain(..len).collect();
+
+        self.chunks.push(self.journal.cas_put(CASObj {
+            links: vec![],
+            data,
+        })?);
+        self.lengths.push(len as u64);
+
+        Ok(())
+    }
+
+    /// Stores whatever is left and the object itself, returning its key.
+    pub fn finish(mut self) -> Result<CASKey> {
+        if self.chunks.is_empty() && !chunk::needs_chunking(&self.buf) {
+            return self.journal.cas_put(CASObj {
+                links: self.links,
+                data: self.buf,
+            });
+        }
+
+        while !self.buf.is_empty() {
+            let len = chunk::boundary(&self.buf, &self.gear);
+            self.put_chunk(len)?;
+        }
+
+        let data = chunk::manifest_data(&Manifest {
+            links: self.links.len(),
+            lengths: self.lengths,
+        })?;
+
+        let mut links = self.links;
+        links.extend(self.chunks);
+
+        self.journal.cas_put(CASObj { links, data })
+    }
+}
+
+impl<J> Write for ObjectWriter<J>
+where
+    J: Deref,
+    J::Target: Journal,
+{
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        self.buf.extend_from_slice(buf);
+
+        // A boundary only depends on the MAX_CHUNK bytes after the chunk's start, but a buffer of
+        // exactly that length might turn out to be the whole object, which is stored unchunked.
+        while self.buf.len() > MAX_CHUNK {
+            let len = chunk::boundary(&self.buf, &self.gear);
+            self.put_chunk(len)?;
+        }
+
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        Ok(())
+    }
+}
@@ -0,0 +1,550 @@
+//! A protocol for two journals to exchange entries and objects over any byte stream.
+//!
+//! Both peers swap their heads, then take turns: the initiator pulls everything it is missing
+//! from the responder while the responder serves requests, and then the roles swap. Each message
+//! is a CBOR encoded [`Message`] prefixed by its length as a big endian `u32`. Requested entries
+//! and objects are sent back as a [pack](crate::pack).
+//!
+//! A peer can also just answer requests with [`serve_fetches`], for a [`Remote`] to fetch
+//! entries and objects one at a time as they are needed instead of syncing everything up front.
+
+use crate::error::invalid;
+use crate::history::is_ancestor;
+use crate::overlay::Archive;
+use crate::pack::{Pack, PackWriter};
+use crate::{
+    clock, transaction, ApplicationId, CASKey, CASObj, DevicePublicKey, Journal, JournalError,
+    JournalKey, Signed,
+};
+use serde::de::{self, Deserializer, Visitor};
+use serde::{Deserialize, Serialize, Serializer};
+use std::cell::RefCell;
+use std::collections::HashSet;
+use std::convert::TryInto;
+use std::fmt;
+use std::io::{self, Cursor, Read, Write};
+
+/// Frames larger than this are rejected rather than allocated.
+const MAX_FRAME_LEN: u32 = 64 << 20;
+
+/// Once a reply pack reaches this size no more is added to it, and the peer asks again for the
+/// rest. Only a single entry or object too big for a frame on its own can't be sent.
+const REPLY_BUDGET: u64 = 16 << 20;
+
+/// How many keys are asked for in one request, which keeps requests well under a frame.
+const MAX_KEYS_PER_REQUEST: usize = 4096;
+
+#[derive(Serialize, Deserialize, Debug)]
+enum Message {
+    Heads(Vec<(ApplicationId, DevicePublicKey, JournalKey)>),
+    WantHeads,
+    WantEntries(Vec<JournalKey>),
+    WantObjects(Vec<CASKey>),
+    Pack(PackData),
+    Done,
+}
+
+/// A whole pack, encoded as a CBOR byte string rather than an array of numbers.
+struct PackData(Vec<u8>);
+
+impl fmt::Debug for PackData {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "PackData({} bytes)", self.0.len())
+    }
+}
+
+impl Serialize for PackData {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        serializer.serialize_bytes(&self.0)
+    }
+}
+
+struct PackDataVisitor;
+
+impl Visitor<'_> for PackDataVisitor {
+    type Value = PackData;
+
+    fn expecting(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str("a pack")
+    }
+
+    fn visit_bytes<E: de::Error>(self, v: &[u8]) -> Result<Self::Value, E> {
+        Ok(PackData(v.to_vec()))
+    }
+
+    fn visit_byte_buf<E: de::Error>(self, v: Vec<u8>) -> Result<Self::Value, E> {
+        Ok(PackData(v))
+    }
+}
+
+impl<'de> Deserialize<'de> for PackData {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        deserializer.deserialize_byte_buf(PackDataVisitor)
+    }
+}
+
+/// Which side of the conversation this peer is. Exactly one side must be the initiator.
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub enum Role {
+    Initiator,
+    Responder,
+}
+
+/// What a sync pulled into the local journal.
+#[derive(Clone, Copy, PartialEq, Eq, Default, Debug)]
+pub struct SyncStats {
+    pub entries_received: usize,
+    pub objects_received: usize,
+    pub heads_updated: usize,
+}
+
+/// Syncs `journal` with the peer on the other end of `stream`.
+///
+/// When this returns successfully both journals hold every entry and object reachable from either
+/// side's heads, and any head that the peer has moved forward is fast-forwarded locally.
+pub fn sync<S: Read + Write>(
+    journal: &dyn Journal,
+    stream: &mut S,
+    role: Role,
+) -> io::Result<SyncStats> {
+    let local_heads = journal
+        .heads()?
+        .into_iter()
+        .map(|((appid, device), key)| (appid, device, key))
+        .collect();
+
+    let remote_heads = match role {
+        Role::Initiator => {
+            send(stream, &Message::Heads(local_heads))?;
+            expect_heads(stream)?
+        }
+        Role::Responder => {
+            let remote_heads = expect_heads(stream)?;
+            send(stream, &Message::Heads(local_heads))?;
+            remote_heads
+        }
+    };
+
+    match role {
+        Role::Initiator => {
+            let stats = pull(journal, stream, &remote_heads)?;
+            serve(journal, stream)?;
+            Ok(stats)
+        }
+        Role::Responder => {
+            serve(journal, stream)?;
+            pull(journal, stream, &remote_heads)
+        }
+    }
+}
+
+fn expect_heads<S: Read>(
+    stream: &mut S,
+) -> io::Result<Vec<(ApplicationId, DevicePublicKey, JournalKey)>> {
+    match recv(stream)? {
+        Message::Heads(heads) => Ok(heads),
+        other => Err(unexpected(&other)),
+    }
+}
+
+fn expect_pack<S: Read>(stream: &mut S) -> io::Result<Pack<Cursor<Vec<u8>>>> {
+    match recv(stream)? {
+        Message::Pack(PackData(data)) => {
+            Pack::open(Cursor::new(data)).map_err(|e| invalid(e.to_string()))
+        }
+        other => Err(unexpected(&other)),
+    }
+}
+
+/// Fetches everything reachable from `remote_heads` that the local journal is missing, then
+/// stores it and updates local heads.
+///
+/// Nothing is stored until everything has been received, and then it is all stored in one
+/// transaction together with the heads. An interrupted sync leaves the journal as it was, so
+/// every stored entry keeps its whole history stored behind it, which is what lets the walk stop
+/// at entries that are already present.
+fn pull<S: Read + Write>(
+    journal: &dyn Journal,
+    stream: &mut S,
+    remote_heads: &[(ApplicationId, DevicePublicKey, JournalKey)],
+) -> io::Result<SyncStats> {
+    let mut stats = SyncStats::default();
+
+    let mut seen_entries = HashSet::new();
+    let mut want_entries = Vec::new();
+
+    for &(_, _, key) in remote_heads {
+        if seen_entries.insert(key) && journal.get_signed(key)?.is_none() {
+            want_entries.push(key);
+        }
+    }
+
+    let mut seen_objects = HashSet::new();
+    let mut want_objects = Vec::new();
+
+    // Newest first, in the order they were found.
+    let mut entries = Vec::new();
+    let mut objects = Vec::new();
+
+    while !want_entries.is_empty() {
+        let batch = fetch(stream, want_entries, Message::WantEntries, |pack, key| {
+            pack.entry(key)
+        })?;
+
+        let mut next = Vec::new();
+
+        for (key, signed) in batch {
+            if signed.key_with(key.algorithm()) != key {
+                return Err(invalid(format!("entry {:?} does not match its hash", key)));
+            }
+
+            let entry = signed
+                .verify()
+                .ok_or_else(|| invalid(format!("entry {:?} has a bad signature", key)))?;
+
+            for &parent in entry.parents() {
+                if seen_entries.insert(parent) && journal.get_signed(parent)?.is_none() {
+                    next.push(parent);
+                }
+            }
+
+            let new_state = entry.new_state();
+
+            if seen_objects.insert(new_state) && journal.cas_get(new_state)?.is_none() {
+                want_objects.push(new_state);
+            }
+
+            entries.push((key, signed, entry));
+        }
+
+        want_entries = next;
+    }
+
+    while !want_objects.is_empty() {
+        let batch = fetch(stream, want_objects, Message::WantObjects, |pack, key| {
+            pack.object(key)
+        })?;
+
+        let mut next = Vec::new();
+
+        for (key, obj) in batch {
+            if obj.key_with(key.algorithm()) != key {
+                return Err(invalid(format!("object {:?} does not match its hash", key)));
+            }
+
+            for &link in &obj.links {
+                if seen_objects.insert(link) && journal.cas_get(link)?.is_none() {
+                    next.push(link);
+                }
+            }
+
+            objects.push((key, obj));
+        }
+
+        want_objects = next;
+    }
+
+    send(stream, &Message::Done)?;
+
+    stats.entries_received = entries.len();
+    stats.objects_received = objects.len();
+
+    stats.heads_updated = transaction(journal, |journal| {
+        // Oldest first, so that even within the transaction nothing is stored before what it
+        // refers to.
+        for (key, obj) in objects.into_iter().rev() {
+            journal.cas_put_with(obj, key.algorithm())?;
+        }
+
+        for (key, signed, entry) in entries.iter().rev() {
+            journal.put_signed_with(signed, key.algorithm())?;
+
+            if let Some(clock) = entry.clock() {
+                clock::observe(journal, clock)?;
+            }
+        }
+
+        fast_forward_heads(journal, remote_heads)
+    })?;
+
+    Ok(stats)
+}
+
+/// Requests `keys` from the peer, as many at a time as fit in a request, until it has sent all of
+/// them. The peer may send fewer than asked for if they would not fit in one reply, in which case
+/// the rest are asked for again, but it must send at least one each time.
+fn fetch<S, K, T, F>(
+    stream: &mut S,
+    mut keys: Vec<K>,
+    want: fn(Vec<K>) -> Message,
+    get: F,
+) -> io::Result<Vec<(K, T)>>
+where
+    S: Read + Write,
+    K: Copy + fmt::Debug,
+    F: Fn(&Pack<Cursor<Vec<u8>>>, K) -> crate::Result<Option<T>>,
+{
+    let mut received = Vec::with_capacity(keys.len());
+
+    while !keys.is_empty() {
+        let rest = keys.split_off(keys.len().min(MAX_KEYS_PER_REQUEST));
+        let batch = std::mem::replace(&mut keys, rest);
+
+        send(stream, &want(batch.clone()))?;
+
+        let pack = expect_pack(stream)?;
+
+        let mut missing = Vec::new();
+
+        for &key in &batch {
+            match get(&pack, key).map_err(|e| invalid(e.to_string()))? {
+                Some(value) => received.push((key, value)),
+                None => missing.push(key),
+            }
+        }
+
+        if missing.len() == batch.len() {
+            return Err(invalid(format!("peer did not send {:?}", batch[0])));
+        }
+
+        missing.append(&mut keys);
+        keys = missing;
+    }
+
+    Ok(received)
+}
+
+/// Answers the peer's requests until it says it is done.
+fn serve<S: Read + Write>(journal: &dyn Journal, stream: &mut S) -> io::Result<()> {
+    loop {
+        match recv(stream)? {
+            Message::WantEntries(keys) => {
+                let mut writer = PackWriter::new(Vec::new())?;
+
+                for key in keys {
+                    if writer.size() >= REPLY_BUDGET {
+                        break;
+                    }
+
+                    if let Some(signed) = journal.get_signed(key)? {
+                        writer.add_entry(key, &signed)?;
+                    }
+                }
+
+                send(stream, &Message::Pack(PackData(writer.finish()?)))?;
+            }
+            Message::WantObjects(keys) => {
+                let mut writer = PackWriter::new(Vec::new())?;
+
+                for key in keys {
+                    if writer.size() >= REPLY_BUDGET {
+                        break;
+                    }
+
+                    if let Some(obj) = journal.cas_get(key)? {
+                        writer.add_object(key, &obj)?;
+                    }
+                }
+
+                send(stream, &Message::Pack(PackData(writer.finish()?)))?;
+            }
+            Message::WantHeads => {
+                let heads = journal
+                    .heads()?
+                    .into_iter()
+                    .map(|((appid, device), key)| (appid, device, key))
+                    .collect();
+
+                send(stream, &Message::Heads(heads))?;
+            }
+            Message::Done => return Ok(()),
+            other => return Err(unexpected(&other)),
+        }
+    }
+}
+
+/// Answers requests from a [`Remote`] on the other end of `stream` until it is dropped.
+pub fn serve_fetches<S: Read + Write>(journal: &dyn Journal, stream: &mut S) -> io::Result<()> {
+    serve(journal, stream)
+}
+
+/// A connection to a peer running [`serve_fetches`], for fetching entries and objects as they are
+/// needed.
+///
+/// Everything fetched is checked against its key, and entries against their signature, so the
+/// peer can't pass off anything it made up, though it can still withhold things. As an
+/// [`Archive`] it lets an
+/// [`OverlayJournal`](crate::OverlayJournal) start with little or no history and fetch the rest
+/// on demand.
+pub struct Remote<S: Write> {
+    stream: RefCell<S>,
+}
+
+impl<S: Read + Write> Remote<S> {
+    pub fn new(stream: S) -> Self {
+        Self {
+            stream: RefCell::new(stream),
+        }
+    }
+
+    /// The peer's heads, for example to adopt with [`fast_forward_heads`].
+    pub fn heads(&self) -> io::Result<Vec<(ApplicationId, DevicePublicKey, JournalKey)>> {
+        let mut stream = self.stream.borrow_mut();
+
+        send(&mut *stream, &Message::WantHeads)?;
+        expect_heads(&mut *stream)
+    }
+
+    /// Fetches an entry, or `None` if the peer does not have it either.
+    pub fn fetch_entry(&self, key: JournalKey) -> io::Result<Option<Signed>> {
+        let pack = self.request(&Message::WantEntries(vec![key]))?;
+
+        let signed = match pack.entry(key).map_err(|e| invalid(e.to_string()))? {
+            Some(signed) => signed,
+            None => return Ok(None),
+        };
+
+        if signed.key_with(key.algorithm()) != key {
+            return Err(invalid(format!("entry {:?} does not match its hash", key)));
+        }
+
+        if signed.verify().is_none() {
+            return Err(invalid(format!("entry {:?} has a bad signature", key)));
+        }
+
+        Ok(Some(signed))
+    }
+
+    /// Fetches an object, or `None` if the peer does not have it either.
+    pub fn fetch_object(&self, key: CASKey) -> io::Result<Option<CASObj>> {
+        let pack = self.request(&Message::WantObjects(vec![key]))?;
+
+        let obj = match pack.object(key).map_err(|e| invalid(e.to_string()))? {
+            Some(obj) => obj,
+            None => return Ok(None),
+        };
+
+        if obj.key_with(key.algorithm()) != key {
+            return Err(invalid(format!("object {:?} does not match its hash", key)));
+        }
+
+        Ok(Some(obj))
+    }
+
+    fn request(&self, message: &Message) -> io::Result<Pack<Cursor<Vec<u8>>>> {
+        let mut stream = self.stream.borrow_mut();
+
+        send(&mut *stream, message)?;
+        expect_pack(&mut *stream)
+    }
+}
+
+impl<S: Write> Drop for Remote<S> {
+    fn drop(&mut self) {
+        // The peer may already be gone, and there is nothing more to ask it either way.
+        let _ = send(self.stream.get_mut(), &Message::Done);
+    }
+}
+
+impl<S: Read + Write> Archive for Remote<S> {
+    fn archived_entry(&self, key: JournalKey) -> crate::Result<Option<Signed>> {
+        Ok(self.fetch_entry(key)?)
+    }
+
+    fn archived_object(&self, key: CASKey) -> crate::Result<Option<CASObj>> {
+        Ok(self.fetch_object(key)?)
+    }
+}
+
+/// Moves local heads forward to any of `heads` that descend from them, and adopts heads for
+/// applications and devices not seen before. Returns how many heads changed.
+///
+/// A head is only taken if its entry is already stored, was signed by the head's device and is
+/// for the head's application. Anything else a peer or bundle claims is ignored.
+pub fn fast_forward_heads(
+    journal: &dyn Journal,
+    heads: &[(ApplicationId, DevicePublicKey, JournalKey)],
+) -> crate::Result<usize> {
+    let local_heads = journal.heads()?;
+    let mut updated = 0;
+
+    for &(appid, device, key) in heads {
+        if !is_head_of(journal, appid, device, key)? {
+            continue;
+        }
+
+        let local = local_heads.get(&(appid, device)).copied();
+
+        let fast_forward = match local {
+            None => true,
+            Some(local) => local != key && is_ancestor(journal, local, key)?,
+        };
+
+        if !fast_forward {
+            continue;
+        }
+
+        // If another writer moved the head since it was read, leave it for the next sync.
+        match journal.update_head_if(device, appid, local, key) {
+            Ok(()) => updated += 1,
+            Err(JournalError::HeadConflict { .. }) => {}
+            Err(e) => return Err(e),
+        }
+    }
+
+    Ok(updated)
+}
+
+/// Whether `key` is a stored entry that `device` signed for `appid`.
+fn is_head_of(
+    journal: &dyn Journal,
+    appid: ApplicationId,
+    device: DevicePublicKey,
+    key: JournalKey,
+) -> crate::Result<bool> {
+    let signed = match journal.get_signed(key)? {
+        Some(signed) => signed,
+        None => return Ok(false),
+    };
+
+    if signed.device() != device {
+        return Ok(false);
+    }
+
+    Ok(matches!(signed.verify(), Some(entry) if entry.application_id() == appid))
+}
+
+fn send<S: Write>(stream: &mut S, message: &Message) -> io::Result<()> {
+    let data = serde_cbor::to_vec(message).map_err(|e| invalid(e.to_string()))?;
+
+    let len: u32 = data
+        .len()
+        .try_into()
+        .ok()
+        .filter(|&len| len <= MAX_FRAME_LEN)
+        .ok_or_else(|| invalid("message too large to send".to_string()))?;
+
+    stream.write_all(&len.to_be_bytes())?;
+    stream.write_all(&data)?;
+    stream.flush()
+}
+
+fn recv<S: Read>(stream: &mut S) -> io::Result<Message> {
+    let mut len = [0; 4];
+    stream.read_exact(&mut len)?;
+
+    let len = u32::from_be_bytes(len);
+
+    if len > MAX_FRAME_LEN {
+        return Err(invalid(format!("peer sent a {} byte message", len)));
+    }
+
+    let mut data = vec![0; len as usize];
+    stream.read_exact(&mut data)?;
+
+    serde_cbor::from_slice(&data).map_err(|e| invalid(e.to_string()))
+}
+
+fn unexpected(message: &Message) -> io::Error {
+    invalid(format!("unexpected message from peer: {:?}", message))
+}
@@ -0,0 +1,196 @@
+//! The same checks run against every backend, which should all behave alike.
+
+mod common;
+
+use common::{app, commit, TempDir};
+use distcomp::{
+    transaction, CASObj, CompositeJournal, FileJournal, HashAlgorithm, Journal, JournalError,
+    MemoryJournal, SqliteJournal,
+};
+use std::path::Path;
+use std::rc::Rc;
+
+fn objects(journal: &dyn Journal) {
+    let obj = CASObj {
+        links: Vec::new(),
+        data: b"object".to_vec(),
+    };
+
+    let key = journal.cas_put(obj.clone()).unwrap();
+
+    assert_eq!(key, obj.key());
+    assert_eq!(
+        journal.cas_get(key).unwrap().map(|obj| obj.data),
+        Some(obj.data)
+    );
+    assert_eq!(journal.cas_list().unwrap(), vec![key]);
+    assert!(journal.cas_written(key).unwrap().is_some());
+
+    journal.cas_delete(key).unwrap();
+
+    assert!(journal.cas_get(key).unwrap().is_none());
+    assert!(journal.cas_list().unwrap().is_empty());
+}
+
+fn algorithms(journal: &dyn Journal) {
+    let (_, first) = commit(journal, app(), b"first");
+    let signed = journal.get_signed(first).unwrap().unwrap();
+
+    for &algorithm in HashAlgorithm::ALL {
+        let obj = CASObj {
+            links: Vec::new(),
+            data: algorithm.name().as_bytes().to_vec(),
+        };
+
+        let key = journal.cas_put_with(obj.clone(), algorithm).unwrap();
+
+        assert_eq!(key, obj.key_with(algorithm));
+        assert_eq!(key.hash().algorithm(), algorithm);
+        assert_eq!(
+            journal.cas_get(key).unwrap().map(|obj| obj.data),
+            Some(obj.data)
+        );
+        assert!(journal.cas_list().unwrap().contains(&key));
+
+        let key = journal.put_signed_with(&signed, algorithm).unwrap();
+
+        assert_eq!(key, signed.key_with(algorithm));
+        assert_eq!(key.hash().algorithm(), algorithm);
+        assert_eq!(journal.get(key).unwrap().unwrap().parents(), &[]);
+        assert!(journal.entry_list().unwrap().contains(&key));
+    }
+
+    // New entries take the configured algorithm, and still link to their parents under theirs.
+    journal
+        .settings_set("HashAlgorithm", HashAlgorithm::Blake2b256.name().as_bytes())
+        .unwrap();
+
+    let (state, second) = commit(journal, app(), b"second");
+
+    assert_eq!(state.hash().algorithm(), HashAlgorithm::Blake2b256);
+    assert_eq!(second.hash().algorithm(), HashAlgorithm::Blake2b256);
+    assert_eq!(journal.get(second).unwrap().unwrap().parents(), &[first]);
+    assert_eq!(journal.children(first).unwrap(), vec![second]);
+}
+
+fn history(journal: &dyn Journal) {
+    let (_, first) = commit(journal, app(), b"first");
+    let (state, second) = commit(journal, app(), b"second");
+
+    assert_eq!(journal.this_head(app()).unwrap(), Some(second));
+    assert_eq!(journal.get_state(app()).unwrap(), Some(state));
+    assert_eq!(journal.get(second).unwrap().unwrap().parents(), &[first]);
+    assert_eq!(journal.children(first).unwrap(), vec![second]);
+
+    let mut entries = journal.entry_list().unwrap();
+    entries.sort();
+    let mut expected = vec![first, second];
+    expected.sort();
+    assert_eq!(entries, expected);
+}
+
+fn conditional_head_updates(journal: &dyn Journal) {
+    let (_, first) = commit(journal, app(), b"first");
+    let (_, second) = commit(journal, app(), b"second");
+    let device = journal.pubkey().unwrap();
+
+    match journal.update_head_if(device, app(), Some(first), first) {
+        Err(JournalError::HeadConflict { found, .. }) => assert_eq!(found, Some(second)),
+        other => panic!("expected a head conflict, got {:?}", other),
+    }
+
+    journal
+        .update_head_if(device, app(), Some(second), first)
+        .unwrap();
+
+    assert_eq!(journal.this_head(app()).unwrap(), Some(first));
+}
+
+fn rollbacks(journal: &dyn Journal) {
+    let (_, before) = commit(journal, app(), b"before");
+
+    let result: Result<(), _> = transaction(journal, |journal| {
+        commit(journal, app(), b"rolled back");
+        journal.settings_set("Setting", b"rolled back")?;
+
+        Err(JournalError::Integrity("roll back".to_string()))
+    });
+
+    assert!(result.is_err());
+    assert_eq!(journal.this_head(app()).unwrap(), Some(before));
+    assert_eq!(journal.settings_get("Setting").unwrap(), None);
+}
+
+fn settings(journal: &dyn Journal) {
+    assert_eq!(journal.settings_get("Setting").unwrap(), None);
+
+    journal.settings_set("Setting", b"value").unwrap();
+
+    assert_eq!(
+        journal.settings_get("Setting").unwrap(),
+        Some(b"value".to_vec())
+    );
+}
+
+macro_rules! backend_tests {
+    ($($name:ident => $check:ident,)*) => {
+        mod memory {
+            use super::*;
+
+            $(
+                #[test]
+                fn $name() {
+                    $check(&MemoryJournal::new());
+                }
+            )*
+        }
+
+        mod sqlite {
+            use super::*;
+
+            $(
+                #[test]
+                fn $name() {
+                    let dir = TempDir::new(concat!("sqlite-", stringify!($name)));
+                    $check(&SqliteJournal::new(&dir.path("sqlite.db")).unwrap());
+                }
+            )*
+        }
+
+        mod files {
+            use super::*;
+
+            $(
+                #[test]
+                fn $name() {
+                    let dir = TempDir::new(concat!("files-", stringify!($name)));
+                    $check(&FileJournal::new(Path::new(&dir.path("journal"))).unwrap());
+                }
+            )*
+        }
+
+        mod composite {
+            use super::*;
+
+            $(
+                #[test]
+                fn $name() {
+                    let dir = TempDir::new(concat!("composite-", stringify!($name)));
+                    let sqlite = SqliteJournal::new(&dir.path("sqlite.db")).unwrap();
+                    let files = FileJournal::new(Path::new(&dir.path("objects"))).unwrap();
+
+                    $check(&CompositeJournal::new(Rc::new(sqlite)).with_cas(Rc::new(files)));
+                }
+            )*
+        }
+    };
+}
+
+backend_tests! {
+    objects_round_trip => objects,
+    every_algorithm_round_trips => algorithms,
+    history_is_recorded => history,
+    heads_only_move_from_the_expected_entry => conditional_head_updates,
+    rolled_back_writes_are_discarded => rollbacks,
+    settings_round_trip => settings,
+}
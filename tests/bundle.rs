@@ -0,0 +1,62 @@
+mod common;
+
+use common::{app, commit};
+use distcomp::bundle::{self, ImportStats};
+use distcomp::{CasStore, EntryStore, HeadStore, KeyStore, MemoryJournal};
+
+#[test]
+fn bundles_round_trip() {
+    let source = MemoryJournal::new();
+    commit(&source, app(), b"first");
+    let (state, head) = commit(&source, app(), b"second");
+
+    let mut data = Vec::new();
+    bundle::create(&source, &[], &mut data).unwrap();
+
+    let journal = MemoryJournal::new();
+
+    assert_eq!(
+        bundle::import(&journal, &mut &data[..]).unwrap(),
+        ImportStats {
+            entries: 2,
+            objects: 2,
+            heads_updated: 1,
+        }
+    );
+
+    let heads = journal.heads().unwrap();
+    assert_eq!(heads[&(app(), source.pubkey().unwrap())], head);
+    assert_eq!(journal.get(head).unwrap().unwrap().new_state(), state);
+    assert_eq!(journal.cas_get(state).unwrap().unwrap().data, b"second");
+
+    // Importing again adds nothing.
+    assert_eq!(
+        bundle::import(&journal, &mut &data[..]).unwrap(),
+        ImportStats::default()
+    );
+}
+
+#[test]
+fn tampered_bundles_are_rejected() {
+    // Bytes below 24 are each encoded as themselves in a CBOR array, so this is easy to find.
+    let contents: Vec<u8> = (1..20).collect();
+
+    let source = MemoryJournal::new();
+    commit(&source, app(), &contents);
+
+    let mut data = Vec::new();
+    bundle::create(&source, &[], &mut data).unwrap();
+
+    let at = data
+        .windows(contents.len())
+        .position(|window| window == &contents[..])
+        .expect("the object is stored uncompressed");
+    data[at] ^= 1;
+
+    let journal = MemoryJournal::new();
+
+    assert!(bundle::import(&journal, &mut &data[..]).is_err());
+    assert!(journal.heads().unwrap().is_empty());
+    assert!(journal.cas_list().unwrap().is_empty());
+    assert!(journal.entry_list().unwrap().is_empty());
+}
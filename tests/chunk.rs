@@ -0,0 +1,92 @@
+mod common;
+
+use common::noise;
+use distcomp::chunk::{self, MAX_CHUNK, MIN_CHUNK};
+use distcomp::{CASKey, CASObj, CasStore, Journal, MemoryJournal};
+use std::collections::HashSet;
+
+/// The chunk links of the manifest stored under `key`.
+fn chunks(journal: &MemoryJournal, key: CASKey) -> Vec<CASKey> {
+    let mut obj = journal.cas_get(key).unwrap().unwrap();
+    let manifest = chunk::manifest(&obj).unwrap().expect("a manifest");
+
+    obj.links.split_off(manifest.links)
+}
+
+#[test]
+fn large_objects_round_trip() {
+    let journal = MemoryJournal::new();
+
+    let leaf = journal
+        .cas_put(CASObj {
+            links: Vec::new(),
+            data: b"leaf".to_vec(),
+        })
+        .unwrap();
+
+    let data = noise(4 * MAX_CHUNK, 1);
+
+    let key = chunk::put(
+        &journal,
+        CASObj {
+            links: vec![leaf],
+            data: data.clone(),
+        },
+    )
+    .unwrap();
+
+    assert!(chunks(&journal, key).len() > 1);
+
+    let obj = chunk::get(&journal, key).unwrap().unwrap();
+    assert_eq!(obj.links, vec![leaf]);
+    assert!(obj.data == data);
+
+    assert_eq!(chunk::links(&journal, key).unwrap().unwrap(), vec![leaf]);
+
+    let split = chunk::split(&data);
+    let (last, rest) = split.split_last().unwrap();
+    assert!(rest
+        .iter()
+        .all(|chunk| chunk.len() >= MIN_CHUNK && chunk.len() <= MAX_CHUNK));
+    assert!(last.len() <= MAX_CHUNK);
+    assert!(split.concat() == data);
+}
+
+#[test]
+fn edits_leave_most_chunks_unchanged() {
+    let journal = MemoryJournal::new();
+
+    let data = noise(8 * MAX_CHUNK, 2);
+
+    let mut edited = data.clone();
+    edited.splice(data.len() / 2..data.len() / 2, b"an edit".iter().cloned());
+
+    let put = |data: Vec<u8>| {
+        chunk::put(
+            &journal,
+            CASObj {
+                links: Vec::new(),
+                data,
+            },
+        )
+        .unwrap()
+    };
+
+    let original = chunks(&journal, put(data));
+    let edited = chunks(&journal, put(edited));
+
+    let original: HashSet<_> = original.into_iter().collect();
+    let new = edited
+        .iter()
+        .filter(|chunk| !original.contains(chunk))
+        .count();
+
+    // Only the chunks around the edit change.
+    assert!(edited.len() > 8);
+    assert!(
+        (1..=2).contains(&new),
+        "{} of {} chunks changed",
+        new,
+        edited.len()
+    );
+}
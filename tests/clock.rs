@@ -0,0 +1,63 @@
+mod common;
+
+use common::{app, commit};
+use distcomp::{bundle, EntryStore, Hlc, MemoryJournal, SettingsStore};
+use std::time::{SystemTime, UNIX_EPOCH};
+
+#[test]
+fn counters_never_wrap() {
+    let max = Hlc {
+        wall: 5,
+        counter: u32::MAX,
+    };
+
+    assert_eq!(max.tick(5), None);
+    assert_eq!(
+        max.tick(6),
+        Some(Hlc {
+            wall: 6,
+            counter: 0
+        })
+    );
+    assert_eq!(Hlc::default().observe(max, 5), None);
+    assert_eq!(
+        Hlc::default().observe(max, 6),
+        Some(Hlc {
+            wall: 6,
+            counter: 0
+        })
+    );
+}
+
+#[test]
+fn far_future_clocks_are_stored_but_not_observed() {
+    let now = SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .unwrap()
+        .as_millis() as u64;
+
+    let source = MemoryJournal::new();
+    let future = Hlc {
+        wall: now + 365 * 24 * 60 * 60 * 1000,
+        counter: 0,
+    };
+    source
+        .settings_set("Clock", &serde_cbor::to_vec(&future).unwrap())
+        .unwrap();
+
+    let (_, key) = commit(&source, app(), b"from the future");
+
+    let mut data = Vec::new();
+    bundle::create(&source, &[], &mut data).unwrap();
+
+    let journal = MemoryJournal::new();
+    bundle::import(&journal, &mut &data[..]).unwrap();
+
+    assert!(journal.get_signed(key).unwrap().is_some());
+
+    commit(&journal, app(), b"after it");
+
+    let clock: Hlc =
+        serde_cbor::from_slice(&journal.settings_get("Clock").unwrap().unwrap()).unwrap();
+    assert!(clock.wall < future.wall);
+}
@@ -0,0 +1,146 @@
+//! Helpers shared by the integration tests.
+
+#![allow(dead_code)]
+
+use distcomp::{ApplicationId, CASKey, CASObj, Journal, JournalKey};
+use std::fs;
+use std::io::{self, Read, Write};
+use std::path::PathBuf;
+use std::sync::mpsc::{channel, Receiver, Sender};
+use uuid::Uuid;
+
+pub fn app() -> ApplicationId {
+    ApplicationId(Uuid::from_bytes([1; 16]))
+}
+
+/// Stores an object holding `data` and commits it as this device's new state for `appid`.
+pub fn commit(journal: &dyn Journal, appid: ApplicationId, data: &[u8]) -> (CASKey, JournalKey) {
+    let state = journal
+        .cas_put(CASObj {
+            links: Vec::new(),
+            data: data.to_vec(),
+        })
+        .unwrap();
+
+    let key = journal.commit_self(appid, state).unwrap();
+
+    (state, key)
+}
+
+/// `len` bytes that don't repeat, so that chunking finds boundaries in them, different for each
+/// `seed`.
+pub fn noise(len: usize, seed: u64) -> Vec<u8> {
+    let mut state = seed;
+
+    (0..len)
+        .map(|_| {
+            state = state
+                .wrapping_mul(6_364_136_223_846_793_005)
+                .wrapping_add(1_442_695_040_888_963_407);
+            (state >> 56) as u8
+        })
+        .collect()
+}
+
+/// A directory for one test's files, removed with everything in it when dropped.
+pub struct TempDir(PathBuf);
+
+impl TempDir {
+    pub fn new(name: &str) -> Self {
+        let path = std::env::temp_dir().join(format!("distcomp-{}-{}", std::process::id(), name));
+
+        let _ = fs::remove_dir_all(&path);
+        fs::create_dir_all(&path).unwrap();
+
+        TempDir(path)
+    }
+
+    /// The path of `file` in this directory.
+    pub fn path(&self, file: &str) -> String {
+        self.0.join(file).to_str().unwrap().to_string()
+    }
+}
+
+impl Drop for TempDir {
+    fn drop(&mut self) {
+        let _ = fs::remove_dir_all(&self.0);
+    }
+}
+
+/// One end of an in-memory byte pipe, for connecting two journals in the same process.
+pub struct Pipe {
+    tx: Sender<Vec<u8>>,
+    rx: Receiver<Vec<u8>>,
+    buf: Vec<u8>,
+    pos: usize,
+
+    /// How many more bytes this end may write before every write fails, if limited.
+    budget: Option<usize>,
+}
+
+/// Two connected ends of a pipe.
+pub fn pipe() -> (Pipe, Pipe) {
+    let (a_tx, b_rx) = channel();
+    let (b_tx, a_rx) = channel();
+
+    let end = |tx, rx| Pipe {
+        tx,
+        rx,
+        buf: Vec::new(),
+        pos: 0,
+        budget: None,
+    };
+
+    (end(a_tx, a_rx), end(b_tx, b_rx))
+}
+
+/// A pipe whose first end breaks after writing `bytes`, as if the connection dropped.
+pub fn breaking_pipe(bytes: usize) -> (Pipe, Pipe) {
+    let (mut a, b) = pipe();
+    a.budget = Some(bytes);
+    (a, b)
+}
+
+impl Read for Pipe {
+    fn read(&mut self, out: &mut [u8]) -> io::Result<usize> {
+        if self.pos == self.buf.len() {
+            match self.rx.recv() {
+                Ok(buf) => {
+                    self.buf = buf;
+                    self.pos = 0;
+                }
+                // The other end is gone.
+                Err(_) => return Ok(0),
+            }
+        }
+
+        let n = out.len().min(self.buf.len() - self.pos);
+        out[..n].copy_from_slice(&self.buf[self.pos..self.pos + n]);
+        self.pos += n;
+
+        Ok(n)
+    }
+}
+
+impl Write for Pipe {
+    fn write(&mut self, data: &[u8]) -> io::Result<usize> {
+        if let Some(budget) = &mut self.budget {
+            if *budget < data.len() {
+                *budget = 0;
+                return Err(io::ErrorKind::BrokenPipe.into());
+            }
+
+            *budget -= data.len();
+        }
+
+        self.tx
+            .send(data.to_vec())
+            .map_err(|_| io::Error::from(io::ErrorKind::BrokenPipe))?;
+
+        Ok(data.len())
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        Ok(())
+    }
+}
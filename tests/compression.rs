@@ -0,0 +1,117 @@
+mod common;
+
+use common::TempDir;
+use distcomp::{CASKey, CASObj, CasStore, Journal, SqliteJournal};
+use rusqlite::{params, Connection};
+
+fn compressible() -> CASObj {
+    CASObj {
+        links: Vec::new(),
+        data: b"compressible ".repeat(1000),
+    }
+}
+
+/// The codec tag and length of the stored body of `key`.
+fn stored(path: &str, key: CASKey) -> (i64, usize) {
+    Connection::open(path)
+        .unwrap()
+        .query_row(
+            "SELECT codec, length(content) FROM cas WHERE id = ?1",
+            params!(key),
+            |row| Ok((row.get(0)?, row.get::<_, i64>(1)? as usize)),
+        )
+        .unwrap()
+}
+
+/// Stores `objs` as rows written before compression, returning their keys.
+fn insert_uncompressed(path: &str, objs: &[CASObj]) -> Vec<CASKey> {
+    let db = Connection::open(path).unwrap();
+
+    objs.iter()
+        .map(|obj| {
+            let key = obj.key();
+            db.execute(
+                "INSERT INTO cas (id, content, codec) VALUES (?1, ?2, 0)",
+                params!(key, serde_cbor::to_vec(obj).unwrap()),
+            )
+            .unwrap();
+            key
+        })
+        .collect()
+}
+
+#[test]
+fn compressed_bodies_round_trip() {
+    let dir = TempDir::new("compressed_bodies_round_trip");
+    let path = dir.path("sqlite.db");
+
+    let obj = compressible();
+    let encoded = serde_cbor::to_vec(&obj).unwrap().len();
+
+    let key = SqliteJournal::new(&path).unwrap().cas_put(obj).unwrap();
+
+    let (codec, len) = stored(&path, key);
+    assert_eq!(codec, 1);
+    assert!(len < encoded / 10);
+
+    let journal = SqliteJournal::new(&path).unwrap();
+    assert_eq!(
+        journal.cas_get(key).unwrap().unwrap().data,
+        compressible().data
+    );
+}
+
+#[test]
+fn uncompressed_rows_still_read() {
+    let dir = TempDir::new("uncompressed_rows_still_read");
+    let path = dir.path("sqlite.db");
+
+    drop(SqliteJournal::new(&path).unwrap());
+
+    let keys = insert_uncompressed(&path, &[compressible()]);
+
+    let journal = SqliteJournal::new(&path).unwrap();
+    assert_eq!(
+        journal.cas_get(keys[0]).unwrap().unwrap().data,
+        compressible().data
+    );
+}
+
+#[test]
+fn recompressing_keeps_every_key_and_its_data() {
+    let dir = TempDir::new("recompressing_keeps_every_key_and_its_data");
+    let path = dir.path("sqlite.db");
+
+    drop(SqliteJournal::new(&path).unwrap());
+
+    // Something that compresses and something too short to.
+    let objs = vec![
+        compressible(),
+        CASObj {
+            links: Vec::new(),
+            data: b"short".to_vec(),
+        },
+    ];
+    let keys = insert_uncompressed(&path, &objs);
+
+    let journal = SqliteJournal::new(&path).unwrap();
+    let before = journal.cas_list().unwrap();
+
+    let stats = journal.recompress().unwrap();
+    assert_eq!(stats.examined, 2);
+    assert_eq!(stats.compressed, 1);
+    assert!(stats.bytes_after < stats.bytes_before);
+
+    assert_eq!(stored(&path, keys[0]).0, 1);
+    assert_eq!(stored(&path, keys[1]).0, 0);
+
+    assert_eq!(journal.cas_list().unwrap(), before);
+    for (key, obj) in keys.iter().zip(&objs) {
+        assert_eq!(journal.cas_get(*key).unwrap().unwrap().data, obj.data);
+    }
+
+    // Only the object that couldn't shrink is left to look at.
+    let again = journal.recompress().unwrap();
+    assert_eq!(again.examined, 1);
+    assert_eq!(again.compressed, 0);
+}
@@ -0,0 +1,60 @@
+mod common;
+
+use common::app;
+use distcomp::{ApplicationId, CASObj, EntryStore, Journal, MemoryJournal, Signed};
+use serde::Serialize;
+use sodiumoxide::crypto::sign;
+
+/// An entry as the first version of the crate wrote it, before entries were versioned.
+#[derive(Serialize)]
+struct OldEntry {
+    application_id: ApplicationId,
+    new_state: [u8; 32],
+    parents: Vec<[u8; 32]>,
+}
+
+#[derive(Serialize)]
+struct OldSigned {
+    from: sign::PublicKey,
+    inner_signed: Vec<u8>,
+}
+
+#[test]
+fn entries_from_before_versioning_still_decode() {
+    let state = CASObj {
+        links: Vec::new(),
+        data: b"old".to_vec(),
+    }
+    .key();
+
+    let mut digest = [0; 32];
+    digest.copy_from_slice(state.hash().digest());
+
+    let (pubkey, privkey) = sign::gen_keypair();
+
+    let old = OldEntry {
+        application_id: app(),
+        new_state: digest,
+        parents: Vec::new(),
+    };
+
+    let signed = OldSigned {
+        from: pubkey,
+        inner_signed: sign::sign(&serde_cbor::to_vec(&old).unwrap(), &privkey),
+    };
+
+    let signed: Signed = serde_cbor::from_slice(&serde_cbor::to_vec(&signed).unwrap()).unwrap();
+
+    let journal = MemoryJournal::new();
+    let key = journal.put_signed(&signed).unwrap();
+    let entry = journal.get(key).unwrap().unwrap();
+
+    assert_eq!(entry.version(), 0);
+    assert_eq!(entry.application_id(), app());
+    assert_eq!(entry.new_state(), state);
+    assert!(entry.parents().is_empty());
+    assert_eq!(entry.timestamp(), None);
+    assert_eq!(entry.message(), None);
+    assert!(entry.metadata().is_empty());
+    assert_eq!(entry.clock(), None);
+}
@@ -0,0 +1,22 @@
+mod common;
+
+use common::{app, commit, TempDir};
+use distcomp::{FileJournal, Journal};
+use std::fs;
+use std::path::Path;
+
+#[test]
+fn a_leftover_lock_file_does_not_block_writers() {
+    let dir = TempDir::new("a_leftover_lock_file_does_not_block_writers");
+    let path = dir.path("journal");
+
+    FileJournal::new(Path::new(&path)).unwrap();
+
+    // As left behind by a writer that crashed.
+    fs::write(Path::new(&path).join("lock"), b"").unwrap();
+
+    let journal = FileJournal::new(Path::new(&path)).unwrap();
+    let (_, key) = commit(&journal, app(), b"state");
+
+    assert_eq!(journal.this_head(app()).unwrap(), Some(key));
+}
@@ -0,0 +1,93 @@
+mod common;
+
+use common::{app, commit, TempDir};
+use distcomp::fsck::{self, Problem};
+use distcomp::{CASObj, HashAlgorithm, Journal, SettingsStore, SqliteJournal};
+use rusqlite::{params, Connection};
+
+#[test]
+fn undecodable_ids_are_reported_and_the_rest_still_checked() {
+    let dir = TempDir::new("undecodable_ids_are_reported_and_the_rest_still_checked");
+    let path = dir.path("sqlite.db");
+
+    commit(&SqliteJournal::new(&path).unwrap(), app(), b"state");
+
+    let obj = |data: &[u8]| CASObj {
+        links: Vec::new(),
+        data: data.to_vec(),
+    };
+
+    // A row whose content doesn't match its key, to show rows are still checked.
+    let claimed = obj(b"claimed").key();
+    let actual = obj(b"actual").key();
+
+    let db = Connection::open(&path).unwrap();
+    db.execute(
+        "INSERT INTO cas (id, content) VALUES (?1, ?2)",
+        params!(&b"not a key"[..], &b""[..]),
+    )
+    .unwrap();
+    db.execute(
+        "INSERT INTO entries (id, inner) VALUES (?1, ?2)",
+        params!(&b"not a key either"[..], &b""[..]),
+    )
+    .unwrap();
+    db.execute(
+        "INSERT INTO cas (id, content) VALUES (?1, ?2)",
+        params!(claimed, serde_cbor::to_vec(&obj(b"actual")).unwrap()),
+    )
+    .unwrap();
+    drop(db);
+
+    let report = fsck::verify(&SqliteJournal::new(&path).unwrap()).unwrap();
+
+    let mut problems = report.problems.clone();
+    problems.sort_by_key(|problem| problem.to_string());
+
+    assert_eq!(
+        problems,
+        vec![
+            Problem::ObjectHashMismatch {
+                key: claimed,
+                actual
+            },
+            Problem::UndecodableEntryId {
+                id: b"not a key either".to_vec()
+            },
+            Problem::UndecodableObjectId {
+                id: b"not a key".to_vec()
+            },
+        ]
+    );
+    assert_eq!(report.objects_checked, 3);
+    assert_eq!(report.entries_checked, 2);
+    assert_eq!(report.heads_checked, 1);
+}
+
+#[test]
+fn stores_holding_both_algorithms_verify() {
+    let dir = TempDir::new("stores_holding_both_algorithms_verify");
+    let journal = SqliteJournal::new(&dir.path("sqlite.db")).unwrap();
+
+    let (state, _) = commit(&journal, app(), b"sha256");
+
+    journal
+        .settings_set("HashAlgorithm", HashAlgorithm::Blake2b256.name().as_bytes())
+        .unwrap();
+
+    let linked = journal
+        .cas_put(CASObj {
+            links: vec![state],
+            data: b"blake2b".to_vec(),
+        })
+        .unwrap();
+    let second = journal.commit_self(app(), linked).unwrap();
+
+    assert_eq!(second.hash().algorithm(), HashAlgorithm::Blake2b256);
+
+    let report = fsck::verify(&journal).unwrap();
+
+    assert!(report.is_ok(), "{:?}", report.problems);
+    assert_eq!(report.objects_checked, 2);
+    assert_eq!(report.entries_checked, 2);
+}
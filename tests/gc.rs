@@ -0,0 +1,101 @@
+mod common;
+
+use common::{app, noise};
+use distcomp::gc::{gc, GcOptions};
+use distcomp::{chunk, CASKey, CASObj, CasStore, Journal, MemoryJournal};
+use std::time::Duration;
+
+/// A journal whose head reaches an object with a link and a chunked object, plus one object
+/// nothing reaches. Returns the journal, the reachable objects and the unreachable one.
+fn journal() -> (MemoryJournal, Vec<CASKey>, CASKey) {
+    let journal = MemoryJournal::new();
+
+    let leaf = journal
+        .cas_put(CASObj {
+            links: Vec::new(),
+            data: b"leaf".to_vec(),
+        })
+        .unwrap();
+
+    let chunked = chunk::put(
+        &journal,
+        CASObj {
+            links: Vec::new(),
+            data: noise(4 * chunk::MAX_CHUNK, 1),
+        },
+    )
+    .unwrap();
+
+    let state = journal
+        .cas_put(CASObj {
+            links: vec![leaf, chunked],
+            data: b"state".to_vec(),
+        })
+        .unwrap();
+
+    journal.commit_self(app(), state).unwrap();
+
+    let garbage = journal
+        .cas_put(CASObj {
+            links: Vec::new(),
+            data: b"garbage".to_vec(),
+        })
+        .unwrap();
+
+    let mut reachable = journal.cas_list().unwrap();
+    reachable.retain(|&key| key != garbage);
+    reachable.sort();
+
+    (journal, reachable, garbage)
+}
+
+fn no_grace_period() -> GcOptions {
+    GcOptions {
+        grace_period: Duration::from_secs(0),
+        ..GcOptions::default()
+    }
+}
+
+#[test]
+fn unreachable_objects_are_swept() {
+    let (journal, reachable, garbage) = journal();
+
+    // The leaf, the state, the manifest and its chunks.
+    assert!(reachable.len() > 4);
+
+    let report = gc(&journal, &no_grace_period()).unwrap();
+
+    assert_eq!(report.swept, vec![garbage]);
+    assert_eq!(report.reachable, reachable.len());
+
+    let mut left = journal.cas_list().unwrap();
+    left.sort();
+    assert_eq!(left, reachable);
+}
+
+#[test]
+fn recent_objects_are_kept() {
+    let (journal, reachable, garbage) = journal();
+
+    let report = gc(&journal, &GcOptions::default()).unwrap();
+
+    assert!(report.swept.is_empty());
+    assert_eq!(report.recent, vec![garbage]);
+    assert_eq!(journal.cas_list().unwrap().len(), reachable.len() + 1);
+}
+
+#[test]
+fn dry_runs_delete_nothing() {
+    let (journal, reachable, garbage) = journal();
+
+    let options = GcOptions {
+        dry_run: true,
+        ..no_grace_period()
+    };
+
+    let report = gc(&journal, &options).unwrap();
+
+    assert_eq!(report.swept, vec![garbage]);
+    assert!(journal.cas_get(garbage).unwrap().is_some());
+    assert_eq!(journal.cas_list().unwrap().len(), reachable.len() + 1);
+}
@@ -0,0 +1,43 @@
+use distcomp::{HashAlgorithm, Multihash};
+
+#[test]
+fn keys_display_and_parse_back() {
+    for &algorithm in HashAlgorithm::ALL {
+        let hash = algorithm.digest(b"data");
+        let shown = hash.to_string();
+
+        assert!(shown.starts_with(&format!("{}:", algorithm.name())));
+        assert_eq!(shown.parse::<Multihash>().unwrap(), hash);
+
+        assert_eq!(Multihash::from_bytes(&hash.to_bytes()), Some(hash));
+        assert_eq!(Multihash::from_multihash(&hash.to_multihash()), Some(hash));
+    }
+
+    let shown = HashAlgorithm::Sha256.digest(b"data").to_string();
+
+    for bad in &[
+        "",
+        "sha2-256",
+        "md5:00",
+        &shown[..shown.len() - 1],
+        &format!("sha2-256:{}", "zz".repeat(32)),
+    ] {
+        assert!(bad.parse::<Multihash>().is_err(), "{:?} parsed", bad);
+    }
+}
+
+#[test]
+fn bare_sha256_digests_still_decode() {
+    let hash = HashAlgorithm::Sha256.digest(b"data");
+
+    // SHA-256 keys have always been stored as the bare digest.
+    assert_eq!(hash.to_bytes(), hash.digest().to_vec());
+    assert_eq!(Multihash::from_bytes(hash.digest()), Some(hash));
+
+    let blake = HashAlgorithm::Blake2b256.digest(b"data");
+    assert_ne!(blake.to_bytes().len(), blake.digest().len());
+    assert_eq!(
+        Multihash::from_bytes(blake.digest()).map(|hash| hash.algorithm()),
+        Some(HashAlgorithm::Sha256)
+    );
+}
@@ -0,0 +1,142 @@
+mod common;
+
+use common::{app, commit};
+use distcomp::history::{divergent_heads, is_ancestor, relate, Log, Relation};
+use distcomp::{
+    bundle, CASObj, CommitInfo, EntryStore, Hlc, Journal, MemoryJournal, SettingsStore,
+};
+use std::time::{SystemTime, UNIX_EPOCH};
+
+#[test]
+fn ancestry_does_not_depend_on_clock_order() {
+    let now = SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .unwrap()
+        .as_millis() as u64;
+
+    let journal = MemoryJournal::new();
+    let (_, first) = commit(&journal, app(), b"first");
+
+    // A clock far enough ahead that the entry after this one doesn't take it on.
+    let future = Hlc {
+        wall: now + 365 * 24 * 60 * 60 * 1000,
+        counter: 0,
+    };
+    journal
+        .settings_set("Clock", &serde_cbor::to_vec(&future).unwrap())
+        .unwrap();
+    let (_, ahead) = commit(&journal, app(), b"ahead");
+
+    journal
+        .settings_set("Clock", &serde_cbor::to_vec(&Hlc::default()).unwrap())
+        .unwrap();
+    let (_, behind) = commit(&journal, app(), b"behind");
+
+    let clock = |key| journal.get(key).unwrap().unwrap().clock().unwrap();
+    assert!(clock(behind) < clock(ahead));
+
+    assert!(is_ancestor(&journal, ahead, behind).unwrap());
+    assert!(is_ancestor(&journal, first, behind).unwrap());
+    assert!(!is_ancestor(&journal, behind, ahead).unwrap());
+}
+
+/// Copies everything in `from` into `to`.
+fn transfer(from: &MemoryJournal, to: &MemoryJournal) {
+    let mut data = Vec::new();
+    bundle::create(from, &[], &mut data).unwrap();
+    bundle::import(to, &mut &data[..]).unwrap();
+}
+
+#[test]
+fn other_heads_are_fast_forwarded_or_merged() {
+    let a = MemoryJournal::new();
+    let b = MemoryJournal::new();
+
+    let (_, a_first) = commit(&a, app(), b"a");
+    transfer(&a, &b);
+
+    // B builds on A's entry, so A can take B's head on as it is.
+    let state = b
+        .cas_put(CASObj {
+            links: Vec::new(),
+            data: b"b".to_vec(),
+        })
+        .unwrap();
+    let b_head = b
+        .commit_with(app(), state, &[a_first], CommitInfo::default())
+        .unwrap();
+    transfer(&b, &a);
+
+    assert_eq!(divergent_heads(&a, app()).unwrap(), vec![b_head]);
+    assert_eq!(
+        relate(&a, Some(a_first), b_head).unwrap(),
+        Relation::FastForward
+    );
+    assert_eq!(relate(&a, None, b_head).unwrap(), Relation::FastForward);
+
+    // B already has everything of A's.
+    assert!(divergent_heads(&b, app()).unwrap().is_empty());
+    assert_eq!(
+        relate(&b, Some(b_head), a_first).unwrap(),
+        Relation::Included
+    );
+
+    // Once A commits on its own, the two have diverged.
+    let (_, a_second) = commit(&a, app(), b"a again");
+
+    assert_eq!(divergent_heads(&a, app()).unwrap(), vec![b_head]);
+    assert_eq!(
+        relate(&a, Some(a_second), b_head).unwrap(),
+        Relation::Diverged
+    );
+}
+
+#[test]
+fn logs_are_topologically_ordered_and_cut_off_by_depth() {
+    let a = MemoryJournal::new();
+    let b = MemoryJournal::new();
+
+    let (_, root) = commit(&a, app(), b"root");
+    transfer(&a, &b);
+
+    let (_, left) = commit(&a, app(), b"left");
+
+    let put = |journal: &MemoryJournal, data: &[u8]| {
+        journal
+            .cas_put(CASObj {
+                links: Vec::new(),
+                data: data.to_vec(),
+            })
+            .unwrap()
+    };
+
+    let state = put(&b, b"right");
+    let right = b
+        .commit_with(app(), state, &[root], CommitInfo::default())
+        .unwrap();
+    transfer(&b, &a);
+
+    let state = put(&a, b"merge");
+    let merge = a
+        .commit_with(app(), state, &[right], CommitInfo::default())
+        .unwrap();
+    let (_, top) = commit(&a, app(), b"top");
+
+    let walk = |log: Log| -> Vec<_> {
+        log.map(|log_entry| {
+            let log_entry = log_entry.unwrap();
+            (log_entry.key, log_entry.depth)
+        })
+        .collect()
+    };
+
+    assert_eq!(
+        walk(Log::new(&a, top)),
+        vec![(top, 0), (merge, 1), (left, 2), (right, 2), (root, 3)]
+    );
+    assert_eq!(
+        walk(Log::new(&a, top).max_depth(2)),
+        vec![(top, 0), (merge, 1), (left, 2), (right, 2)]
+    );
+    assert_eq!(walk(Log::new(&a, top).max_depth(0)), vec![(top, 0)]);
+}
@@ -0,0 +1,103 @@
+mod common;
+
+use common::{app, commit, TempDir};
+use distcomp::{CASObj, CasStore, EntryStore, JournalError, SqliteJournal, SCHEMA_VERSION};
+use rusqlite::{params, Connection};
+
+#[test]
+fn links_are_backfilled_from_entries() {
+    let dir = TempDir::new("links_are_backfilled_from_entries");
+    let path = dir.path("sqlite.db");
+
+    let (first, second) = {
+        let journal = SqliteJournal::new(&path).unwrap();
+
+        let (_, first) = commit(&journal, app(), b"first");
+        let (_, second) = commit(&journal, app(), b"second");
+
+        (first, second)
+    };
+
+    // Wind the database back to before the links were recorded.
+    let db = Connection::open(&path).unwrap();
+    db.execute("DELETE FROM links", params!()).unwrap();
+    db.execute_batch(&format!("PRAGMA user_version = {}", SCHEMA_VERSION - 1))
+        .unwrap();
+    drop(db);
+
+    let journal = SqliteJournal::new(&path).unwrap();
+
+    assert_eq!(journal.children(first).unwrap(), vec![second]);
+    assert!(journal.children(second).unwrap().is_empty());
+}
+
+#[test]
+fn databases_from_newer_versions_are_refused() {
+    let dir = TempDir::new("databases_from_newer_versions_are_refused");
+    let path = dir.path("sqlite.db");
+
+    drop(SqliteJournal::new(&path).unwrap());
+
+    let db = Connection::open(&path).unwrap();
+    db.execute_batch(&format!("PRAGMA user_version = {}", SCHEMA_VERSION + 1))
+        .unwrap();
+    drop(db);
+
+    match SqliteJournal::new(&path) {
+        Err(JournalError::SchemaTooNew(version)) => assert_eq!(version, SCHEMA_VERSION + 1),
+        Err(e) => panic!("expected SchemaTooNew, got {:?}", e),
+        Ok(_) => panic!("a newer database was opened"),
+    }
+}
+
+#[test]
+fn unversioned_databases_are_upgraded_in_place() {
+    let dir = TempDir::new("unversioned_databases_are_upgraded_in_place");
+    let path = dir.path("sqlite.db");
+
+    let obj = CASObj {
+        links: Vec::new(),
+        data: b"from before versioning".to_vec(),
+    };
+
+    // The layout databases had before the schema was versioned, with keys stored as bare SHA-256
+    // digests and object bodies uncompressed.
+    let db = Connection::open(&path).unwrap();
+    db.execute_batch(
+        "
+        CREATE TABLE settings (id BLOB NOT NULL PRIMARY KEY, value BLOB NOT NULL);
+        CREATE TABLE heads (
+            application_id BLOB NOT NULL,
+            device_id BLOB NOT NULL,
+            entry_id BLOB NOT NULL,
+            PRIMARY KEY (application_id, device_id)
+        );
+        CREATE TABLE entries (id BLOB NOT NULL PRIMARY KEY, inner BLOB NOT NULL);
+        CREATE TABLE cas (id BLOB NOT NULL PRIMARY KEY, content BLOB NOT NULL);
+        ",
+    )
+    .unwrap();
+
+    let content = serde_cbor::to_vec(&obj).unwrap();
+    let id = sodiumoxide::crypto::hash::sha256::hash(&content)
+        .as_ref()
+        .to_vec();
+    db.execute("INSERT INTO cas VALUES (?1, ?2)", params!(id, content))
+        .unwrap();
+    drop(db);
+
+    let journal = SqliteJournal::new(&path).unwrap();
+
+    assert_eq!(journal.cas_get(obj.key()).unwrap().unwrap().data, obj.data);
+
+    let (_, first) = commit(&journal, app(), b"first");
+    let (_, second) = commit(&journal, app(), b"second");
+    assert_eq!(journal.children(first).unwrap(), vec![second]);
+    drop(journal);
+
+    let db = Connection::open(&path).unwrap();
+    let version: u32 = db
+        .query_row("PRAGMA user_version", params!(), |row| row.get(0))
+        .unwrap();
+    assert_eq!(version, SCHEMA_VERSION);
+}
@@ -0,0 +1,54 @@
+mod common;
+
+use common::{app, commit};
+use distcomp::overlay::Archive;
+use distcomp::{
+    CASKey, CASObj, CasStore, EntryStore, JournalError, JournalKey, MemoryJournal, OverlayJournal,
+    Result, Signed,
+};
+use std::rc::Rc;
+
+/// An archive that answers every request with the same entry and object.
+struct Lying {
+    signed: Signed,
+    obj: CASObj,
+}
+
+impl Archive for Lying {
+    fn archived_entry(&self, _: JournalKey) -> Result<Option<Signed>> {
+        Ok(Some(self.signed.clone()))
+    }
+
+    fn archived_object(&self, _: CASKey) -> Result<Option<CASObj>> {
+        Ok(Some(self.obj.clone()))
+    }
+}
+
+#[test]
+fn archived_data_is_checked_against_its_key() {
+    let archive = Rc::new(MemoryJournal::new());
+    let (state, key) = commit(&*archive, app(), b"archived");
+    let (other_state, other_key) = commit(&*archive, app(), b"other");
+
+    let honest = OverlayJournal::new(Rc::new(MemoryJournal::new())).with_archive(archive.clone());
+
+    assert!(honest.get_signed(key).unwrap().is_some());
+    assert_eq!(honest.cas_get(state).unwrap().unwrap().data, b"archived");
+
+    let lying = OverlayJournal::new(Rc::new(MemoryJournal::new())).with_archive(Rc::new(Lying {
+        signed: archive.get_signed(other_key).unwrap().unwrap(),
+        obj: archive.cas_get(other_state).unwrap().unwrap(),
+    }));
+
+    assert!(lying.get_signed(other_key).unwrap().is_some());
+
+    match lying.get_signed(key) {
+        Err(JournalError::Integrity(_)) => {}
+        other => panic!("expected an integrity error, got {:?}", other),
+    }
+
+    match lying.cas_get(state) {
+        Err(JournalError::Integrity(_)) => {}
+        other => panic!("expected an integrity error, got {:?}", other),
+    }
+}
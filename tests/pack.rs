@@ -0,0 +1,111 @@
+//! Reading and writing packs, including packs made to exhaust a reader.
+
+use distcomp::pack::{Pack, PackWriter, MAGIC, MAX_BODY_LEN};
+use distcomp::{CASKey, CASObj, HashAlgorithm};
+use flate2::write::ZlibEncoder;
+use flate2::Compression;
+use std::io::{Cursor, Write};
+
+fn object(data: Vec<u8>) -> (CASKey, CASObj) {
+    let obj = CASObj {
+        links: Vec::new(),
+        data,
+    };
+
+    (obj.key(), obj)
+}
+
+#[test]
+fn objects_round_trip() {
+    let mut writer = PackWriter::new(Vec::new()).unwrap();
+    let mut objects = Vec::new();
+
+    for i in 0..20u32 {
+        // Similar bodies, so that most are stored as deltas.
+        let mut data = vec![7; 4000];
+        data[i as usize * 100] = 0;
+
+        let (key, obj) = object(data);
+        writer.add_object(key, &obj).unwrap();
+        objects.push((key, obj));
+    }
+
+    let data = writer.finish().unwrap();
+    assert!(data.len() < 20 * 4000 / 4);
+
+    let pack = Pack::open(Cursor::new(data)).unwrap();
+    assert_eq!(pack.len(), 20);
+
+    for (key, obj) in objects {
+        assert_eq!(pack.object(key).unwrap().unwrap().data, obj.data);
+    }
+}
+
+/// A pack holding one record with `body`, claiming `len` as its length.
+fn hand_made_pack(key: CASKey, codec: u8, len: u32, body: &[u8]) -> Vec<u8> {
+    let key = key.hash().to_multihash();
+
+    let mut pack = MAGIC.to_vec();
+    pack.push(1);
+
+    let offset = pack.len() as u64;
+
+    pack.extend_from_slice(&[0, key.len() as u8]);
+    pack.extend_from_slice(&key);
+    pack.extend_from_slice(&[0, codec]);
+    pack.extend_from_slice(&len.to_be_bytes());
+    pack.extend_from_slice(body);
+
+    let index_offset = pack.len() as u64;
+
+    pack.extend_from_slice(&1u32.to_be_bytes());
+    pack.extend_from_slice(&[0, key.len() as u8]);
+    pack.extend_from_slice(&key);
+    pack.extend_from_slice(&offset.to_be_bytes());
+    pack.extend_from_slice(&index_offset.to_be_bytes());
+
+    pack
+}
+
+#[test]
+fn lengths_past_the_end_are_rejected() {
+    let key = CASKey::new(HashAlgorithm::Sha256.digest(b"x"));
+
+    let pack = Pack::open(Cursor::new(hand_made_pack(key, 0, u32::MAX, b"short"))).unwrap();
+
+    assert!(pack.object(key).is_err());
+}
+
+#[test]
+fn bodies_inflating_past_the_limit_are_rejected() {
+    let key = CASKey::new(HashAlgorithm::Sha256.digest(b"x"));
+
+    let mut encoder = ZlibEncoder::new(Vec::new(), Compression::best());
+    let zeros = vec![0; 1 << 20];
+
+    for _ in 0..=MAX_BODY_LEN >> 20 {
+        encoder.write_all(&zeros).unwrap();
+    }
+
+    let bomb = encoder.finish().unwrap();
+    assert!(bomb.len() < 1 << 20);
+
+    let pack = hand_made_pack(key, 1, bomb.len() as u32, &bomb);
+    let pack = Pack::open(Cursor::new(pack)).unwrap();
+
+    assert!(pack.object(key).is_err());
+}
+
+#[test]
+fn offsets_past_the_end_of_the_address_space_are_rejected() {
+    let key = CASKey::new(HashAlgorithm::Sha256.digest(b"x"));
+
+    // Something in front of the pack, as in a bundle, so adding offsets to its start overflows.
+    let mut data = b"prefix".to_vec();
+    data.extend(hand_made_pack(key, 0, 5, b"short"));
+
+    let end = data.len();
+    data[end - 8..].copy_from_slice(&u64::MAX.to_be_bytes());
+
+    assert!(Pack::open_at(Cursor::new(data), 6).is_err());
+}
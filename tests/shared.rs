@@ -0,0 +1,43 @@
+mod common;
+
+use common::{commit, TempDir};
+use distcomp::{history, ApplicationId, HeadStore, Journal, SharedSqliteJournal};
+use std::sync::Arc;
+use std::thread;
+use uuid::Uuid;
+
+#[test]
+fn threads_commit_concurrently() {
+    let dir = TempDir::new("threads_commit_concurrently");
+    let journal = Arc::new(SharedSqliteJournal::new(&dir.path("sqlite.db")).unwrap());
+
+    // More threads than run at once, so later ones reuse the connections earlier ones are done
+    // with.
+    for round in 0..4u8 {
+        let threads: Vec<_> = (0..4u8)
+            .map(|i| {
+                let journal = Arc::clone(&journal);
+                let appid = ApplicationId(Uuid::from_bytes([round * 4 + i; 16]));
+
+                thread::spawn(move || {
+                    for n in 0..20u32 {
+                        commit(&*journal, appid, &n.to_le_bytes());
+                    }
+                })
+            })
+            .collect();
+
+        for thread in threads {
+            thread.join().unwrap();
+        }
+    }
+
+    assert_eq!(journal.heads().unwrap().len(), 16);
+
+    for app in 0..16u8 {
+        let appid = ApplicationId(Uuid::from_bytes([app; 16]));
+        let head = journal.this_head(appid).unwrap().unwrap();
+
+        assert_eq!(history::Log::new(&*journal, head).count(), 20);
+    }
+}
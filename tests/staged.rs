@@ -0,0 +1,65 @@
+mod common;
+
+use common::{app, commit};
+use distcomp::{
+    CasStore, EntryStore, Journal, JournalError, MemoryJournal, StagedJournal, Transactional,
+};
+use std::rc::Rc;
+
+#[test]
+fn commits_reach_the_base_only_when_applied() {
+    let base = Rc::new(MemoryJournal::new());
+    let staged = StagedJournal::new(base.clone());
+
+    let (state, key) = commit(&staged, app(), b"staged");
+
+    assert_eq!(staged.this_head(app()).unwrap(), Some(key));
+    assert_eq!(base.this_head(app()).unwrap(), None);
+    assert!(base.get(key).unwrap().is_none());
+    assert!(base.cas_get(state).unwrap().is_none());
+
+    staged.apply().unwrap();
+
+    assert_eq!(base.this_head(app()).unwrap(), Some(key));
+    assert_eq!(base.get_state(app()).unwrap(), Some(state));
+}
+
+#[test]
+fn applying_over_a_moved_head_fails_and_writes_nothing() {
+    let base = Rc::new(MemoryJournal::new());
+    commit(&*base, app(), b"first");
+
+    let staged = StagedJournal::new(base.clone());
+    let (_, ours) = commit(&staged, app(), b"ours");
+
+    let (_, theirs) = commit(&*base, app(), b"theirs");
+
+    match staged.apply() {
+        Err(JournalError::HeadConflict { found, .. }) => assert_eq!(found, Some(theirs)),
+        other => panic!("expected a head conflict, got {:?}", other),
+    }
+
+    assert_eq!(base.this_head(app()).unwrap(), Some(theirs));
+    assert!(base.get(ours).unwrap().is_none());
+}
+
+#[test]
+fn rolled_back_writes_never_reach_the_base() {
+    let base = Rc::new(MemoryJournal::new());
+    let (first, _) = commit(&*base, app(), b"first");
+    let objects = base.cas_list().unwrap();
+
+    let staged = StagedJournal::new(base.clone());
+
+    staged.begin_transaction().unwrap();
+    commit(&staged, app(), b"discarded");
+    staged.cas_delete(first).unwrap();
+    assert!(staged.cas_get(first).unwrap().is_none());
+    staged.rollback_transaction().unwrap();
+
+    staged.apply().unwrap();
+
+    assert_eq!(staged.cas_list().unwrap(), objects);
+    assert_eq!(base.cas_list().unwrap(), objects);
+    assert!(base.cas_get(first).unwrap().is_some());
+}
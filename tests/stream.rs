@@ -0,0 +1,89 @@
+mod common;
+
+use common::noise;
+use distcomp::chunk::{self, MANIFEST_MAGIC, MAX_CHUNK};
+use distcomp::stream::{ObjectReader, ObjectWriter};
+use distcomp::{CASObj, CasStore, MemoryJournal};
+use std::io::{Read, Seek, SeekFrom, Write};
+
+#[test]
+fn written_objects_get_the_same_key_as_put() {
+    let journal = MemoryJournal::new();
+
+    let mut magic = MANIFEST_MAGIC.to_vec();
+    magic.extend_from_slice(b"but small");
+
+    let cases = vec![
+        Vec::new(),
+        b"small".to_vec(),
+        magic,
+        noise(MAX_CHUNK, 3),
+        noise(MAX_CHUNK + 1, 4),
+        noise(5 * MAX_CHUNK, 5),
+    ];
+
+    for data in cases {
+        let put = chunk::put(
+            &journal,
+            CASObj {
+                links: Vec::new(),
+                data: data.clone(),
+            },
+        )
+        .unwrap();
+
+        // Uneven writes, so that chunk boundaries fall inside them.
+        let mut writer = ObjectWriter::new(&journal, Vec::new());
+        for piece in data.chunks(7919) {
+            writer.write_all(piece).unwrap();
+        }
+
+        assert_eq!(writer.finish().unwrap(), put, "length {}", data.len());
+    }
+}
+
+#[test]
+fn reads_span_chunks_and_stop_at_the_end() {
+    let journal = MemoryJournal::new();
+
+    let data = noise(4 * MAX_CHUNK, 6);
+
+    let key = chunk::put(
+        &journal,
+        CASObj {
+            links: Vec::new(),
+            data: data.clone(),
+        },
+    )
+    .unwrap();
+
+    let manifest = chunk::manifest(&journal.cas_get(key).unwrap().unwrap())
+        .unwrap()
+        .unwrap();
+    let boundary = manifest.lengths[0];
+
+    let mut reader = ObjectReader::open(&journal, key).unwrap().unwrap();
+    assert_eq!(reader.len(), data.len() as u64);
+
+    // A read that starts in the first chunk and ends in the second.
+    let mut buf = [0; 100];
+    reader.seek(SeekFrom::Start(boundary - 50)).unwrap();
+    reader.read_exact(&mut buf).unwrap();
+    let start = boundary as usize - 50;
+    assert_eq!(&buf[..], &data[start..start + 100]);
+
+    reader.seek(SeekFrom::End(-10)).unwrap();
+    let mut tail = Vec::new();
+    reader.read_to_end(&mut tail).unwrap();
+    assert_eq!(&tail[..], &data[data.len() - 10..]);
+
+    assert_eq!(reader.read(&mut buf).unwrap(), 0);
+
+    reader.seek(SeekFrom::End(10)).unwrap();
+    assert_eq!(reader.read(&mut buf).unwrap(), 0);
+
+    reader.seek(SeekFrom::Start(0)).unwrap();
+    let mut all = Vec::new();
+    reader.read_to_end(&mut all).unwrap();
+    assert!(all == data);
+}
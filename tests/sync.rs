@@ -0,0 +1,206 @@
+//! Syncing two journals in the same process over an in-memory pipe.
+
+mod common;
+
+use common::{app, breaking_pipe, commit, pipe, Pipe};
+use distcomp::sync::{self, Role, SyncStats};
+use distcomp::{
+    fsck, ApplicationId, CASObj, CasStore, EntryStore, HeadStore, Journal, KeyStore, MemoryJournal,
+};
+use std::io;
+use std::thread;
+use uuid::Uuid;
+
+/// Syncs `initiator` with `responder`, which is synced from another thread.
+fn sync_over(
+    initiator: &MemoryJournal,
+    responder: MemoryJournal,
+    (near, far): (Pipe, Pipe),
+) -> (io::Result<SyncStats>, MemoryJournal, io::Result<SyncStats>) {
+    let peer = thread::spawn(move || {
+        let mut far = far;
+        let stats = sync::sync(&responder, &mut far, Role::Responder);
+        (responder, stats)
+    });
+
+    let mut near = near;
+    let stats = sync::sync(initiator, &mut near, Role::Initiator);
+    drop(near);
+
+    let (responder, responder_stats) = peer.join().unwrap();
+
+    (stats, responder, responder_stats)
+}
+
+fn assert_consistent(journal: &dyn Journal) {
+    let report = fsck::verify(journal).unwrap();
+    assert!(report.is_ok(), "{:?}", report.problems);
+}
+
+#[test]
+fn sync_exchanges_history_both_ways() {
+    let a = MemoryJournal::new();
+    let b = MemoryJournal::new();
+
+    for i in 0..3 {
+        commit(&a, app(), format!("a{}", i).as_bytes());
+    }
+
+    let (b_state, _) = commit(&b, app(), b"b");
+    let a_state = a.get_state(app()).unwrap();
+
+    let (stats, b, b_stats) = sync_over(&a, b, pipe());
+
+    let stats = stats.unwrap();
+    let b_stats = b_stats.unwrap();
+
+    assert_eq!(stats.entries_received, 1);
+    assert_eq!(stats.objects_received, 1);
+    assert_eq!(b_stats.entries_received, 3);
+    assert_eq!(b_stats.objects_received, 3);
+
+    assert_eq!(a.heads().unwrap(), b.heads().unwrap());
+    assert_eq!(a.heads().unwrap().len(), 2);
+
+    let b_head = a.heads().unwrap()[&(app(), b.pubkey().unwrap())];
+    assert_eq!(a.get(b_head).unwrap().unwrap().new_state(), b_state);
+    assert_eq!(
+        b.get(a.this_head(app()).unwrap().unwrap())
+            .unwrap()
+            .unwrap()
+            .new_state(),
+        a_state.unwrap()
+    );
+
+    assert_consistent(&a);
+    assert_consistent(&b);
+
+    // Nothing is left to send.
+    let (stats, _, b_stats) = sync_over(&a, b, pipe());
+    assert_eq!(stats.unwrap(), SyncStats::default());
+    assert_eq!(b_stats.unwrap(), SyncStats::default());
+}
+
+#[test]
+fn interrupted_sync_leaves_no_holes() {
+    let mut source = MemoryJournal::new();
+
+    for i in 0..5u8 {
+        let mut last = None;
+
+        // A small tree of objects under each state, so that objects arrive over several rounds.
+        for j in 0..3u8 {
+            let leaf = source
+                .cas_put(CASObj {
+                    links: last.into_iter().collect(),
+                    data: vec![i, j],
+                })
+                .unwrap();
+
+            last = Some(leaf);
+        }
+
+        source
+            .commit_self(
+                app(),
+                source
+                    .cas_put(CASObj {
+                        links: last.into_iter().collect(),
+                        data: vec![i],
+                    })
+                    .unwrap(),
+            )
+            .unwrap();
+    }
+
+    let local = MemoryJournal::new();
+
+    // Cut the source off at different points of its replies.
+    for &cut in &[0, 50, 200, 400, 800, 1500, 3000] {
+        let (stats, returned, _) = sync_over(&local, source, breaking_pipe_far(cut));
+        source = returned;
+
+        assert!(stats.is_err(), "sync cut after {} bytes succeeded", cut);
+
+        assert!(local.heads().unwrap().is_empty());
+        assert!(local.entry_list().unwrap().is_empty());
+        assert!(local.cas_list().unwrap().is_empty());
+        assert_consistent(&local);
+    }
+
+    let (stats, source, _) = sync_over(&local, source, pipe());
+    let stats = stats.unwrap();
+
+    assert_eq!(stats.entries_received, 5);
+    assert_eq!(stats.objects_received, 20);
+    assert_eq!(local.heads().unwrap(), source.heads().unwrap());
+    assert_consistent(&local);
+}
+
+/// A pipe whose far end, the responder's, breaks after `bytes`.
+fn breaking_pipe_far(bytes: usize) -> (Pipe, Pipe) {
+    let (far, near) = breaking_pipe(bytes);
+    (near, far)
+}
+
+#[test]
+fn heads_not_signed_by_their_device_are_ignored() {
+    let local = MemoryJournal::new();
+    let evil = MemoryJournal::new();
+
+    let other_app = ApplicationId(Uuid::from_bytes([2; 16]));
+
+    let (_, honest) = commit(&evil, app(), b"honest");
+    let (_, forged) = commit(&evil, app(), b"forged");
+
+    // Claim that the local device's head, and the peer's own head for another application, are
+    // entries the peer signed for this application.
+    evil.update_head(local.pubkey().unwrap(), app(), forged)
+        .unwrap();
+    evil.update_head(evil.pubkey().unwrap(), other_app, honest)
+        .unwrap();
+
+    let (stats, evil, _) = sync_over(&local, evil, pipe());
+    stats.unwrap();
+
+    let heads = local.heads().unwrap();
+
+    assert_eq!(heads.len(), 1);
+    assert_eq!(heads[&(app(), evil.pubkey().unwrap())], forged);
+    assert_eq!(local.this_head(app()).unwrap(), None);
+
+    assert_consistent(&local);
+}
+
+#[test]
+fn large_replies_are_split() {
+    let source = MemoryJournal::new();
+
+    // Random data doesn't compress, so this is more than fits in one reply.
+    let links = (0..20)
+        .map(|_| {
+            source
+                .cas_put(CASObj {
+                    links: Vec::new(),
+                    data: sodiumoxide::randombytes::randombytes(1 << 20),
+                })
+                .unwrap()
+        })
+        .collect();
+
+    let root = source
+        .cas_put(CASObj {
+            links,
+            data: Vec::new(),
+        })
+        .unwrap();
+
+    source.commit_self(app(), root).unwrap();
+
+    let local = MemoryJournal::new();
+
+    let (stats, _, _) = sync_over(&local, source, pipe());
+
+    assert_eq!(stats.unwrap().objects_received, 21);
+    assert_consistent(&local);
+}